@@ -0,0 +1,444 @@
+//! Command line arguments
+
+use crate::error::CliError;
+use clap::builder::{BoolishValueParser, PossibleValuesParser, TypedValueParser};
+use clap::{Arg, ArgAction, ArgMatches, Args, FromArgMatches, Parser, Subcommand};
+use hexyg_core::options::{ValueKind, OPTIONS};
+use hexyg_core::settings::Layer;
+use hexyg_core::{CheckOptions, Config, Error, OptionSpec, Position, Settings, Slice, Template};
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// Mode flags of the first versions, kept as aliases of their subcommands
+const LEGACY_MODES: [&str; 2] = ["--bin-to-hex", "--hex-to-bin"];
+
+#[derive(Parser)]
+#[command(name = "hexyg")]
+#[command(about = "Bidirectional converter between binary data and hex text format")]
+#[command(version)]
+#[command(disable_help_flag = true)]
+#[command(arg_required_else_help = true)]
+pub struct Cli {
+    /// Print help
+    #[arg(long = "help", short = '?', short_alias = 'h', global = true, action = ArgAction::Help)]
+    help: Option<bool>,
+
+    /// Configuration file (default: hexyg.toml, hexyg.json, hexyg.ini or .hexygrc in the working directory or above)
+    #[arg(long = "config", global = true, value_name = "PATH")]
+    pub config_file: Option<PathBuf>,
+
+    /// Profile of the configuration file
+    #[arg(long = "profile", global = true, value_name = "NAME")]
+    pub profile: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+impl Cli {
+    /// Parse the arguments of the process
+    pub fn parse_args() -> Self {
+        Cli::parse_from(legacy_args(std::env::args_os()))
+    }
+}
+
+/// Move a legacy mode flag such as `--bin-to-hex` in front of the other arguments,
+/// where it selects its subcommand
+fn legacy_args(args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    let mut args: Vec<OsString> = args.into_iter().collect();
+    let end = args
+        .iter()
+        .position(|arg| arg == "--")
+        .unwrap_or(args.len());
+    let legacy = args
+        .iter()
+        .take(end)
+        .skip(1)
+        .position(|arg| LEGACY_MODES.iter().any(|mode| arg == mode));
+    if let Some(index) = legacy {
+        let mode = args.remove(index + 1);
+        args.insert(1, mode);
+    }
+    args
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Convert binary data to hex text format
+    #[command(long_flag = "bin-to-hex")]
+    Encode(EncodeArgs),
+
+    /// Convert hex text to binary data
+    #[command(long_flag = "hex-to-bin")]
+    Decode(DecodeArgs),
+
+    /// Check hex text and report all errors without converting it
+    Check(CheckArgs),
+
+    /// Reformat hex files using the options declared in their #option lines
+    Fmt(FmtArgs),
+
+    /// Print the options of the configuration file and the environment
+    Config(ConfigArgs),
+}
+
+#[derive(Args)]
+pub struct InputArgs {
+    /// Input file (if not specified, reads from stdin)
+    #[arg(long, short = 'i')]
+    pub input: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct OutputArgs {
+    /// Output file (if not specified, writes to stdout)
+    #[arg(long, short = 'o')]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct RangeArgs {
+    /// Start address (decimal or 0x-prefixed hex, negative for end-based)
+    #[arg(long, allow_hyphen_values = true)]
+    pub from: Option<Position>,
+
+    /// End address (exclusive, decimal or 0x-prefixed hex, negative for end-based)
+    #[arg(long, allow_hyphen_values = true)]
+    pub to: Option<Position>,
+}
+
+impl RangeArgs {
+    pub fn slice(&self) -> Slice {
+        Slice::new(self.from, self.to)
+    }
+}
+
+#[derive(Args)]
+pub struct FormatArgs {
+    /// Line template such as "{addr:08X}: {hex:block=4} |{ascii}|", or a preset: xxd, hexdump, od
+    #[arg(
+        long = "format-line",
+        value_name = "TEMPLATE",
+        conflicts_with = "format_file"
+    )]
+    pub format_line: Option<Template>,
+
+    /// File with the header, line and footer templates
+    #[arg(long = "format-file", value_name = "PATH")]
+    pub format_file: Option<PathBuf>,
+}
+
+impl FormatArgs {
+    /// Template of the text, `None` for hex text
+    pub fn template(&self) -> Result<Option<Template>, CliError> {
+        match (&self.format_line, &self.format_file) {
+            (Some(template), _) => Ok(Some(template.clone())),
+            (None, Some(path)) => {
+                let text = std::fs::read_to_string(path)?;
+                Ok(Some(Template::from_format_file(&text)?))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct EncodeArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+
+    #[command(flatten)]
+    pub range: RangeArgs,
+
+    #[command(flatten)]
+    pub format: FormatArgs,
+
+    /// Set option in hex file: --option name=value
+    #[arg(long = "option", value_name = "NAME=VALUE", value_parser = parse_option)]
+    pub options: Vec<(String, String)>,
+
+    /// Start address in hex file (decimal or 0x-prefixed hex; default: --from)
+    #[arg(long = "start-address", value_parser = parse_start_address)]
+    pub start_address: Option<u64>,
+
+    #[command(flatten)]
+    pub flags: OptionFlags,
+}
+
+impl EncodeArgs {
+    /// Configuration of the hex text to write: the options of the command line
+    /// over `settings`
+    pub fn config(&self, mut settings: Settings) -> Result<Config, CliError> {
+        // Options passed through with --option; known ones also configure the output
+        for (name, value) in &self.options {
+            settings.apply(name, value, Layer::CommandLine)?;
+        }
+        for (name, value) in &self.flags.values {
+            settings.apply(name, value, Layer::CommandLine)?;
+        }
+        let mut config = settings.into_config();
+
+        // Generated options win over pass-through ones; conflicting values are reported by bin_to_hex
+        for (name, value) in &self.options {
+            if config.option_value(name).is_some() {
+                config.extra_options.push((name.clone(), value.clone()));
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Flags of the options of the registry that have one, such as `--line-length`
+#[derive(Debug, Clone, Default)]
+pub struct OptionFlags {
+    /// Option names and values of the flags given, in registry order
+    pub values: Vec<(&'static str, String)>,
+}
+
+impl FromArgMatches for OptionFlags {
+    fn from_arg_matches(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        let mut flags = OptionFlags::default();
+        flags.update_from_arg_matches(matches)?;
+        Ok(flags)
+    }
+
+    fn update_from_arg_matches(&mut self, matches: &ArgMatches) -> Result<(), clap::Error> {
+        for (spec, _) in flagged_options() {
+            if let Some(value) = matches.get_one::<String>(spec.name) {
+                self.values.retain(|(name, _)| *name != spec.name);
+                self.values.push((spec.name, value.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Args for OptionFlags {
+    fn augment_args(cmd: clap::Command) -> clap::Command {
+        cmd.args(flagged_options().map(|(spec, flag)| option_flag(spec, flag)))
+    }
+
+    fn augment_args_for_update(cmd: clap::Command) -> clap::Command {
+        Self::augment_args(cmd)
+    }
+}
+
+fn flagged_options() -> impl Iterator<Item = (&'static OptionSpec, &'static str)> {
+    OPTIONS.iter().filter_map(|spec| Some((spec, spec.flag?)))
+}
+
+/// Flag of an option, validated as the option value
+fn option_flag(spec: &'static OptionSpec, flag: &'static str) -> Arg {
+    let default = match spec.default {
+        "" => "empty".to_string(),
+        " " => "one space".to_string(),
+        default => default.to_string(),
+    };
+    let value_name = match spec.kind {
+        ValueKind::Number { .. } => "N",
+        ValueKind::Bool => "BOOL",
+        ValueKind::Whitespace => "WHITESPACE",
+        ValueKind::Char => "CHAR",
+        _ => "VALUE",
+    };
+    let arg = Arg::new(spec.name)
+        .long(flag)
+        .value_name(value_name)
+        .action(ArgAction::Set)
+        .help(format!("{} (default: {})", spec.help, default));
+    match spec.kind {
+        ValueKind::Choice(values) => arg
+            .value_parser(PossibleValuesParser::new(values))
+            .ignore_case(true),
+        ValueKind::Bool => {
+            arg.value_parser(BoolishValueParser::new().map(|value| value.to_string()))
+        }
+        _ => arg.value_parser(move |value: &str| match spec.validate(value) {
+            Ok(()) => Ok(value.to_string()),
+            Err(Error::Config(message)) => Err(message),
+            Err(err) => Err(err.to_string()),
+        }),
+    }
+}
+
+#[derive(Args)]
+pub struct DecodeArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+
+    #[command(flatten)]
+    pub range: RangeArgs,
+
+    #[command(flatten)]
+    pub format: FormatArgs,
+
+    /// Check consistency: none, text, values, all, text,values
+    #[arg(long = "check", default_value = "none", value_parser = parse_checks)]
+    pub check: CheckOptions,
+
+    /// Stop reporting after N errors (default: report all)
+    #[arg(long = "max-errors", value_name = "N")]
+    pub max_errors: Option<usize>,
+}
+
+impl DecodeArgs {
+    pub fn check_options(&self) -> CheckOptions {
+        CheckOptions {
+            max_errors: self.max_errors,
+            ..self.check.clone()
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct CheckArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    /// Consistency checks besides syntax: none, text, values, all, text,values
+    #[arg(long = "check", default_value = "all", value_parser = parse_checks)]
+    pub check: CheckOptions,
+
+    /// Stop reporting after N errors (default: report all)
+    #[arg(long = "max-errors", value_name = "N")]
+    pub max_errors: Option<usize>,
+}
+
+impl CheckArgs {
+    pub fn check_options(&self) -> CheckOptions {
+        CheckOptions {
+            max_errors: self.max_errors,
+            ..self.check.clone()
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct FmtArgs {
+    /// Do not write files; print a diff and fail if any file is not formatted
+    #[arg(long)]
+    pub check: bool,
+
+    /// Files or directories with *.hex files (if not specified, formats stdin to stdout)
+    pub paths: Vec<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct ConfigArgs {
+    /// Print the layer that set each option: default, file, environment
+    #[arg(long)]
+    pub explain: bool,
+}
+
+fn parse_option(option: &str) -> Result<(String, String), String> {
+    let (name, value) = option
+        .split_once('=')
+        .ok_or_else(|| format!("invalid option: {option}. Use --option name=value"))?;
+    Ok((name.trim().to_string(), value.trim().to_string()))
+}
+
+fn parse_start_address(value: &str) -> Result<u64, String> {
+    match value.parse::<Position>() {
+        Ok(Position::Start(address)) => Ok(address),
+        Ok(Position::End(_)) => Err("start address must not be negative".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn parse_checks(value: &str) -> Result<CheckOptions, String> {
+    let mut options = CheckOptions::default();
+    for mode in value.split(',').map(str::trim) {
+        match mode {
+            "none" => {}
+            "text" => options.text = true,
+            "values" => options.values = true,
+            "all" => options = options.with_text(true).with_values(true),
+            _ => {
+                return Err(format!(
+                    "invalid check mode: {value}. Use none, text, values or all"
+                ))
+            }
+        }
+    }
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    fn parse(args: &[&str]) -> Cli {
+        Cli::try_parse_from(legacy_args(args.iter().map(OsString::from))).unwrap()
+    }
+
+    #[test]
+    fn test_command_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_legacy_mode_flags() {
+        let cli = parse(&["hexyg", "-i", "a.bin", "--bin-to-hex", "--line-length", "8"]);
+        let Command::Encode(args) = cli.command else {
+            panic!("encode expected");
+        };
+        assert_eq!(args.input.input, Some(PathBuf::from("a.bin")));
+        assert_eq!(args.flags.values, [("line_length", "8".to_string())]);
+
+        let cli = parse(&["hexyg", "--hex-to-bin", "--check", "text"]);
+        let Command::Decode(args) = cli.command else {
+            panic!("decode expected");
+        };
+        assert!(args.check.text && !args.check.values);
+
+        // options of another command are rejected
+        let args = legacy_args(["hexyg", "--bin-to-hex", "--check", "all"].map(OsString::from));
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_format_options() {
+        let cli = parse(&["hexyg", "decode", "--format-line", "xxd"]);
+        let Command::Decode(args) = cli.command else {
+            panic!("decode expected");
+        };
+        assert_eq!(args.format.template().unwrap(), "xxd".parse().ok());
+
+        let args = ["hexyg", "encode", "--format-line", "{addr"];
+        assert!(Cli::try_parse_from(args).is_err());
+        let args = [
+            "hexyg",
+            "encode",
+            "--format-line",
+            "xxd",
+            "--format-file",
+            "a",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_layered_config() {
+        let cli = parse(&["hexyg", "encode", "--profile", "wide", "--line-length", "8"]);
+        assert_eq!(cli.profile.as_deref(), Some("wide"));
+        let Command::Encode(args) = cli.command else {
+            panic!("encode expected");
+        };
+
+        let mut settings = Settings::new();
+        let env = [("HEXYG_LINE_LENGTH", "32"), ("HEXYG_BLOCK_LENGTH", "4")];
+        settings
+            .apply_env(env.map(|(name, value)| (name.to_string(), value.to_string())))
+            .unwrap();
+        let config = args.config(settings).unwrap();
+        assert_eq!(config.line_length, 8);
+        assert_eq!(config.block_length, 4);
+    }
+}
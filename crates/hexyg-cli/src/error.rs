@@ -0,0 +1,62 @@
+//! Errors of the command line tool and their exit codes
+
+use std::io;
+use thiserror::Error;
+
+/// Exit code when the input has errors or files are not formatted
+pub const EXIT_FAILURE: u8 = 1;
+
+/// Exit code for invalid arguments or options; clap uses it for its own errors too
+pub const EXIT_USAGE: u8 = 2;
+
+/// Exit code when reading or writing a file fails
+pub const EXIT_IO: u8 = 3;
+
+#[derive(Debug, Error)]
+pub enum CliError {
+    /// Invalid arguments or options
+    #[error("{0}")]
+    Usage(String),
+
+    /// The input has errors or is not formatted; the details are already printed
+    #[error("failed")]
+    Failed,
+
+    /// Reading or writing failed
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// Conversion error that is not reported as a diagnostic
+    #[error(transparent)]
+    Core(hexyg_core::Error),
+}
+
+impl CliError {
+    /// Process exit code for the error
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Usage(_) => EXIT_USAGE,
+            CliError::Failed | CliError::Core(_) => EXIT_FAILURE,
+            CliError::Io(_) => EXIT_IO,
+        }
+    }
+}
+
+impl From<hexyg_core::Error> for CliError {
+    fn from(err: hexyg_core::Error) -> Self {
+        match err {
+            hexyg_core::Error::Io(err) => CliError::Io(err),
+            hexyg_core::Error::Config(message) => CliError::Usage(message),
+            err => CliError::Core(err),
+        }
+    }
+}
+
+impl From<codespan_reporting::files::Error> for CliError {
+    fn from(err: codespan_reporting::files::Error) -> Self {
+        match err {
+            codespan_reporting::files::Error::Io(err) => CliError::Io(err),
+            err => CliError::Io(io::Error::other(err)),
+        }
+    }
+}
@@ -1,183 +1,394 @@
-use anyhow::Result;
-use clap::Parser;
-use hexyg_core::{config::*, Config};
+mod cli;
+mod error;
+
+use cli::{CheckArgs, Cli, Command, ConfigArgs, DecodeArgs, EncodeArgs, FmtArgs};
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+use codespan_reporting::files::SimpleFile;
+use codespan_reporting::term::{
+    self,
+    termcolor::{ColorChoice, StandardStream},
+};
+use error::CliError;
+use hexyg_core::convert::{HexWriter, HexygWriter};
+use hexyg_core::{options, Config, Report, Settings, Severity, Slice, Template};
 use std::fs::File;
-use std::io::{self, BufReader, BufWriter};
-use std::path::PathBuf;
-
-#[derive(Parser)]
-#[command(name = "hexyg")]
-#[command(about = "Bidirectional converter between binary data and hex text format")]
-#[command(version)]
-#[command(disable_help_flag = true)]
-struct Cli {
-    /// Print help
-    #[arg(long = "help", short = '?', short_alias = 'h', action = clap::ArgAction::Help)]
-    help: Option<bool>,
-
-    /// Convert binary data to hex text format
-    #[arg(long = "bin-to-hex", group = "mode")]
-    bin_to_hex: bool,
-
-    /// Convert hex text to binary data
-    #[arg(long = "hex-to-bin", group = "mode")]
-    hex_to_bin: bool,
-
-    // Common options
-    /// Input file (if not specified, reads from stdin)
-    #[arg(long, short = 'i')]
-    input: Option<PathBuf>,
-
-    /// Output file (if not specified, writes to stdout)
-    #[arg(long, short = 'o')]
-    output: Option<PathBuf>,
-
-    /// Start address (decimal or 0x-prefixed hex, negative for end-based)
-    #[arg(long)]
-    from: Option<String>,
-
-    /// End address (exclusive, decimal or 0x-prefixed hex, negative for end-based)
-    #[arg(long)]
-    to: Option<String>,
-
-    // --bin-to-hex options
-    /// Set option in hex file: --option name=value
-    #[arg(long = "option", value_name = "NAME=VALUE")]
-    options: Vec<String>,
-
-    /// Start address in hex file (decimal or 0x-prefixed hex)
-    #[arg(long = "start-address")]
-    start_address: Option<String>,
-
-    /// Address size: u16, u24, u32, u64, u128, stretch
-    #[arg(long = "address-size")]
-    address_size: Option<String>,
-
-    /// Alignment step for address width
-    #[arg(long = "address-size-step")]
-    address_size_step: Option<usize>,
-
-    /// Line length in bytes (default: 16)
-    #[arg(long = "line-length")]
-    line_length: Option<usize>,
-
-    /// Split line into blocks of N bytes (default: 0, no blocking)
-    #[arg(long = "block-length")]
-    block_length: Option<usize>,
-
-    /// Byte separator (empty or whitespace)
-    #[arg(long = "byte-separator", default_value = " ")]
-    byte_separator: String,
-
-    /// Block separator (empty or whitespace)
-    #[arg(long = "block-separator", default_value = " ")]
-    block_separator: String,
-
-    /// Repeat address: never, once, every_line (default: every_line)
-    #[arg(long = "repeat-address", default_value = "every_line")]
-    repeat_address: String,
-
-    /// Show ASCII preview: true or false (default: true)
-    #[arg(long = "preview", default_value = "true")]
-    preview: String,
-
-    // --hex-to-bin options
-    /// Check consistency: none, text, values, all, text,values
-    #[arg(long = "check", default_value = "none")]
-    check: String,
-}
-
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-
-    // Check that a mode is specified
-    if !cli.bin_to_hex && !cli.hex_to_bin {
-        eprintln!("Error: Must specify either --bin-to-hex or --hex-to-bin");
-        eprintln!("Use --help for usage information");
-        std::process::exit(1);
-    }
-
-    // Build configuration
-    let mut config = Config::default();
-
-    // Parse address_size
-    if let Some(addr_size) = cli.address_size {
-        config.address_size = match addr_size.to_lowercase().as_str() {
-            "u16" => AddressSize::U16,
-            "u24" => AddressSize::U24,
-            "u32" => AddressSize::U32,
-            "u40" => AddressSize::U40,
-            "u48" => AddressSize::U48,
-            "u64" => AddressSize::U64,
-            "stretch" => AddressSize::Stretch,
-            _ => {
-                eprintln!("Invalid address-size: {}. Use u16, u24, u32, u40, u48, u64, or stretch", addr_size);
-                std::process::exit(1);
+use std::io::{self, BufReader, BufWriter, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+type Result<T> = std::result::Result<T, CliError>;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse_args();
+    let result = match &cli.command {
+        Command::Encode(args) => settings(&cli).and_then(|settings| encode(args, settings)),
+        Command::Decode(args) => settings(&cli).and_then(|settings| decode(args, settings)),
+        Command::Check(args) => settings(&cli).and_then(|settings| check(args, settings)),
+        Command::Fmt(args) => settings(&cli).and_then(|settings| fmt(args, settings)),
+        Command::Config(args) => settings(&cli).and_then(|settings| config(args, settings)),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            if !matches!(err, CliError::Failed) {
+                eprintln!("Error: {err}");
             }
-        };
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
+
+/// Options of the configuration file and the environment
+fn settings(cli: &Cli) -> Result<Settings> {
+    let dir = std::env::current_dir()?;
+    let env = std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)));
+    let settings = Settings::load(
+        cli.config_file.as_deref(),
+        cli.profile.as_deref(),
+        &dir,
+        env,
+    )?;
+    for warning in settings.warnings() {
+        eprintln!("Warning: {warning}");
+    }
+    Ok(settings)
+}
+
+fn encode(args: &EncodeArgs, settings: Settings) -> Result<()> {
+    // Unknown options are written as is, but a near miss is likely a typo
+    for (name, _) in &args.options {
+        if let (None, Some(suggestion)) = (options::find(name), options::suggest(name)) {
+            eprintln!("Warning: unknown option '{name}', did you mean '{suggestion}'?");
+        }
     }
+    let mut config = args.config(settings)?;
+    let slice = args.range.slice();
+    let (input, range) = encode_input(args.input.input.as_deref(), &slice)?;
+    config.start_address = args.start_address.unwrap_or(range.start);
+    let output = open_output(args.output.output.as_deref())?;
+
+    // Stdin read to its end has no known length
+    let len = (range.end != u64::MAX).then_some(range.end - range.start);
+    let mut input = input.take(range.end - range.start);
+    let template = args.format.template()?;
+    let mut hex_writer: Box<dyn HexWriter> = match &template {
+        Some(template) => Box::new(template.writer()),
+        None => Box::new(HexygWriter::default()),
+    };
+    hexyg_core::convert::bin_to_hex_with(&mut input, output, &config, len, &mut *hex_writer)?;
+    if range.end != u64::MAX && input.limit() > 0 {
+        // A pipe ended before `--to`
+        slice.resolve(range.end - input.limit())?;
+    }
+    Ok(())
+}
 
-    if let Some(line_length) = cli.line_length {
-        config.line_length = line_length;
+/// Binary input positioned at the start of `slice`, and the window of the slice
+///
+/// Files are seeked, stdin is skipped. An end-relative slice of stdin needs its
+/// length, so stdin is then read into memory. The end of the window is `u64::MAX`
+/// when stdin is read to its end.
+fn encode_input(path: Option<&Path>, slice: &Slice) -> Result<(Box<dyn Read>, Range<u64>)> {
+    if let Some(path) = path {
+        let mut file = File::open(path)?;
+        let range = slice.resolve(file.metadata()?.len())?;
+        file.seek(SeekFrom::Start(range.start))?;
+        return Ok((Box::new(BufReader::new(file)), range));
+    }
+    if slice.is_end_relative() {
+        let mut data = read_stdin()?;
+        let range = slice.resolve(data.len() as u64)?;
+        data.drain(..range.start as usize);
+        return Ok((Box::new(io::Cursor::new(data)), range));
     }
+    let range = slice.resolve(u64::MAX)?;
+    let mut input = io::stdin().lock();
+    let skipped = io::copy(&mut (&mut input).take(range.start), &mut io::sink())?;
+    if skipped < range.start {
+        slice.resolve(skipped)?;
+    }
+    Ok((Box::new(input), range))
+}
 
-    if let Some(block_length) = cli.block_length {
-        config.block_length = block_length;
+fn decode(args: &DecodeArgs, settings: Settings) -> Result<()> {
+    let config = settings.into_config();
+    if let Some(template) = args.format.template()? {
+        return decode_template(args, &template, &config);
     }
+    let check_options = args.check_options();
+    let consistency_check = check_options.text || check_options.values;
+    let path = args.input.input.as_deref();
+
+    // Keep stdin in memory so that errors can be shown with source snippets,
+    // and files too when they are read twice for a consistency check
+    let source = match path {
+        Some(path) if consistency_check => Some(std::fs::read(path)?),
+        Some(_) => None,
+        None => Some(read_stdin()?),
+    };
+    let name = source_name(path);
 
-    // Parse repeat_address
-    config.repeat_address = match cli.repeat_address.as_str() {
-        "every_line" => true,
-        "never" | "once" => false,
-        _ => {
-            eprintln!("Invalid repeat-address: {}. Use never, once, or every_line", cli.repeat_address);
-            std::process::exit(1);
+    if consistency_check {
+        let source = source.as_deref().unwrap_or_default();
+        let report = hexyg_core::check_with(source, &config, &check_options).with_file(&name);
+        if !report.diagnostics.is_empty() {
+            report_diagnostics(&report, &String::from_utf8_lossy(source))?;
         }
+        if !report.is_ok() {
+            return Err(CliError::Failed);
+        }
+    }
+
+    let input: Box<dyn io::Read> = match (path, &source) {
+        (_, Some(source)) => Box::new(&source[..]),
+        (Some(path), None) => Box::new(BufReader::new(File::open(path)?)),
+        (None, None) => Box::new(io::empty()),
     };
+    let output = open_output(args.output.output.as_deref())?;
 
-    // Parse preview
-    config.show_preview = match cli.preview.to_lowercase().as_str() {
-        "true" | "yes" | "1" => true,
-        "false" | "no" | "0" => false,
-        _ => {
-            eprintln!("Invalid preview value: {}. Use true or false", cli.preview);
-            std::process::exit(1);
+    let slice = args.range.slice();
+    if let Err(err) = hexyg_core::convert::hex_to_bin_slice(input, output, &config, &slice) {
+        if err.location().is_none() {
+            return Err(err.into());
+        }
+        // Check the whole input again to report every problem, not only the first one
+        let source = match (path, source) {
+            (_, Some(source)) => source,
+            (Some(path), None) => std::fs::read(path)?,
+            (None, None) => Vec::new(),
+        };
+        let mut report =
+            hexyg_core::check_with(&source[..], &config, &check_options).with_file(&name);
+        if report.is_ok() {
+            report.diagnostics.push(err.with_file(name).into());
         }
+        report_diagnostics(&report, &String::from_utf8_lossy(&source))?;
+        return Err(CliError::Failed);
+    }
+    Ok(())
+}
+
+/// Decode text written with a line template
+fn decode_template(args: &DecodeArgs, template: &Template, config: &Config) -> Result<()> {
+    let check_options = args.check_options();
+    if check_options.text || check_options.values {
+        return Err(CliError::Usage(
+            "--check cannot be used with --format-line or --format-file".to_string(),
+        ));
+    }
+    let input: Box<dyn Read> = match args.input.input.as_deref() {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(io::stdin().lock()),
     };
+    let output = open_output(args.output.output.as_deref())?;
+    let slice = args.range.slice();
+    let name = source_name(args.input.input.as_deref());
+    hexyg_core::convert::write_slice(output, &slice, |output| {
+        template.read(input, output, config)
+    })
+    .map_err(|err| err.with_file(name).into())
+}
 
-    // Note: byte_separator, block_separator, from, to, start_address, check are parsed but not yet used
-    // These will be implemented in future iterations
+fn check(args: &CheckArgs, settings: Settings) -> Result<()> {
+    let path = args.input.input.as_deref();
+    let source = match path {
+        Some(path) => std::fs::read(path)?,
+        None => read_stdin()?,
+    };
+    let report = hexyg_core::check_with(&source[..], settings.config(), &args.check_options())
+        .with_file(&source_name(path));
+    if !report.diagnostics.is_empty() {
+        report_diagnostics(&report, &String::from_utf8_lossy(&source))?;
+    }
+    if !report.is_ok() {
+        return Err(CliError::Failed);
+    }
+    Ok(())
+}
 
-    // Execute command
-    if cli.bin_to_hex {
-        let input: Box<dyn io::Read> = if let Some(path) = cli.input {
-            Box::new(BufReader::new(File::open(path)?))
-        } else {
-            Box::new(io::stdin().lock())
-        };
+/// Print the effective options, with `--explain` also the layers that set them
+fn config(args: &ConfigArgs, settings: Settings) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    if !args.explain {
+        let text =
+            toml::to_string(settings.config()).map_err(|err| CliError::Usage(err.to_string()))?;
+        stdout.write_all(text.as_bytes())?;
+        return Ok(());
+    }
+    let rows = settings.explain();
+    let width =
+        |column: fn(&(String, String, _)) -> usize| rows.iter().map(column).max().unwrap_or(0);
+    let name_width = width(|(name, _, _)| name.len());
+    let value_width = width(|(_, value, _)| format!("{:?}", value).len());
+    for (name, value, layer) in &rows {
+        writeln!(
+            stdout,
+            "{:name_width$} = {:value_width$}  # {}",
+            name,
+            format!("{:?}", value),
+            layer
+        )?;
+    }
+    Ok(())
+}
 
-        let output: Box<dyn io::Write> = if let Some(path) = cli.output {
-            Box::new(BufWriter::new(File::create(path)?))
-        } else {
-            Box::new(io::stdout().lock())
-        };
+/// Format the files of `args`; fails if any of them is invalid or, with `--check`, not formatted
+fn fmt(args: &FmtArgs, settings: Settings) -> Result<()> {
+    if fmt_files(args, settings.config())? {
+        Ok(())
+    } else {
+        Err(CliError::Failed)
+    }
+}
 
-        hexyg_core::convert::bin_to_hex(input, output, &config)?;
-    } else if cli.hex_to_bin {
-        let input: Box<dyn io::Read> = if let Some(path) = cli.input {
-            Box::new(BufReader::new(File::open(path)?))
-        } else {
-            Box::new(io::stdin().lock())
+/// Returns whether all files were valid and, with `--check`, already formatted
+fn fmt_files(args: &FmtArgs, config: &Config) -> Result<bool> {
+    if args.paths.is_empty() {
+        let source = read_stdin()?;
+        let Some(formatted) = format_source(&source, "<stdin>", config)? else {
+            return Ok(false);
         };
+        let source = String::from_utf8(source).expect("formatted input is UTF-8");
+        if args.check {
+            return Ok(print_diff(&source, &formatted, "<stdin>"));
+        }
+        io::stdout().lock().write_all(formatted.as_bytes())?;
+        return Ok(true);
+    }
 
-        let output: Box<dyn io::Write> = if let Some(path) = cli.output {
-            Box::new(BufWriter::new(File::create(path)?))
-        } else {
-            Box::new(io::stdout().lock())
+    let mut files = Vec::new();
+    for path in &args.paths {
+        collect_hex_files(path, &mut files)?;
+    }
+    let mut ok = true;
+    for file in files {
+        let name = file.display().to_string();
+        let source = std::fs::read(&file)?;
+        let Some(formatted) = format_source(&source, &name, config)? else {
+            ok = false;
+            continue;
         };
+        if formatted.as_bytes() == source {
+            continue;
+        }
+        if args.check {
+            let source = String::from_utf8(source).expect("formatted input is UTF-8");
+            ok &= print_diff(&source, &formatted, &name);
+        } else {
+            std::fs::write(&file, formatted)?;
+        }
+    }
+    Ok(ok)
+}
 
-        hexyg_core::convert::hex_to_bin(input, output)?;
+/// Formatted text of a hex file, or `None` after reporting why it cannot be formatted
+///
+/// `config` holds the options in effect before the first `#option` of the file.
+fn format_source(source: &[u8], name: &str, config: &Config) -> Result<Option<String>> {
+    let Ok(text) = std::str::from_utf8(source) else {
+        eprintln!("{name}: not a UTF-8 text file");
+        return Ok(None);
+    };
+    match hexyg_core::format(text, config) {
+        Ok(formatted) => Ok(Some(formatted)),
+        Err(err) => {
+            let report = Report {
+                diagnostics: vec![err.with_file(name).into()],
+                truncated: false,
+            };
+            report_diagnostics(&report, text)?;
+            Ok(None)
+        }
     }
+}
 
+/// Add `path` if it is a file, or the `*.hex` files under it if it is a directory
+fn collect_hex_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = std::fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            collect_hex_files(&entry, files)?;
+        } else if entry.extension().is_some_and(|ext| ext == "hex") {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
+
+/// Print a unified diff from `source` to `formatted`; returns whether they are equal
+fn print_diff(source: &str, formatted: &str, name: &str) -> bool {
+    if source == formatted {
+        return true;
+    }
+    let diff = similar::TextDiff::from_lines(source, formatted);
+    print!("{}", diff.unified_diff().header(name, name));
+    false
+}
+
+fn read_stdin() -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    io::stdin().lock().read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn open_output(path: Option<&Path>) -> Result<Box<dyn io::Write>> {
+    Ok(match path {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    })
+}
+
+/// File name shown in diagnostics
+fn source_name(path: Option<&Path>) -> String {
+    match path {
+        Some(path) => path.display().to_string(),
+        None => "<stdin>".to_string(),
+    }
+}
+
+/// Print diagnostics with source snippets and carets under the offending text
+fn report_diagnostics(report: &Report, source: &str) -> Result<()> {
+    let color = if io::stderr().is_terminal() {
+        ColorChoice::Auto
+    } else {
+        ColorChoice::Never
+    };
+    let writer = StandardStream::stderr(color);
+    let mut writer = writer.lock();
+    let config = term::Config::default();
+
+    for diagnostic in &report.diagnostics {
+        let severity = match diagnostic.severity {
+            Severity::Error => codespan_reporting::diagnostic::Severity::Error,
+            Severity::Warning => codespan_reporting::diagnostic::Severity::Warning,
+            Severity::Info => codespan_reporting::diagnostic::Severity::Note,
+        };
+        let location = diagnostic.location.clone().unwrap_or_default();
+        let file = SimpleFile::new(location.file.unwrap_or_default(), source);
+        let mut rendered = Diagnostic::new(severity).with_message(&diagnostic.message);
+        if diagnostic.location.is_some() {
+            let end = location.span.end.min(source.len());
+            let start = location.span.start.min(end);
+            rendered = rendered.with_labels(vec![Label::primary((), start..end)]);
+        }
+        term::emit(&mut writer, &config, &file, &rendered)?;
+    }
+
+    if report.truncated {
+        writeln!(
+            writer,
+            "too many errors, stopped after {}",
+            report.error_count()
+        )?;
+    }
     Ok(())
 }
@@ -0,0 +1,486 @@
+//! Checking hex text without producing binary output
+
+use crate::convert::AddressedWriter;
+use crate::diagnostic::{Diagnostic, Report};
+use crate::error::Location;
+use crate::lexer::Span;
+use crate::options;
+use crate::parser::{Annotation, Event, Parser};
+use crate::preview::preview_text;
+use crate::types::{decode, encode, struct_field_value, Type, TypeRegistry, Value};
+use crate::{Config, Error, Result};
+use std::io::{self, BufReader, Read, Write};
+
+/// Options for [`check_with`]
+#[derive(Debug, Clone, Default)]
+pub struct CheckOptions {
+    /// Stop after this many errors, `None` for no limit
+    pub max_errors: Option<usize>,
+    /// Verify that `[preview]` texts match the bytes on their line
+    pub text: bool,
+    /// Verify that annotation values match the bytes they describe
+    pub values: bool,
+}
+
+impl CheckOptions {
+    pub fn with_text(mut self, text: bool) -> Self {
+        self.text = text;
+        self
+    }
+
+    pub fn with_values(mut self, values: bool) -> Self {
+        self.values = values;
+        self
+    }
+}
+
+/// Check hex text with default options and collect all diagnostics
+pub fn check<R: Read>(reader: R) -> Report {
+    check_with(reader, &Config::default(), &CheckOptions::default())
+}
+
+/// Check hex text and collect all diagnostics
+///
+/// Syntax errors are recovered at line boundaries. Address errors are reported and
+/// checking continues from the declared address. Previews and annotation values are
+/// compared with the bytes when enabled in `options`.
+pub fn check_with<R: Read>(reader: R, config: &Config, options: &CheckOptions) -> Report {
+    let mut report = Report::default();
+    let mut output = AddressedWriter::new(ValueTracker::default(), config.padding);
+    let mut parser = Parser::with_config(BufReader::new(reader), config.clone());
+    // Bytes of the last data line and the source line they were written on
+    let mut line_bytes: Option<(Vec<u8>, usize)> = None;
+
+    while let Some(event) = parser.next() {
+        let mut warning = None;
+        let result = match event {
+            Ok(Event::Option { name, span, .. }) => {
+                output.padding = parser.config().padding;
+                // Unknown options are kept as metadata, but a near miss is likely a typo
+                if let (None, Some(suggestion)) = (options::find(&name), options::suggest(&name)) {
+                    warning = Some(Diagnostic::warning(
+                        Location::new(span),
+                        format!("unknown option '{}', did you mean '{}'?", name, suggestion),
+                    ));
+                }
+                Ok(())
+            }
+            Ok(Event::Address { address, span }) => {
+                output.get_mut().end_line();
+                let result = output
+                    .seek(address, span)
+                    .inspect_err(|_| output.resync(address));
+                let position = output.position();
+                output.get_mut().start_line(position);
+                result
+            }
+            Ok(Event::Bytes { data, span }) => {
+                let position = output.position();
+                output.get_mut().start_line(position);
+                let result = output.write_all(&data);
+                line_bytes = Some((data, span.line));
+                result
+            }
+            Ok(Event::Preview { text, span }) if options.text => {
+                let data = match &line_bytes {
+                    Some((data, line)) if *line == span.line => data.as_slice(),
+                    _ => &[],
+                };
+                let expected = preview_text(data, parser.config());
+                if text == expected {
+                    Ok(())
+                } else {
+                    Err(Error::parse(
+                        span,
+                        format!("preview [{}] does not match data [{}]", text, expected),
+                    ))
+                }
+            }
+            Ok(Event::Annotation(annotation)) if options.values => {
+                expected_values(&annotation, parser.types())
+                    .map(|(values, size)| output.get_mut().expect(annotation.offset, values, size))
+                    .map_err(|e| Error::parse(annotation.span, e.to_string()))
+            }
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        };
+
+        report.diagnostics.extend(warning);
+        let mut errors: Vec<Diagnostic> = result.err().map(Diagnostic::from).into_iter().collect();
+        errors.extend(output.get_mut().take_mismatches());
+        for diagnostic in errors {
+            report.diagnostics.push(diagnostic);
+            if options
+                .max_errors
+                .is_some_and(|max| report.error_count() >= max)
+            {
+                report.truncated = true;
+                return report;
+            }
+        }
+    }
+
+    for value in output.get_mut().pending.drain(..) {
+        report.diagnostics.push(Diagnostic::error(
+            Location::new(value.span),
+            format!("{} extends beyond the end of data", value.description()),
+        ));
+    }
+
+    report
+}
+
+/// Annotation value waiting for the bytes it describes
+#[derive(Debug)]
+struct ExpectedValue {
+    /// Address of the first byte, relative to the annotation start until registered
+    address: u64,
+    /// Field path for struct members, e.g. `data.name_y`
+    path: Option<String>,
+    ty: Type,
+    value: Value,
+    expected: Vec<u8>,
+    actual: Vec<u8>,
+    span: Span,
+}
+
+impl ExpectedValue {
+    fn is_complete(&self) -> bool {
+        self.actual.len() == self.expected.len()
+    }
+
+    fn description(&self) -> String {
+        match &self.path {
+            Some(path) => format!("field {}: {} value {}", path, self.ty.name(), self.value),
+            None => format!("{} value {}", self.ty.name(), self.value),
+        }
+    }
+
+    /// Actual bytes as a value of the annotation type, or as hex if they do not decode
+    fn found(&self) -> String {
+        match decode(&self.ty, &self.actual) {
+            Ok(value) => value.to_string(),
+            Err(_) => self
+                .actual
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
+}
+
+/// Binary output of the checker that verifies annotation values as bytes arrive
+#[derive(Debug, Default)]
+struct ValueTracker {
+    /// Address of the next byte to be written
+    position: u64,
+    /// Address of the first byte of the current data line
+    line_start: u64,
+    /// Address for the next sequential annotation
+    cursor: u64,
+    /// Bytes written since `line_start`
+    line: Vec<u8>,
+    /// Whether written bytes belong to a data line; gap padding is not kept
+    in_line: bool,
+    pending: Vec<ExpectedValue>,
+    mismatches: Vec<Diagnostic>,
+}
+
+impl ValueTracker {
+    fn start_line(&mut self, address: u64) {
+        self.position = address;
+        self.line_start = address;
+        self.cursor = address;
+        self.line.clear();
+        self.in_line = true;
+    }
+
+    /// Stop keeping written bytes until the next line starts
+    fn end_line(&mut self) {
+        self.line = Vec::new();
+        self.in_line = false;
+    }
+
+    /// Register values of an annotation on the current line
+    ///
+    /// Offset annotations start at the line address, others continue after the
+    /// previous annotation.
+    fn expect(&mut self, offset: Option<u64>, values: Vec<ExpectedValue>, size: u64) {
+        let start = match offset {
+            Some(offset) => self.line_start + offset,
+            None => self.cursor,
+        };
+        for mut value in values {
+            value.address += start;
+            if value.address < self.position {
+                let start = (value.address - self.line_start) as usize;
+                let end = (start + value.expected.len()).min(self.line.len());
+                value.actual.extend_from_slice(&self.line[start..end]);
+            }
+            self.settle(value);
+        }
+        self.cursor = start + size;
+    }
+
+    /// Compare a complete value or keep it until its bytes are written
+    fn settle(&mut self, value: ExpectedValue) {
+        if !value.is_complete() {
+            self.pending.push(value);
+        } else if value.actual != value.expected {
+            self.mismatches.push(Diagnostic::error(
+                Location::new(value.span),
+                format!(
+                    "{} does not match data, found {}",
+                    value.description(),
+                    value.found()
+                ),
+            ));
+        }
+    }
+
+    fn take_mismatches(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.mismatches)
+    }
+}
+
+impl Write for ValueTracker {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let end = self.position + buf.len() as u64;
+        for mut value in std::mem::take(&mut self.pending) {
+            let next = value.address + value.actual.len() as u64;
+            if next >= self.position && next < end {
+                let start = (next - self.position) as usize;
+                let missing = value.expected.len() - value.actual.len();
+                let stop = (start + missing).min(buf.len());
+                value.actual.extend_from_slice(&buf[start..stop]);
+            }
+            self.settle(value);
+        }
+        if self.in_line {
+            self.line.extend_from_slice(buf);
+        }
+        self.position = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Expected bytes of an annotation and its total size
+///
+/// Struct values are flattened into their fields. Addresses of the returned values
+/// are relative to the annotation start.
+fn expected_values(
+    annotation: &Annotation,
+    types: &TypeRegistry,
+) -> Result<(Vec<ExpectedValue>, u64)> {
+    let ty = types.resolve(&annotation.type_name)?;
+    let value = Value::from(&annotation.value);
+    // Encode the whole value first to report missing or unknown fields
+    let size = encode(&ty, &value)?.len() as u64;
+    let mut values = Vec::new();
+    flatten(ty, value, 0, None, annotation.span, &mut values)?;
+    Ok((values, size))
+}
+
+fn flatten(
+    ty: Type,
+    value: Value,
+    address: u64,
+    path: Option<String>,
+    span: Span,
+    values: &mut Vec<ExpectedValue>,
+) -> Result<()> {
+    match (ty, value) {
+        (Type::Struct(st), Value::Struct(fields)) => {
+            let path = path.unwrap_or_else(|| st.name.clone());
+            for field in &st.fields {
+                let value = struct_field_value(&st, &fields, &field.name)?.clone();
+                flatten(
+                    field.ty.clone(),
+                    value,
+                    address + field.offset as u64,
+                    Some(format!("{}.{}", path, field.name)),
+                    span,
+                    values,
+                )?;
+            }
+        }
+        (ty, value) => {
+            let expected = encode(&ty, &value)?;
+            values.push(ExpectedValue {
+                address,
+                path,
+                ty,
+                value,
+                expected,
+                actual: Vec::new(),
+                span,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Padding;
+    use crate::diagnostic::Severity;
+
+    const INPUT: &str = "#option padding=forbidden\n\
+                         00000000: 00 01 02 03\n\
+                         00000004: 04 ZZ\n\
+                         00000008: 08\n\
+                         00000006: 06 | u8=\n\
+                         00000007: 07\n";
+
+    #[test]
+    fn test_check_collects_all_diagnostics() {
+        let report = check(INPUT.as_bytes());
+
+        let messages: Vec<String> = report.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "line 3, column 14: error: expected hex byte, found 'ZZ'",
+                "line 4, column 1: error: gap between 00000004 and 00000008 is not allowed with padding=forbidden",
+                "line 5, column 1: error: address 00000006 goes backwards from 00000008",
+                "line 5, column 19: error: expected value, found end of line",
+            ]
+        );
+        assert!(report
+            .diagnostics
+            .iter()
+            .all(|d| d.severity == Severity::Error));
+        assert_eq!(report.error_count(), 4);
+        assert!(!report.truncated);
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn test_check_max_errors() {
+        let options = CheckOptions {
+            max_errors: Some(2),
+            ..CheckOptions::default()
+        };
+        let report = check_with(INPUT.as_bytes(), &Config::default(), &options);
+
+        assert_eq!(report.error_count(), 2);
+        assert!(report.truncated);
+    }
+
+    #[test]
+    fn test_check_reference_corpus() {
+        let input = include_str!("../../../tests/corpus/valid/01_reference.hex");
+        let options = CheckOptions::default().with_text(true).with_values(true);
+        let report = check_with(input.as_bytes(), &Config::default(), &options)
+            .with_file("01_reference.hex");
+
+        assert!(report.diagnostics.is_empty(), "{:?}", report);
+    }
+
+    fn messages(input: &str, options: &CheckOptions) -> Vec<String> {
+        check_with(input.as_bytes(), &Config::default(), options)
+            .diagnostics
+            .iter()
+            .map(|d| d.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_check_unknown_options() {
+        let input = "#option line_lenght=8\n#option author=me\n#option repeat_adress=once\n00\n";
+        let report = check(input.as_bytes());
+
+        assert_eq!(
+            report
+                .diagnostics
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>(),
+            vec!["line 1, column 1: warning: unknown option 'line_lenght', did you mean 'line_length'?"]
+        );
+        assert!(report.is_ok());
+    }
+
+    #[test]
+    fn test_check_text() {
+        let input = "00000000: 48 69 00 7F [Hi..]\n\
+                     00000004: 48 69 [Ho]\n\
+                     [orphan]\n";
+        let options = CheckOptions::default().with_text(true);
+
+        assert_eq!(
+            messages(input, &options),
+            vec![
+                "line 2, column 17: error: preview [Ho] does not match data [Hi]",
+                "line 3, column 1: error: preview [orphan] does not match data []",
+            ]
+        );
+        assert!(messages(input, &CheckOptions::default()).is_empty());
+    }
+
+    #[test]
+    fn test_check_values() {
+        let input = "00000000: FF 00 00 00 EE EE 00 00 | +0:u32=255 +03:u32=61166\n\
+                     00000008: 01 00 02 00 03 | u16=1 u16=3 u8=3\n\
+                     #option endian=BIG_ENDIAN\n\
+                     0000000D: 00 01 | u16=1\n\
+                     0000000F: 48 65 6C | utf8=\"Hello\"\n\
+                     \x20         6C 6F\n\
+                     00000014: 01 | u8=300 u8=\"x\" i7=1\n\
+                     00000015: 02 | u16=2\n";
+        let options = CheckOptions::default().with_values(true);
+
+        assert_eq!(
+            messages(input, &options),
+            vec![
+                "line 1, column 48: error: u32 value 61166 does not match data, found 15658496",
+                "line 2, column 34: error: u16 value 3 does not match data, found 2",
+                "line 7, column 16: error: value 300 does not fit into u8",
+                "line 7, column 23: error: u8 value must be an integer",
+                "line 7, column 30: error: unknown type 'i7'",
+                "line 8, column 16: error: u16 value 2 extends beyond the end of data",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_large_gap() {
+        // padding still completes values, but is not kept as bytes of a line
+        let input = "00: 01 | u16=1\n20000000: 02 [.] | u8=2\n";
+        let options = CheckOptions::default().with_text(true).with_values(true);
+        assert!(messages(input, &options).is_empty());
+
+        let mut output = AddressedWriter::new(ValueTracker::default(), Padding::Value(0));
+        output.get_mut().start_line(0);
+        output.write_all(&[1]).unwrap();
+        output.get_mut().end_line();
+        output.seek(0x2000_0000, Span::default()).unwrap();
+        assert_eq!(output.get_mut().line.capacity(), 0);
+        assert_eq!(output.position(), 0x2000_0000);
+    }
+
+    #[test]
+    fn test_check_struct_values() {
+        let input = "#struct point { x:u16, y:u16 }\n\
+                     #struct segment { from:point, to:point }\n\
+                     00000000: 01 00 02 00 03 00 05 00 | segment={from={x=1, y=2}, to={x=3, y=4}}\n\
+                     00000008: 01 00 02 00 | point={x=1}\n\
+                     0000000C: 01 00 02 00 | point={x=1, y=2, z=3}\n";
+        let options = CheckOptions::default().with_values(true);
+
+        assert_eq!(
+            messages(input, &options),
+            vec![
+                "line 3, column 37: error: field segment.to.y: u16 value 4 does not match data, found 5",
+                "line 4, column 25: error: missing value for field 'y' of struct 'point'",
+                "line 5, column 25: error: struct 'point' has no field 'z'",
+            ]
+        );
+    }
+}
@@ -1,37 +1,387 @@
 //! Configuration for hexyg conversion operations
 
+use crate::options::{self, ValueKind, OPTIONS};
+use crate::{Error, Result};
+use serde::de::Error as _;
+use serde::ser::{Error as _, SerializeMap};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
 /// Byte order (endianness)
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum Endian {
     /// Little endian byte order
+    #[default]
     Little,
     /// Big endian byte order
     Big,
 }
 
-impl Default for Endian {
-    fn default() -> Self {
-        Endian::Little
+impl FromStr for Endian {
+    type Err = Error;
+
+    /// Parse `LITTLE_ENDIAN`/`BIG_ENDIAN` or the short forms `little`, `le`, `big`, `be`
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "little_endian" | "little" | "le" => Ok(Endian::Little),
+            "big_endian" | "big" | "be" => Ok(Endian::Big),
+            _ => Err(Error::Config(format!(
+                "invalid endian '{}': expected LITTLE_ENDIAN or BIG_ENDIAN",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Endian {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endian::Little => write!(f, "LITTLE_ENDIAN"),
+            Endian::Big => write!(f, "BIG_ENDIAN"),
+        }
     }
 }
 
 /// Address size in bytes
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum AddressSize {
     U8,
     U16,
     U24,
+    #[default]
     U32,
     U40,
     U48,
     U64,
-    /// Automatically adjust based on data size
+    U128,
+    /// As wide as the final address of the data, in steps of `address_size_step`
     Stretch,
 }
 
-impl Default for AddressSize {
-    fn default() -> Self {
-        AddressSize::U32
+impl AddressSize {
+    /// Number of hex digits of an address, `None` for [`AddressSize::Stretch`]
+    pub fn digits(self) -> Option<usize> {
+        match self {
+            AddressSize::U8 => Some(2),
+            AddressSize::U16 => Some(4),
+            AddressSize::U24 => Some(6),
+            AddressSize::U32 => Some(8),
+            AddressSize::U40 => Some(10),
+            AddressSize::U48 => Some(12),
+            AddressSize::U64 => Some(16),
+            AddressSize::U128 => Some(32),
+            AddressSize::Stretch => None,
+        }
+    }
+
+    /// Whether `address` fits into the digits of the size; any address fits a
+    /// stretched one
+    pub fn fits(self, address: u64) -> bool {
+        self.digits()
+            .is_none_or(|digits| fits_digits(address, digits))
+    }
+}
+
+/// Whether `address` can be written in `digits` hex digits
+pub(crate) fn fits_digits(address: u64, digits: usize) -> bool {
+    digits >= 16 || address >> (4 * digits) == 0
+}
+
+impl FromStr for AddressSize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "u8" => Ok(AddressSize::U8),
+            "u16" => Ok(AddressSize::U16),
+            "u24" => Ok(AddressSize::U24),
+            "u32" => Ok(AddressSize::U32),
+            "u40" => Ok(AddressSize::U40),
+            "u48" => Ok(AddressSize::U48),
+            "u64" => Ok(AddressSize::U64),
+            "u128" => Ok(AddressSize::U128),
+            "stretch" => Ok(AddressSize::Stretch),
+            _ => Err(Error::Config(format!(
+                "invalid address size '{}': expected u8, u16, u24, u32, u40, u48, u64, u128 or stretch",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for AddressSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AddressSize::U8 => "u8",
+            AddressSize::U16 => "u16",
+            AddressSize::U24 => "u24",
+            AddressSize::U32 => "u32",
+            AddressSize::U40 => "u40",
+            AddressSize::U48 => "u48",
+            AddressSize::U64 => "u64",
+            AddressSize::U128 => "u128",
+            AddressSize::Stretch => "stretch",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Data lines that start with their address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RepeatAddress {
+    /// No line; the data is written from address 0 with holes filled with padding
+    Never,
+    /// The first line only; holes are filled with padding
+    Once,
+    /// The first line and the first line after every hole
+    OnDiscontinuity,
+    /// Every line
+    #[default]
+    EveryLine,
+}
+
+impl RepeatAddress {
+    /// Whether the data is written as one continuous block, holes filled with padding
+    pub fn is_continuous(self) -> bool {
+        matches!(self, RepeatAddress::Never | RepeatAddress::Once)
+    }
+}
+
+impl FromStr for RepeatAddress {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "never" => Ok(RepeatAddress::Never),
+            "once" => Ok(RepeatAddress::Once),
+            "on_discontinuity" => Ok(RepeatAddress::OnDiscontinuity),
+            "every_line" => Ok(RepeatAddress::EveryLine),
+            _ => Err(Error::Config(format!(
+                "invalid repeat_address '{}': expected never, once, on_discontinuity or every_line",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for RepeatAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RepeatAddress::Never => "never",
+            RepeatAddress::Once => "once",
+            RepeatAddress::OnDiscontinuity => "on_discontinuity",
+            RepeatAddress::EveryLine => "every_line",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Radix of the data bytes of a hex file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Radix {
+    /// 8 binary digits per byte
+    Binary,
+    /// 3 octal digits per byte
+    Octal,
+    /// 3 decimal digits per byte
+    Decimal,
+    /// 2 hex digits per byte
+    #[default]
+    Hex,
+}
+
+impl Radix {
+    /// Base of the digits
+    pub fn base(self) -> u32 {
+        match self {
+            Radix::Binary => 2,
+            Radix::Octal => 8,
+            Radix::Decimal => 10,
+            Radix::Hex => 16,
+        }
+    }
+
+    /// Number of digits of a byte, with leading zeros
+    pub fn digits(self) -> usize {
+        match self {
+            Radix::Binary => 8,
+            Radix::Octal | Radix::Decimal => 3,
+            Radix::Hex => 2,
+        }
+    }
+
+    /// Name of the digits in messages, such as `binary`
+    pub fn name(self) -> &'static str {
+        match self {
+            Radix::Binary => "binary",
+            Radix::Octal => "octal",
+            Radix::Decimal => "decimal",
+            Radix::Hex => "hex",
+        }
+    }
+
+    /// Digits of `byte`; hex digits are uppercase
+    pub fn format(self, byte: u8) -> String {
+        match self {
+            Radix::Binary => format!("{:08b}", byte),
+            Radix::Octal => format!("{:03o}", byte),
+            Radix::Decimal => format!("{:03}", byte),
+            Radix::Hex => format!("{:02X}", byte),
+        }
+    }
+}
+
+impl FromStr for Radix {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "2" => Ok(Radix::Binary),
+            "8" => Ok(Radix::Octal),
+            "10" => Ok(Radix::Decimal),
+            "16" => Ok(Radix::Hex),
+            _ => Err(Error::Config(format!(
+                "invalid radix '{}': expected 2, 8, 10 or 16",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Radix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.base())
+    }
+}
+
+/// Size of the words the bytes of a data line are grouped into
+///
+/// Words are counted from the start of each line. A word is written as one number
+/// in the document's endianness, so with [`Endian::Little`] its bytes are written
+/// in reverse order, like `xxd -e`; a partial word at the end of a line is reversed
+/// as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Word {
+    /// Single bytes, no grouping
+    #[default]
+    U8,
+    /// 16-bit words
+    U16,
+    /// 32-bit words
+    U32,
+    /// 64-bit words
+    U64,
+}
+
+impl Word {
+    /// Size of a word in bytes
+    pub fn size(self) -> usize {
+        match self {
+            Word::U8 => 1,
+            Word::U16 => 2,
+            Word::U32 => 4,
+            Word::U64 => 8,
+        }
+    }
+
+    /// Swap between the memory order of `data` and the order it is written in
+    ///
+    /// Reverses the bytes of every word for [`Endian::Little`] and does nothing for
+    /// [`Endian::Big`]; swapping twice restores the bytes.
+    pub fn swap(self, data: &mut [u8], endian: Endian) {
+        if endian == Endian::Little {
+            for word in data.chunks_mut(self.size()) {
+                word.reverse();
+            }
+        }
+    }
+}
+
+impl FromStr for Word {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "u8" => Ok(Word::U8),
+            "u16" => Ok(Word::U16),
+            "u32" => Ok(Word::U32),
+            "u64" => Ok(Word::U64),
+            _ => Err(Error::Config(format!(
+                "invalid word '{}': expected u8, u16, u32 or u64",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Word {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "u{}", self.size() * 8)
+    }
+}
+
+/// Character encoding of the preview column
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PreviewEncoding {
+    /// Printable ASCII, 0x20..=0x7E
+    #[default]
+    Ascii,
+    /// ISO-8859-1
+    Latin1,
+    /// IBM PC code page 437
+    Cp437,
+    /// Windows-1251
+    Cp1251,
+    /// KOI8-R
+    Koi8R,
+    /// EBCDIC code page 037
+    Ebcdic037,
+    /// UTF-8, a character spread across the cells of its bytes
+    Utf8,
+    /// UTF-16 little endian
+    Utf16Le,
+    /// UTF-16 big endian
+    Utf16Be,
+}
+
+impl FromStr for PreviewEncoding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "ascii" => Ok(PreviewEncoding::Ascii),
+            "latin1" | "iso-8859-1" => Ok(PreviewEncoding::Latin1),
+            "cp437" => Ok(PreviewEncoding::Cp437),
+            "cp1251" | "windows-1251" => Ok(PreviewEncoding::Cp1251),
+            "koi8-r" | "koi8r" => Ok(PreviewEncoding::Koi8R),
+            "ebcdic-037" | "cp037" => Ok(PreviewEncoding::Ebcdic037),
+            "utf8" | "utf-8" => Ok(PreviewEncoding::Utf8),
+            "utf16le" | "utf-16le" => Ok(PreviewEncoding::Utf16Le),
+            "utf16be" | "utf-16be" => Ok(PreviewEncoding::Utf16Be),
+            _ => Err(Error::Config(format!(
+                "invalid preview_encoding '{}': expected ascii, latin1, cp437, cp1251, koi8-r, ebcdic-037, utf8, utf16le or utf16be",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for PreviewEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PreviewEncoding::Ascii => "ascii",
+            PreviewEncoding::Latin1 => "latin1",
+            PreviewEncoding::Cp437 => "cp437",
+            PreviewEncoding::Cp1251 => "cp1251",
+            PreviewEncoding::Koi8R => "koi8-r",
+            PreviewEncoding::Ebcdic037 => "ebcdic-037",
+            PreviewEncoding::Utf8 => "utf8",
+            PreviewEncoding::Utf16Le => "utf16le",
+            PreviewEncoding::Utf16Be => "utf16be",
+        };
+        write!(f, "{}", name)
     }
 }
 
@@ -50,8 +400,38 @@ impl Default for Padding {
     }
 }
 
+impl FromStr for Padding {
+    type Err = Error;
+
+    /// Parse `forbidden` or a two-digit hex byte such as `00` or `FF`
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("forbidden") {
+            return Ok(Padding::Forbidden);
+        }
+        if s.len() == 2 {
+            if let Ok(byte) = u8::from_str_radix(s, 16) {
+                return Ok(Padding::Value(byte));
+            }
+        }
+        Err(Error::Config(format!(
+            "invalid padding '{}': expected a hex byte (00..FF) or 'forbidden'",
+            s
+        )))
+    }
+}
+
+impl fmt::Display for Padding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Padding::Value(byte) => write!(f, "{:02X}", byte),
+            Padding::Forbidden => write!(f, "forbidden"),
+        }
+    }
+}
+
 /// Configuration for hex conversion
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Config {
     /// Byte order
     pub endian: Endian,
@@ -59,21 +439,61 @@ pub struct Config {
     /// Address size
     pub address_size: AddressSize,
 
+    /// Digits the width of a stretched address is a multiple of (default: 1)
+    pub address_size_step: usize,
+
     /// Padding behavior
     pub padding: Padding,
 
     /// Number of bytes per line (default: 16)
     pub line_length: usize,
 
+    /// Radix of the data bytes (default: 16)
+    pub radix: Radix,
+
+    /// Words the bytes of a line are grouped into (default: u8, no grouping)
+    pub word: Word,
+
     /// Number of bytes per block within a line (default: 1, meaning space after each byte)
     /// If set to 0 or line_length, no intra-line spacing
     pub block_length: usize,
 
-    /// Whether to repeat address on every line
-    pub repeat_address: bool,
+    /// Whitespace between the bytes of a block (default: empty)
+    pub byte_separator: String,
+
+    /// Whitespace between the blocks of a line (default: one space)
+    pub block_separator: String,
+
+    /// Data lines that start with their address
+    pub repeat_address: RepeatAddress,
 
     /// Whether to include ASCII preview in square brackets
     pub show_preview: bool,
+
+    /// Character encoding of the preview (default: ascii)
+    pub preview_encoding: PreviewEncoding,
+
+    /// Preview character of bytes that are not printable characters (default: `.`)
+    pub preview_placeholder: char,
+
+    /// Whether the preview shows control characters as Unicode control pictures
+    pub preview_control_pictures: bool,
+
+    /// Text encoding of the hex file (only `utf8` is supported)
+    pub encoding: String,
+
+    /// Name of the binary file the hex text was generated from
+    pub bin_filename: Option<String>,
+
+    /// Options without a dedicated field, written to the hex file as is
+    pub extra_options: Vec<(String, String)>,
+
+    /// Address of the first byte written by `bin_to_hex` (default: 0)
+    pub start_address: u64,
+
+    /// Options set by the environment or the command line, which `#option` lines
+    /// of a hex file do not change
+    pub overrides: Vec<(String, String)>,
 }
 
 impl Default for Config {
@@ -81,11 +501,24 @@ impl Default for Config {
         Config {
             endian: Endian::default(),
             address_size: AddressSize::default(),
+            address_size_step: 1,
             padding: Padding::default(),
             line_length: 16,
+            radix: Radix::default(),
+            word: Word::default(),
             block_length: 1,
-            repeat_address: true,
+            byte_separator: String::new(),
+            block_separator: " ".to_string(),
+            repeat_address: RepeatAddress::default(),
             show_preview: true,
+            preview_encoding: PreviewEncoding::default(),
+            preview_placeholder: '.',
+            preview_control_pictures: false,
+            encoding: "utf8".to_string(),
+            bin_filename: None,
+            extra_options: Vec::new(),
+            start_address: 0,
+            overrides: Vec::new(),
         }
     }
 }
@@ -120,14 +553,44 @@ impl Config {
         self
     }
 
+    /// Builder: set the radix of the data bytes
+    pub fn with_radix(mut self, radix: Radix) -> Self {
+        self.radix = radix;
+        self
+    }
+
+    /// Builder: set the words the bytes of a line are grouped into
+    pub fn with_word(mut self, word: Word) -> Self {
+        self.word = word;
+        self
+    }
+
     /// Builder: set block length
     pub fn with_block_length(mut self, block_length: usize) -> Self {
         self.block_length = block_length;
         self
     }
 
-    /// Builder: set whether to repeat address
-    pub fn with_repeat_address(mut self, repeat: bool) -> Self {
+    /// Builder: set the whitespace between the bytes of a block
+    pub fn with_byte_separator(mut self, separator: &str) -> Self {
+        self.byte_separator = separator.to_string();
+        self
+    }
+
+    /// Builder: set the whitespace between the blocks of a line
+    pub fn with_block_separator(mut self, separator: &str) -> Self {
+        self.block_separator = separator.to_string();
+        self
+    }
+
+    /// Builder: set the digits the width of a stretched address is a multiple of
+    pub fn with_address_size_step(mut self, step: usize) -> Self {
+        self.address_size_step = step;
+        self
+    }
+
+    /// Builder: set which data lines start with their address
+    pub fn with_repeat_address(mut self, repeat: RepeatAddress) -> Self {
         self.repeat_address = repeat;
         self
     }
@@ -137,4 +600,309 @@ impl Config {
         self.show_preview = show;
         self
     }
+
+    /// Builder: set the character encoding of the preview
+    pub fn with_preview_encoding(mut self, encoding: PreviewEncoding) -> Self {
+        self.preview_encoding = encoding;
+        self
+    }
+
+    /// Builder: set the preview character of bytes that are not printable characters
+    pub fn with_preview_placeholder(mut self, placeholder: char) -> Self {
+        self.preview_placeholder = placeholder;
+        self
+    }
+
+    /// Builder: set whether the preview shows control characters as control pictures
+    pub fn with_preview_control_pictures(mut self, pictures: bool) -> Self {
+        self.preview_control_pictures = pictures;
+        self
+    }
+
+    /// Builder: add an option that is written to the hex file as is
+    pub fn with_extra_option(mut self, name: &str, value: &str) -> Self {
+        self.extra_options
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Builder: set the address of the first byte written by `bin_to_hex`
+    pub fn with_start_address(mut self, address: u64) -> Self {
+        self.start_address = address;
+        self
+    }
+
+    /// Apply an `#option name=value` directive
+    ///
+    /// Values are checked against the [`OPTIONS`] registry; aliases set the option
+    /// they stand for. Unknown options are kept in `extra_options`.
+    pub fn apply_option(&mut self, name: &str, value: &str) -> Result<()> {
+        let Some(spec) = options::find(name) else {
+            match self.extra_options.iter_mut().find(|(n, _)| n == name) {
+                Some(option) => option.1 = value.to_string(),
+                None => self
+                    .extra_options
+                    .push((name.to_string(), value.to_string())),
+            }
+            return Ok(());
+        };
+        match spec.name {
+            "bin_filename" => self.bin_filename = Some(value.to_string()),
+            "encoding" => {
+                if !matches!(value.to_ascii_lowercase().as_str(), "utf8" | "utf-8") {
+                    return Err(Error::Config(format!(
+                        "unsupported encoding '{}': only utf8 is supported",
+                        value
+                    )));
+                }
+                self.encoding = "utf8".to_string();
+            }
+            "endian" => self.endian = value.parse()?,
+            "address_size" => self.address_size = value.parse()?,
+            "address_size_step" => self.address_size_step = spec.number(value)?,
+            "padding" => self.padding = value.parse()?,
+            "line_length" => self.line_length = spec.number(value)?,
+            "radix" => self.radix = value.parse()?,
+            "word" => self.word = value.parse()?,
+            "block_length" => self.block_length = spec.number(value)?,
+            "byte_separator" => self.byte_separator = spec.whitespace(value)?,
+            "block_separator" => self.block_separator = spec.whitespace(value)?,
+            "repeat_address" => self.repeat_address = value.parse()?,
+            "preview" => self.show_preview = spec.bool(value)?,
+            "preview_encoding" => self.preview_encoding = value.parse()?,
+            "preview_placeholder" => self.preview_placeholder = spec.char(value)?,
+            "preview_control_pictures" => self.preview_control_pictures = spec.bool(value)?,
+            name => {
+                return Err(Error::Config(format!(
+                    "option '{}' is declared but not supported",
+                    name
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply an `#option` line of a hex file; options in `overrides` keep their value
+    pub fn apply_directive(&mut self, name: &str, value: &str) -> Result<()> {
+        self.apply_option(name, value)?;
+        let name = options::find(name).map_or(name, |spec| spec.name);
+        if let Some((_, value)) = self.overrides.iter().find(|(n, _)| n == name) {
+            let value = value.clone();
+            self.apply_option(name, &value)?;
+        }
+        Ok(())
+    }
+
+    /// Canonical value of a generated option, `None` for unknown or unset options
+    pub fn option_value(&self, name: &str) -> Option<String> {
+        let value = match options::find(name)?.name {
+            "bin_filename" => return self.bin_filename.clone(),
+            "encoding" => self.encoding.clone(),
+            "endian" => self.endian.to_string(),
+            "address_size" => self.address_size.to_string(),
+            "address_size_step" => self.address_size_step.to_string(),
+            "padding" => self.padding.to_string(),
+            "line_length" => self.line_length.to_string(),
+            "radix" => self.radix.to_string(),
+            "word" => self.word.to_string(),
+            "block_length" => self.block_length.to_string(),
+            "byte_separator" => self.byte_separator.clone(),
+            "block_separator" => self.block_separator.clone(),
+            "repeat_address" => self.repeat_address.to_string(),
+            "preview" => self.show_preview.to_string(),
+            "preview_encoding" => self.preview_encoding.to_string(),
+            "preview_placeholder" => self.preview_placeholder.to_string(),
+            "preview_control_pictures" => self.preview_control_pictures.to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Check that `name=value` agrees with the effective value of a generated option
+    pub fn check_option(&self, name: &str, value: &str) -> Result<()> {
+        let Some(current) = self.option_value(name) else {
+            return Ok(());
+        };
+        let mut probe = self.clone();
+        probe.apply_option(name, value)?;
+        if probe.option_value(name).as_deref() != Some(current.as_str()) {
+            return Err(Error::Config(format!(
+                "option {}={} conflicts with generated option {}={}",
+                name, value, name, current
+            )));
+        }
+        Ok(())
+    }
+
+    /// Options written to the header of a generated hex file
+    ///
+    /// Contains the effective generated options followed by `extra_options`; options
+    /// declared with `omit_default` are left out while they have their default.
+    /// Fails if an extra option conflicts with a generated one.
+    pub fn header_options(&self) -> Result<Vec<(String, String)>> {
+        let mut options: Vec<(String, String)> = OPTIONS
+            .iter()
+            .filter_map(|spec| Some((spec, self.option_value(spec.name)?)))
+            .filter(|(spec, value)| !(spec.omit_default && value == spec.default))
+            .map(|(spec, value)| (spec.name.to_string(), value))
+            .collect();
+        for (name, value) in &self.extra_options {
+            if self.option_value(name).is_some() {
+                self.check_option(name, value)?;
+            } else {
+                options.push((name.clone(), value.clone()));
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Value of an option in a configuration file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum OptionValue {
+    Bool(bool),
+    Number(u64),
+    Text(String),
+}
+
+impl OptionValue {
+    /// Typed value of option `name` from its text
+    fn typed(name: &str, value: String) -> Self {
+        let typed = match options::find(name).map(|spec| spec.kind) {
+            Some(ValueKind::Number { .. }) => value.parse().ok().map(OptionValue::Number),
+            // Choices of numbers, such as the radix
+            Some(ValueKind::Choice(values)) if values.iter().all(|v| v.parse::<u64>().is_ok()) => {
+                value.parse().ok().map(OptionValue::Number)
+            }
+            Some(ValueKind::Bool) => value.parse().ok().map(OptionValue::Bool),
+            _ => None,
+        };
+        typed.unwrap_or(OptionValue::Text(value))
+    }
+}
+
+impl fmt::Display for OptionValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionValue::Bool(value) => write!(f, "{}", value),
+            OptionValue::Number(value) => write!(f, "{}", value),
+            OptionValue::Text(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// A config is a map of option names to values: the generated options followed by
+/// `extra_options`
+impl Serialize for Config {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let options = self.header_options().map_err(S::Error::custom)?;
+        let mut map = serializer.serialize_map(Some(options.len()))?;
+        for (name, value) in options {
+            let value = OptionValue::typed(&name, value);
+            map.serialize_entry(&name, &value)?;
+        }
+        map.end()
+    }
+}
+
+/// Options of the map are applied to the defaults; unknown ones become `extra_options`
+impl<'de> Deserialize<'de> for Config {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let options = BTreeMap::<String, OptionValue>::deserialize(deserializer)?;
+        let mut config = Config::default();
+        for (name, value) in options {
+            config
+                .apply_option(&name, &value.to_string())
+                .map_err(D::Error::custom)?;
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_padding_from_str() {
+        assert_eq!("00".parse::<Padding>().unwrap(), Padding::Value(0x00));
+        assert_eq!("ff".parse::<Padding>().unwrap(), Padding::Value(0xFF));
+        assert_eq!("forbidden".parse::<Padding>().unwrap(), Padding::Forbidden);
+        assert!("0".parse::<Padding>().is_err());
+        assert!("GG".parse::<Padding>().is_err());
+    }
+
+    #[test]
+    fn test_apply_option() {
+        let mut config = Config::default();
+        config.apply_option("endian", "BIG_ENDIAN").unwrap();
+        config.apply_option("address_size", "u16").unwrap();
+        config.apply_option("padding", "forbidden").unwrap();
+        config.apply_option("line_length", "8").unwrap();
+        config.apply_option("block_length", "4").unwrap();
+        config
+            .apply_option("repeat_address", "on_discontinuity")
+            .unwrap();
+        config.apply_option("address_size_step", "4").unwrap();
+        config.apply_option("bin_filename", "lorem.bin").unwrap();
+        config.apply_option("author", "me").unwrap();
+
+        assert_eq!(config.endian, Endian::Big);
+        assert_eq!(config.address_size, AddressSize::U16);
+        assert_eq!(config.padding, Padding::Forbidden);
+        assert_eq!(config.line_length, 8);
+        assert_eq!(config.block_length, 4);
+        assert_eq!(config.repeat_address, RepeatAddress::OnDiscontinuity);
+        assert_eq!(config.address_size_step, 4);
+        assert_eq!(config.bin_filename.as_deref(), Some("lorem.bin"));
+        assert_eq!(config.extra_options, vec![("author".into(), "me".into())]);
+
+        assert!(config.apply_option("endian", "middle").is_err());
+        assert!(config.apply_option("line_length", "0").is_err());
+        assert!(config.apply_option("repeat_address", "sometimes").is_err());
+        assert!(config.apply_option("byte_separator", "\t").is_ok());
+        assert!(config.apply_option("block_separator", " | ").is_err());
+        assert!(config.apply_option("encoding", "cp1251").is_err());
+    }
+
+    #[test]
+    fn test_header_options() {
+        let config = Config::default()
+            .with_line_length(8)
+            .with_extra_option("line_length", "8")
+            .with_extra_option("endian", "little")
+            .with_extra_option("author", "me");
+        let header = config.header_options().unwrap();
+        assert!(header.contains(&("line_length".into(), "8".into())));
+        assert!(header.contains(&("endian".into(), "LITTLE_ENDIAN".into())));
+        assert_eq!(header.last(), Some(&("author".into(), "me".into())));
+        assert!(!header.iter().any(|(name, _)| name == "bin_filename"));
+
+        let config = Config::default().with_extra_option("line_length", "32");
+        let err = config.header_options().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Configuration error: option line_length=32 conflicts with generated option line_length=16"
+        );
+    }
+
+    #[test]
+    fn test_serde() {
+        let config = Config::default()
+            .with_line_length(8)
+            .with_preview(false)
+            .with_extra_option("author", "me");
+        let text = toml::to_string(&config).unwrap();
+        assert!(text.contains("line_length = 8\n"));
+        assert!(text.contains("preview = false\n"));
+        assert!(text.contains("padding = \"00\"\n"));
+        assert_eq!(toml::from_str::<Config>(&text).unwrap(), config);
+
+        let config: Config =
+            serde_json::from_str(r#"{"endian": "be", "block_length": 4}"#).unwrap();
+        assert_eq!(config.endian, Endian::Big);
+        assert_eq!(config.block_length, 4);
+        assert!(serde_json::from_str::<Config>(r#"{"line_length": 0}"#).is_err());
+    }
 }
@@ -1,290 +1,976 @@
 //! Conversion functions between binary and hex formats
 
+use crate::config::{fits_digits, AddressSize, Padding, Radix, RepeatAddress};
+use crate::error::Location;
+use crate::lexer::Span;
+use crate::parser::{Event, Parser};
+use crate::preview::preview_text;
+use crate::slice::Slice;
 use crate::{Config, Error, Result};
-use std::io::{BufRead, BufReader, Read, Write};
+use std::io::{self, BufReader, Read, Write};
+use std::ops::Range;
 
 /// Convert binary data to hex text format
 ///
+/// The output starts with `#option` lines for the effective options and the
+/// pass-through options from `Config::extra_options`. The first byte is shown at
+/// `Config::start_address`; with `repeat_address=never` no address is written, so
+/// the text is read back from address 0.
+///
 /// # Arguments
 /// * `reader` - Input binary data
 /// * `writer` - Output hex text
 /// * `config` - Configuration options
-pub fn bin_to_hex<R: Read, W: Write>(
+pub fn bin_to_hex<R: Read, W: Write>(reader: R, writer: W, config: &Config) -> Result<()> {
+    bin_to_hex_with(reader, writer, config, None, &mut HexygWriter::default())
+}
+
+/// Convert binary data to text whose lines are written by `hex_writer`
+///
+/// Lines hold `line_length` bytes, the first one at `Config::start_address`; which
+/// lines show their address follows `repeat_address`.
+///
+/// `len` is the number of bytes of `reader`, if it is known, such as the length of
+/// a file. A stretched address is as wide as the final one, so without `len` the
+/// whole input is read into memory first.
+pub fn bin_to_hex_with<R, W, H>(
     reader: R,
     mut writer: W,
     config: &Config,
-) -> Result<()> {
+    len: Option<u64>,
+    hex_writer: &mut H,
+) -> Result<()>
+where
+    R: Read,
+    W: Write,
+    H: HexWriter + ?Sized,
+{
     let mut reader = BufReader::new(reader);
-    let mut address: u64 = 0;
-    let mut buffer = vec![0u8; config.line_length];
 
-    loop {
-        let bytes_read = reader.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
+    let mut buffered = Vec::new();
+    let len = match len {
+        Some(len) => len,
+        None if config.address_size == AddressSize::Stretch => {
+            reader.read_to_end(&mut buffered)? as u64
         }
+        None => 0,
+    };
+    let end = end_address(config.start_address, len)?;
+    let last_address = end.saturating_sub(1).max(config.start_address);
+    let mut reader = (&buffered[..]).chain(reader);
 
-        let line_data = &buffer[0..bytes_read];
+    hex_writer.begin(&mut writer, config, address_width(config, last_address))?;
 
-        // Write address if needed
-        if config.repeat_address || address == 0 {
-            write_address(&mut writer, address, config)?;
-            write!(writer, ": ")?;
-        } else {
-            // Indent continuation lines
-            write!(writer, "          ")?;
+    let mut address = config.start_address;
+    let mut data = Vec::with_capacity(config.line_length);
+    loop {
+        data.clear();
+        (&mut reader)
+            .take(config.line_length as u64)
+            .read_to_end(&mut data)?;
+        if data.is_empty() {
+            break;
         }
 
-        // Write hex bytes
-        write_hex_bytes(&mut writer, line_data, config)?;
+        let show_address = match config.repeat_address {
+            RepeatAddress::Never => false,
+            RepeatAddress::Once | RepeatAddress::OnDiscontinuity => address == config.start_address,
+            RepeatAddress::EveryLine => true,
+        };
+        let line = HexLine {
+            address,
+            show_address,
+            data: &data,
+        };
+        let count = address - config.start_address + data.len() as u64;
+        let end = end_address(config.start_address, count)?;
+        hex_writer.write_line(&mut writer, &line)?;
+
+        address = end;
+    }
 
-        // Write ASCII preview if enabled
-        if config.show_preview {
-            write!(writer, " ")?;
-            write_ascii_preview(&mut writer, line_data)?;
-        }
+    hex_writer.end(&mut writer, address)
+}
+
+/// Address after `count` bytes from `start`, an error if it does not fit into u64
+pub(crate) fn end_address(start: u64, count: u64) -> Result<u64> {
+    start.checked_add(count).ok_or_else(|| {
+        Error::OutOfRange(format!(
+            "address range exceeds u64: {} bytes from {:X}",
+            count, start
+        ))
+    })
+}
 
-        writeln!(writer)?;
+/// Data line passed to a [`HexWriter`]
+#[derive(Debug, Clone, Copy)]
+pub struct HexLine<'a> {
+    /// Address of the first byte
+    pub address: u64,
+    /// Whether the line starts with its address or is indented as wide
+    pub show_address: bool,
+    /// Bytes of the line, at most `line_length`
+    pub data: &'a [u8],
+}
 
-        address += bytes_read as u64;
+/// Writer of the lines of [`bin_to_hex_with`]
+///
+/// Implement it to write binary data in another text layout; [`HexygWriter`] writes
+/// hex text and [`Template`](crate::template::Template) fills line templates.
+pub trait HexWriter {
+    /// Write the text before the first line; `address_width` is the number of hex
+    /// digits an address takes
+    fn begin(
+        &mut self,
+        writer: &mut dyn Write,
+        config: &Config,
+        address_width: usize,
+    ) -> Result<()>;
+
+    /// Write a data line
+    fn write_line(&mut self, writer: &mut dyn Write, line: &HexLine<'_>) -> Result<()>;
+
+    /// Write the text after the last line; `end` is the address after the last byte
+    fn end(&mut self, writer: &mut dyn Write, end: u64) -> Result<()> {
+        let _ = (writer, end);
+        Ok(())
     }
+}
 
-    Ok(())
+/// [`HexWriter`] of hex text: `#option` lines for the effective options, then data
+/// lines laid out by the options
+#[derive(Debug, Default)]
+pub struct HexygWriter {
+    config: Config,
+    address_width: usize,
+    hex_end: usize,
 }
 
-/// Convert hex text to binary data
-///
-/// # Arguments
-/// * `reader` - Input hex text
-/// * `writer` - Output binary data
-pub fn hex_to_bin<R: Read, W: Write>(reader: R, mut writer: W) -> Result<()> {
-    let reader = BufReader::new(reader);
+impl HexWriter for HexygWriter {
+    fn begin(
+        &mut self,
+        writer: &mut dyn Write,
+        config: &Config,
+        address_width: usize,
+    ) -> Result<()> {
+        write_header(writer, config)?;
+        let layout = LineLayout::new(config, address_width);
+        self.hex_end = layout.hex_end;
+        self.address_width = address_width;
+        self.config = config.clone();
+        Ok(())
+    }
 
-    for line in reader.lines() {
-        let line = line?;
-        let line = line.trim();
+    fn write_line(&mut self, writer: &mut dyn Write, line: &HexLine<'_>) -> Result<()> {
+        let layout = LineLayout {
+            config: &self.config,
+            address_width: self.address_width,
+            hex_end: self.hex_end,
+        };
+        layout.write_line(writer, line.address, line.show_address, line.data)
+    }
+}
 
-        // Skip empty lines and comments
-        if line.is_empty() || line.starts_with("//") {
-            continue;
-        }
+/// Number of hex digits of the addresses of data whose final address is `last_address`
+pub(crate) fn address_width(config: &Config, last_address: u64) -> usize {
+    config.address_size.digits().unwrap_or_else(|| {
+        let digits = (u64::BITS - last_address.leading_zeros())
+            .div_ceil(4)
+            .max(1) as usize;
+        let step = config.address_size_step.max(1);
+        digits.div_ceil(step) * step
+    })
+}
+
+/// Column layout of data lines
+///
+/// A data line is the address, or an indent as wide, the hex column and then the
+/// extras: preview, annotations and comments. Bytes of a block are joined with
+/// `byte_separator` and blocks with `block_separator`; blocks are counted from the
+/// start of the line. The hex column of a short line is padded with spaces to the
+/// width of a full line, so the extras of all lines start in the same column; tabs
+/// count up to the next multiple of [`TAB_WIDTH`] columns.
+pub(crate) struct LineLayout<'a> {
+    config: &'a Config,
+    /// Digits of an address
+    address_width: usize,
+    /// Column after the hex column of a full line
+    hex_end: usize,
+}
 
-        // Skip block comments (simple handling)
-        if line.starts_with("/*") || line.ends_with("*/") {
-            continue;
+/// Columns between tab stops when aligning lines
+const TAB_WIDTH: usize = 8;
+
+impl<'a> LineLayout<'a> {
+    pub(crate) fn new(config: &'a Config, address_width: usize) -> Self {
+        let full = vec![0; config.line_length.max(1)];
+        let indent = " ".repeat(address_width + 2);
+        LineLayout {
+            config,
+            address_width,
+            hex_end: columns(&(indent + &config_hex_text(&full, config))),
         }
+    }
 
-        // Skip directives
-        if line.starts_with("#") {
-            continue;
+    /// Text of a data line without its newline and trailing whitespace
+    ///
+    /// Fails if the address is shown and does not fit into the width of addresses.
+    pub(crate) fn line(
+        &self,
+        address: u64,
+        show_address: bool,
+        data: &[u8],
+        extras: &[String],
+    ) -> Result<String> {
+        let mut line = if show_address {
+            if !fits_digits(address, self.address_width) {
+                return Err(Error::OutOfRange(format!(
+                    "address {:X} does not fit into address_size={}",
+                    address, self.config.address_size
+                )));
+            }
+            format!("{:0width$X}: ", address, width = self.address_width)
+        } else {
+            " ".repeat(self.address_width + 2)
+        };
+        line.push_str(&config_hex_text(data, self.config));
+        if !extras.is_empty() {
+            let padding = self.hex_end.saturating_sub(columns(&line));
+            line.extend(std::iter::repeat_n(' ', padding + 1));
+            line.push_str(&extras.join(" "));
         }
+        line.truncate(line.trim_end().len());
+        Ok(line)
+    }
 
-        // Extract hex data from line
-        let hex_data = extract_hex_from_line(line)?;
-        if !hex_data.is_empty() {
-            let bytes = parse_hex_string(&hex_data)?;
-            writer.write_all(&bytes)?;
+    /// Write a data line with its preview if previews are shown
+    pub(crate) fn write_line<W: Write + ?Sized>(
+        &self,
+        writer: &mut W,
+        address: u64,
+        show_address: bool,
+        data: &[u8],
+    ) -> Result<()> {
+        let mut extras = Vec::new();
+        if self.config.show_preview {
+            extras.push(format!("[{}]", preview_text(data, self.config)));
         }
+        writeln!(
+            writer,
+            "{}",
+            self.line(address, show_address, data, &extras)?
+        )?;
+        Ok(())
     }
+}
 
-    Ok(())
+/// Display width of a line with tabs expanded to the next tab stop
+pub(crate) fn columns(text: &str) -> usize {
+    advance(0, text)
 }
 
-/// Write address in appropriate format based on config
-fn write_address<W: Write>(writer: &mut W, address: u64, config: &Config) -> Result<()> {
-    use crate::config::AddressSize;
-
-    let width = match config.address_size {
-        AddressSize::U8 => 2,
-        AddressSize::U16 => 4,
-        AddressSize::U24 => 6,
-        AddressSize::U32 => 8,
-        AddressSize::U40 => 10,
-        AddressSize::U48 => 12,
-        AddressSize::U64 => 16,
-        AddressSize::Stretch => {
-            // Calculate minimum width needed
-            if address < 0x100 {
-                2
-            } else if address < 0x10000 {
-                4
-            } else if address < 0x1000000 {
-                6
-            } else if address < 0x100000000 {
-                8
-            } else {
-                16
-            }
-        }
-    };
+/// Column after `text` written from `column`
+pub(crate) fn advance(column: usize, text: &str) -> usize {
+    text.chars().fold(column, |column, c| match c {
+        '\t' => (column / TAB_WIDTH + 1) * TAB_WIDTH,
+        _ => column + 1,
+    })
+}
 
-    write!(writer, "{:0width$X}", address, width = width)?;
-    Ok(())
+/// Bytes of a line in the radix of `config`, grouped into its words and joined
+/// with its separators
+fn config_hex_text(data: &[u8], config: &Config) -> String {
+    let mut data = data.to_vec();
+    config.word.swap(&mut data, config.endian);
+    hex_text(
+        &data,
+        config.radix,
+        config.word.size(),
+        config.block_length,
+        &config.byte_separator,
+        &config.block_separator,
+    )
 }
 
-/// Write hex bytes with appropriate spacing
-fn write_hex_bytes<W: Write>(writer: &mut W, data: &[u8], config: &Config) -> Result<()> {
+/// Bytes of a line in `radix` joined with `byte_separator` within blocks of
+/// `block_length` bytes and `block_separator` between them; 0 is one block
+///
+/// The digits of a word of `word` bytes are not separated; a block is rounded up
+/// to whole words.
+pub(crate) fn hex_text(
+    data: &[u8],
+    radix: Radix,
+    word: usize,
+    block_length: usize,
+    byte_separator: &str,
+    block_separator: &str,
+) -> String {
+    let block_length = match block_length {
+        0 => usize::MAX,
+        n => n.div_ceil(word) * word,
+    };
+    let mut text = String::with_capacity(data.len() * (radix.digits() + 1));
     for (i, byte) in data.iter().enumerate() {
-        // Add space before byte if:
-        // - It's not the first byte AND
-        // - block_length > 0 (spacing enabled) AND
-        // - We're starting a new block
-        if i > 0 && config.block_length > 0 && i % config.block_length == 0 {
-            write!(writer, " ")?;
+        if i > 0 && i.is_multiple_of(word) {
+            text.push_str(if i.is_multiple_of(block_length) {
+                block_separator
+            } else {
+                byte_separator
+            });
         }
+        text.push_str(&radix.format(*byte));
+    }
+    text
+}
 
-        // Write the byte
-        write!(writer, "{:02X}", byte)?;
-
-        // Add space after byte if:
-        // - block_length > 0 (spacing enabled) AND
-        // - It's not the last byte AND
-        // - We're not at the end of a block
-        if config.block_length > 0
-            && (i + 1) < data.len()
-            && (i + 1) % config.block_length != 0
-        {
-            write!(writer, " ")?;
+/// Convert hex text to binary data
+///
+/// `#option` directives in the input override `config` from the line they appear on.
+/// Bytes are placed at their declared addresses. Gaps between addresses are filled
+/// with the padding byte from the effective options.
+///
+/// # Arguments
+/// * `reader` - Input hex text
+/// * `writer` - Output binary data
+/// * `config` - Configuration options
+pub fn hex_to_bin<R: Read, W: Write>(reader: R, writer: W, config: &Config) -> Result<()> {
+    let mut output = AddressedWriter::new(writer, config.padding);
+    let mut parser = Parser::with_config(BufReader::new(reader), config.clone());
+
+    while let Some(event) = parser.next() {
+        match event? {
+            // Only padding affects placement of bytes
+            Event::Option { .. } => output.padding = parser.config().padding,
+            Event::Address { address, span } => output.seek(address, span)?,
+            Event::Bytes { data, .. } => output.write_all(&data)?,
+            _ => {}
         }
     }
-    Ok(())
+
+    output.flush()
 }
 
-/// Write ASCII preview in square brackets
-fn write_ascii_preview<W: Write>(writer: &mut W, data: &[u8]) -> Result<()> {
-    write!(writer, "[")?;
-    for &byte in data {
-        if byte >= 32 && byte < 127 {
-            write!(writer, "{}", byte as char)?;
-        } else {
-            write!(writer, ".")?;
-        }
+/// Convert the bytes of `slice` of hex text to binary data
+///
+/// Positions of the slice are addresses of the hex text; negative ones count from
+/// the end of its data, the address after the last byte. Only an end-relative slice
+/// keeps the whole data in memory.
+pub fn hex_to_bin_slice<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    config: &Config,
+    slice: &Slice,
+) -> Result<()> {
+    write_slice(writer, slice, |output| hex_to_bin(reader, output, config))
+}
+
+/// Write the bytes of `slice` of the binary data that `convert` writes
+///
+/// Positions of the slice are addresses of the data, negative ones count from its
+/// end. Only an end-relative slice keeps the whole data in memory.
+pub fn write_slice<W, F>(mut writer: W, slice: &Slice, convert: F) -> Result<()>
+where
+    W: Write,
+    F: FnOnce(&mut dyn Write) -> Result<()>,
+{
+    if slice.is_end_relative() {
+        let mut data = Vec::new();
+        convert(&mut data)?;
+        let range = slice.resolve(data.len() as u64)?;
+        writer.write_all(&data[range.start as usize..range.end as usize])?;
+        writer.flush()?;
+        return Ok(());
     }
-    write!(writer, "]")?;
+    let mut window = Window {
+        writer,
+        range: slice.resolve(u64::MAX)?,
+        position: 0,
+    };
+    convert(&mut window)?;
+    // Positions past the end of the data are errors
+    slice.resolve(window.position)?;
     Ok(())
 }
 
-/// Extract hex data from a line, removing address, preview, annotations, and comments
-fn extract_hex_from_line(line: &str) -> Result<String> {
-    let mut result = String::new();
+/// Writer that passes on only the bytes of `range` of its input
+struct Window<W: Write> {
+    writer: W,
+    range: Range<u64>,
+    position: u64,
+}
 
-    // Remove inline comments first (everything after '//')
-    let line = if let Some(pos) = line.find("//") {
-        &line[..pos]
-    } else {
-        line
-    };
+impl<W: Write> Write for Window<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let end = self.position + buf.len() as u64;
+        let from = self.range.start.clamp(self.position, end);
+        let to = self.range.end.clamp(from, end);
+        self.writer
+            .write_all(&buf[(from - self.position) as usize..(to - self.position) as usize])?;
+        self.position = end;
+        Ok(buf.len())
+    }
 
-    // Remove address (everything before first ':')
-    let line = if let Some(pos) = line.find(':') {
-        &line[pos + 1..]
-    } else {
-        line
-    };
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
 
-    // Remove ASCII preview (everything in square brackets)
-    let line = if let Some(start) = line.find('[') {
-        &line[..start]
-    } else {
-        line
-    };
+/// Output sink that places bytes at their declared addresses
+pub(crate) struct AddressedWriter<W: Write> {
+    writer: W,
+    pub(crate) padding: Padding,
+    /// Address of the next byte to be written
+    position: u64,
+    /// Last address declared explicitly in the input
+    last_address: Option<u64>,
+}
 
-    // Remove annotations (everything after '|')
-    let line = if let Some(pos) = line.find('|') {
-        &line[..pos]
-    } else {
-        line
-    };
+impl<W: Write> AddressedWriter<W> {
+    pub(crate) fn new(writer: W, padding: Padding) -> Self {
+        AddressedWriter {
+            writer,
+            padding,
+            position: 0,
+            last_address: None,
+        }
+    }
 
-    // Extract only hex characters (no spaces)
-    for ch in line.chars() {
-        if ch.is_ascii_hexdigit() {
-            result.push(ch);
+    /// Move to a declared address, filling the gap with padding if needed
+    pub(crate) fn seek(&mut self, address: u64, span: Span) -> Result<()> {
+        if let Some(previous) = self.last_address {
+            if address < previous {
+                return Err(Error::AddressBackwards {
+                    address,
+                    previous,
+                    location: Location::new(span),
+                });
+            }
+        }
+        if address < self.position {
+            return Err(Error::AddressOverlap {
+                address,
+                expected: self.position,
+                location: Location::new(span),
+            });
         }
-        // Silently ignore all other characters (spaces, etc)
+        if address > self.position {
+            match self.padding {
+                Padding::Forbidden => {
+                    return Err(Error::AddressGap {
+                        address,
+                        expected: self.position,
+                        location: Location::new(span),
+                    });
+                }
+                Padding::Value(byte) => self.fill(byte, address - self.position)?,
+            }
+        }
+        self.last_address = Some(address);
+        Ok(())
     }
 
-    Ok(result)
-}
+    fn fill(&mut self, byte: u8, mut count: u64) -> Result<()> {
+        let chunk = [byte; 4096];
+        self.position += count;
+        while count > 0 {
+            let n = count.min(chunk.len() as u64) as usize;
+            self.writer.write_all(&chunk[..n])?;
+            count -= n as u64;
+        }
+        Ok(())
+    }
+
+    /// Address of the next byte to be written
+    pub(crate) fn position(&self) -> u64 {
+        self.position
+    }
 
-/// Parse hex string to bytes
-fn parse_hex_string(hex: &str) -> Result<Vec<u8>> {
-    let hex = hex.trim();
-    if hex.is_empty() {
-        return Ok(Vec::new());
+    pub(crate) fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
     }
 
-    if hex.len() % 2 != 0 {
-        return Err(Error::OddHexLength);
+    /// Continue from `address` after a failed seek, without writing anything
+    pub(crate) fn resync(&mut self, address: u64) {
+        self.position = address;
+        self.last_address = Some(address);
     }
 
-    let mut bytes = Vec::with_capacity(hex.len() / 2);
-    for i in (0..hex.len()).step_by(2) {
-        let byte_str = &hex[i..i + 2];
-        let byte = u8::from_str_radix(byte_str, 16).map_err(|_| {
-            Error::InvalidHexChar(
-                byte_str.chars().next().unwrap_or('?'),
-                i,
-            )
-        })?;
-        bytes.push(byte);
+    pub(crate) fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        self.writer.write_all(data)?;
+        self.position += data.len() as u64;
+        Ok(())
     }
 
-    Ok(bytes)
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Write `#option` lines for the effective options, followed by an empty line
+pub(crate) fn write_header<W: Write + ?Sized>(writer: &mut W, config: &Config) -> Result<()> {
+    for (name, value) in config.header_options()? {
+        writeln!(writer, "#option {}={}", name, format_option_value(&value))?;
+    }
+    writeln!(writer)?;
+    Ok(())
+}
+
+/// Quote an option value unless it can be written as an unquoted value
+fn format_option_value(value: &str) -> String {
+    let unquoted = !value.is_empty()
+        && !value.contains("//")
+        && !value.contains("/*")
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_+-./".contains(c));
+    if unquoted {
+        value.to_string()
+    } else {
+        let mut quoted = String::with_capacity(value.len() + 2);
+        quoted.push('"');
+        for c in value.chars() {
+            match c {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                '\n' => quoted.push_str("\\n"),
+                '\r' => quoted.push_str("\\r"),
+                '\t' => quoted.push_str("\\t"),
+                _ => quoted.push(c),
+            }
+        }
+        quoted.push('"');
+        quoted
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::check::{check_with, CheckOptions};
+    use crate::config::{Endian, Word};
+
+    #[test]
+    fn test_bin_to_hex_basic() {
+        let input = b"Hello";
+        let mut output = Vec::new();
+        let config = Config::default();
+
+        bin_to_hex(&input[..], &mut output, &config).unwrap();
+
+        let result = String::from_utf8(output).unwrap();
+        assert!(result.contains("48 65 6C 6C 6F"));
+        assert!(result.contains("[Hello]"));
+    }
 
     #[test]
-    fn test_parse_hex_string() {
-        assert_eq!(parse_hex_string("48656C6C6F").unwrap(), b"Hello");
-        assert_eq!(parse_hex_string("DEADBEEF").unwrap(), vec![0xDE, 0xAD, 0xBE, 0xEF]);
+    fn test_bin_to_hex_header() {
+        let config = Config::default()
+            .with_line_length(4)
+            .with_extra_option("author", "John Doe")
+            .with_extra_option("bin_filename", "a.bin");
+        let mut output = Vec::new();
+
+        bin_to_hex(&b"Hi"[..], &mut output, &config).unwrap();
+
+        let result = String::from_utf8(output).unwrap();
+        assert!(result.starts_with(
+            "#option encoding=utf8\n#option endian=LITTLE_ENDIAN\n#option address_size=u32\n"
+        ));
+        assert!(result.contains("#option line_length=4\n"));
+        assert!(result.contains("#option bin_filename=a.bin\n"));
+        assert!(result.contains("#option author=\"John Doe\"\n"));
+        assert!(result.ends_with("a.bin\n\n00000000: 48 69       [Hi]\n"));
+
+        let config = Config::default().with_extra_option("padding", "FF");
+        assert!(matches!(
+            bin_to_hex(&b"Hi"[..], Vec::new(), &config),
+            Err(Error::Config(_))
+        ));
     }
 
     #[test]
-    fn test_parse_hex_string_lowercase() {
-        assert_eq!(parse_hex_string("deadbeef").unwrap(), vec![0xDE, 0xAD, 0xBE, 0xEF]);
+    fn test_hex_to_bin_applies_options() {
+        // Padding of the config is overridden by the file from that line on
+        let input = b"00000001: 01\n#option padding=forbidden\n00000003: 02\n";
+        let config = Config::default().with_padding(Padding::Value(0xEE));
+
+        let err = hex_to_bin(&input[..], Vec::new(), &config).unwrap_err();
+
+        assert!(matches!(err, Error::AddressGap { .. }));
+        assert_eq!(err.location().unwrap().span.line, 3);
     }
 
     #[test]
-    fn test_extract_hex_from_line() {
-        assert_eq!(
-            extract_hex_from_line("00000000: 48 65 6C 6C 6F [Hello]").unwrap(),
-            "48656C6C6F"
-        );
+    fn test_hex_to_bin_basic() {
+        let input = b"48 65 6C 6C 6F";
+        let mut output = Vec::new();
+
+        hex_to_bin(&input[..], &mut output, &Config::default()).unwrap();
+
+        assert_eq!(output, b"Hello");
+    }
+
+    #[test]
+    fn test_hex_to_bin_address_gap_padding() {
+        let input = b"00000000: 01 02\n00000004: 03\n";
+        let mut output = Vec::new();
+        let config = Config::default().with_padding(Padding::Value(0xFF));
+
+        hex_to_bin(&input[..], &mut output, &config).unwrap();
+
+        assert_eq!(output, vec![0x01, 0x02, 0xFF, 0xFF, 0x03]);
+    }
+
+    #[test]
+    fn test_hex_to_bin_padding_option() {
+        let input = b"#option padding=AA // fill\n00000002: 01\n";
+        let mut output = Vec::new();
+
+        hex_to_bin(&input[..], &mut output, &Config::default()).unwrap();
+
+        assert_eq!(output, vec![0xAA, 0xAA, 0x01]);
+    }
+
+    #[test]
+    fn test_hex_to_bin_padding_forbidden() {
+        let input = b"#option padding=forbidden\n00000000: 01 02\n00000002: 03\n00000008: 04\n";
+        let mut output = Vec::new();
+
+        let err = hex_to_bin(&input[..], &mut output, &Config::default()).unwrap_err();
+
+        assert!(matches!(
+            err,
+            Error::AddressGap {
+                address: 8,
+                expected: 3,
+                ..
+            }
+        ));
         assert_eq!(
-            extract_hex_from_line("48 65 6C 6C 6F").unwrap(),
-            "48656C6C6F"
+            err.to_string(),
+            "line 4, column 1: gap between 00000003 and 00000008 is not allowed with padding=forbidden"
         );
+    }
+
+    #[test]
+    fn test_hex_to_bin_overlap_and_backwards() {
+        let input = b"00000000: 01 02 03 04\n00000002: 05\n";
+        let err = hex_to_bin(&input[..], Vec::new(), &Config::default()).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::AddressOverlap {
+                address: 2,
+                expected: 4,
+                ..
+            }
+        ));
+        assert_eq!(err.location().unwrap().span.line, 2);
+
+        let input = b"00000010: 01\n00000008: 02\n";
+        let err = hex_to_bin(&input[..], Vec::new(), &Config::default()).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::AddressBackwards {
+                address: 8,
+                previous: 0x10,
+                ..
+            }
+        ));
+        assert_eq!(err.location().unwrap().span.line, 2);
+    }
+
+    #[test]
+    fn test_hex_to_bin_comments_and_previews() {
+        let input =
+            b"/* header\n 00 01 */\n00000000: 41 7C [A|] // 42\n00000002: 2F /* 43 */ 3A [/:]\n";
+        let mut output = Vec::new();
+
+        hex_to_bin(&input[..], &mut output, &Config::default()).unwrap();
+
+        assert_eq!(output, b"A|/:");
+    }
+
+    #[test]
+    fn test_hex_to_bin_reference_corpus() {
+        let input = include_str!("../../../tests/corpus/valid/01_reference.hex");
+        let mut output = Vec::new();
+
+        hex_to_bin(input.as_bytes(), &mut output, &Config::default()).unwrap();
+
+        assert_eq!(output.len(), 0x37B);
+        assert_eq!(&output[..11], b"Lorem ipsum");
+        assert!(output[0x7B..0x100].iter().all(|&b| b == 0x00));
         assert_eq!(
-            extract_hex_from_line("00000000: 48 65 6C 6C 6F [Hello] | +0:utf8=\"Hello\"").unwrap(),
-            "48656C6C6F"
+            &output[0x100..0x110],
+            &(0x00..0x10).collect::<Vec<u8>>()[..]
         );
+        assert_eq!(&output[0x200..0x204], &[0xFF, 0x00, 0x00, 0x00]);
+        assert_eq!(&output[0x220..0x230], b"Lorem ipsum dolo");
+        assert_eq!(&output[0x300..0x37B], &output[0x00..0x7B]);
+
+        // Round trip through the generator keeps the bytes
+        let mut hex_output = Vec::new();
+        bin_to_hex(&output[..], &mut hex_output, &Config::default()).unwrap();
+        let mut bin_output = Vec::new();
+        hex_to_bin(&hex_output[..], &mut bin_output, &Config::default()).unwrap();
+        assert_eq!(bin_output, output);
     }
 
     #[test]
-    fn test_bin_to_hex_basic() {
-        let input = b"Hello";
+    fn test_bin_to_hex_start_address() {
+        let config = Config::default()
+            .with_line_length(2)
+            .with_repeat_address(RepeatAddress::Once)
+            .with_start_address(0x10);
         let mut output = Vec::new();
-        let config = Config::default();
 
-        bin_to_hex(&input[..], &mut output, &config).unwrap();
+        bin_to_hex(&b"abc"[..], &mut output, &config).unwrap();
 
         let result = String::from_utf8(output).unwrap();
-        assert!(result.contains("48 65 6C 6C 6F"));
-        assert!(result.contains("[Hello]"));
+        assert!(result.ends_with("\n00000010: 61 62 [ab]\n          63    [c]\n"));
     }
 
     #[test]
-    fn test_hex_to_bin_basic() {
-        let input = b"48 65 6C 6C 6F";
+    fn test_bin_to_hex_address_overflow() {
+        let config = Config::default()
+            .with_address_size(AddressSize::U64)
+            .with_start_address(u64::MAX - 2);
         let mut output = Vec::new();
+        bin_to_hex(&b"ab"[..], &mut output, &config).unwrap();
+        let result = String::from_utf8(output).unwrap();
+        assert!(result.contains("\nFFFFFFFFFFFFFFFD: 61 62 "), "{result}");
 
-        hex_to_bin(&input[..], &mut output).unwrap();
+        let err = bin_to_hex(&b"abcd"[..], io::sink(), &config).unwrap_err();
+        assert!(matches!(err, Error::OutOfRange(_)), "{err}");
+        assert_eq!(
+            err.to_string(),
+            "Address out of range: address range exceeds u64: 4 bytes from FFFFFFFFFFFFFFFD"
+        );
+        let config = config.with_address_size(AddressSize::Stretch);
+        let err = bin_to_hex(&b"abcd"[..], io::sink(), &config).unwrap_err();
+        assert!(matches!(err, Error::OutOfRange(_)), "{err}");
+
+        // a fixed size does not widen for larger addresses
+        let config = Config::default()
+            .with_address_size(AddressSize::U8)
+            .with_start_address(0xF0);
+        let err = bin_to_hex(&[0; 0x11][..], io::sink(), &config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Address out of range: address 100 does not fit into address_size=u8"
+        );
+        let config = config.with_repeat_address(RepeatAddress::Once);
+        assert!(bin_to_hex(&[0; 0x11][..], io::sink(), &config).is_ok());
+    }
 
-        assert_eq!(output, b"Hello");
+    #[test]
+    fn test_bin_to_hex_stretch_known_length() {
+        let config = Config::default()
+            .with_address_size(AddressSize::Stretch)
+            .with_start_address(0xF0);
+        let write = |len| {
+            let mut output = Vec::new();
+            let mut writer = HexygWriter::default();
+            bin_to_hex_with(&b"abc"[..], &mut output, &config, len, &mut writer).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        // the width comes from the given length instead of the bytes read
+        assert!(write(None).contains("\nF0: 61 62 63 "));
+        assert!(write(Some(0x11)).contains("\n0F0: 61 62 63 "));
+    }
+
+    #[test]
+    fn test_bin_to_hex_address_modes() {
+        let lines = |config: Config, len: usize| {
+            let mut output = Vec::new();
+            let input: Vec<u8> = (0..len as u8).collect();
+            bin_to_hex(&input[..], &mut output, &config.with_preview(false)).unwrap();
+            let text = String::from_utf8(output).unwrap();
+            let data = text.split_once("\n\n").unwrap().1.to_string();
+            data.lines().map(str::to_string).collect::<Vec<_>>()
+        };
+
+        // stretched addresses are as wide as the final one on every line
+        let stretch = Config::default()
+            .with_address_size(AddressSize::Stretch)
+            .with_line_length(4)
+            .with_start_address(0xFE);
+        assert_eq!(
+            lines(stretch.clone(), 6),
+            ["0FE: 00 01 02 03", "102: 04 05"]
+        );
+        assert_eq!(
+            lines(stretch.with_address_size_step(4), 6),
+            ["00FE: 00 01 02 03", "0102: 04 05"]
+        );
+
+        let u128 = Config::default()
+            .with_address_size(AddressSize::U128)
+            .with_line_length(2)
+            .with_repeat_address(RepeatAddress::OnDiscontinuity);
+        let indent = " ".repeat(34);
+        assert_eq!(
+            lines(u128, 3),
+            [format!("{:032X}: 00 01", 0), format!("{indent}02")]
+        );
+
+        let never = Config::default()
+            .with_address_size(AddressSize::U8)
+            .with_line_length(2)
+            .with_repeat_address(RepeatAddress::Never);
+        assert_eq!(lines(never, 3), ["    00 01", "    02"]);
+    }
+
+    #[test]
+    fn test_hex_to_bin_slice() {
+        let input = b"00000000: 00 01 02 03\n00000008: 08 09\n";
+        let slice = |from: &str, to: &str| {
+            let slice = Slice::new(from.parse().ok(), to.parse().ok());
+            let mut output = Vec::new();
+            hex_to_bin_slice(&input[..], &mut output, &Config::default(), &slice).map(|_| output)
+        };
+        assert_eq!(slice("2", "0x9").unwrap(), [2, 3, 0, 0, 0, 0, 8]);
+        assert_eq!(slice("-3", "").unwrap(), [0, 8, 9]);
+        assert_eq!(slice("", "-8").unwrap(), [0, 1]);
+        assert!(slice("10", "").unwrap().is_empty());
+        assert!(matches!(slice("", "11"), Err(Error::OutOfRange(_))));
+        assert!(matches!(slice("12", ""), Err(Error::OutOfRange(_))));
+        assert!(matches!(slice("-11", ""), Err(Error::OutOfRange(_))));
+    }
+
+    #[test]
+    fn test_bin_to_hex_separators() {
+        let data = b"Lorem ipsum";
+        let layouts = [
+            (
+                Config::default().with_line_length(8).with_block_length(4),
+                "00000000: 4C6F7265 6D206970 [Lorem ip]\n00000008: 73756D            [sum]\n",
+            ),
+            (
+                Config::default()
+                    .with_line_length(8)
+                    .with_block_length(0)
+                    .with_byte_separator("  "),
+                "00000000: 4C  6F  72  65  6D  20  69  70 [Lorem ip]\n\
+                 00000008: 73  75  6D                     [sum]\n",
+            ),
+            (
+                Config::default()
+                    .with_line_length(8)
+                    .with_block_length(2)
+                    .with_block_separator("\t"),
+                // a tab advances to the next multiple of 8 columns
+                &format!(
+                    "00000000: 4C6F\t7265\t6D20\t6970 [Lorem ip]\n00000008: 7375\t6D{} [sum]\n",
+                    " ".repeat(18)
+                ),
+            ),
+        ];
+
+        for (config, expected) in layouts {
+            let mut output = Vec::new();
+            bin_to_hex(&data[..], &mut output, &config).unwrap();
+            let text = String::from_utf8(output).unwrap();
+            assert!(text.ends_with(&format!("\n\n{expected}")), "{text}");
+
+            // the options in the header are enough to read the layout back
+            let mut binary = Vec::new();
+            hex_to_bin(text.as_bytes(), &mut binary, &Config::default()).unwrap();
+            assert_eq!(binary, data);
+            let options = CheckOptions::default().with_text(true);
+            let report = check_with(text.as_bytes(), &Config::default(), &options);
+            assert!(report.diagnostics.is_empty(), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_bin_to_hex_radix() {
+        let data = b"Hi, \x00\xFF";
+        let layouts = [
+            (
+                Radix::Binary,
+                "00000000: 01001000 01101001 00101100 [Hi,]\n\
+                 00000003: 00100000 00000000 11111111 [ ..]\n",
+            ),
+            (
+                Radix::Octal,
+                "00000000: 110 151 054 [Hi,]\n00000003: 040 000 377 [ ..]\n",
+            ),
+            (
+                Radix::Decimal,
+                "00000000: 072 105 044 [Hi,]\n00000003: 032 000 255 [ ..]\n",
+            ),
+        ];
+
+        for (radix, expected) in layouts {
+            let config = Config::default().with_line_length(3).with_radix(radix);
+            let mut output = Vec::new();
+            bin_to_hex(&data[..], &mut output, &config).unwrap();
+            let text = String::from_utf8(output).unwrap();
+            assert!(text.contains(&format!("#option radix={radix}\n")), "{text}");
+            assert!(text.ends_with(&format!("\n\n{expected}")), "{text}");
+
+            let mut binary = Vec::new();
+            hex_to_bin(text.as_bytes(), &mut binary, &Config::default()).unwrap();
+            assert_eq!(binary, data);
+            assert_eq!(
+                crate::format::format(&text, &Config::default()).unwrap(),
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_bin_to_hex_word() {
+        let data = b"abcdefghijklmnopq";
+        let layouts = [
+            (
+                Word::U32,
+                Endian::Little,
+                1,
+                "00000000: 64636261 68676665 6C6B6A69 706F6E6D [abcdefghijklmnop]\n\
+                 00000010: 71                                  [q]\n",
+            ),
+            (
+                Word::U16,
+                Endian::Big,
+                1,
+                "00000000: 6162 6364 6566 6768 696A 6B6C 6D6E 6F70 [abcdefghijklmnop]\n\
+                 00000010: 71                                      [q]\n",
+            ),
+            (
+                Word::U64,
+                Endian::Little,
+                4,
+                "00000000: 6867666564636261 706F6E6D6C6B6A69 [abcdefghijklmnop]\n\
+                 00000010: 71                                [q]\n",
+            ),
+        ];
+
+        for (word, endian, block_length, expected) in layouts {
+            let config = Config::default()
+                .with_word(word)
+                .with_endian(endian)
+                .with_block_length(block_length);
+            let mut output = Vec::new();
+            bin_to_hex(&data[..], &mut output, &config).unwrap();
+            let text = String::from_utf8(output).unwrap();
+            assert!(text.contains(&format!("#option word={word}\n")), "{text}");
+            assert!(text.ends_with(&format!("\n\n{expected}")), "{text}");
+
+            let mut binary = Vec::new();
+            hex_to_bin(text.as_bytes(), &mut binary, &Config::default()).unwrap();
+            assert_eq!(binary, data);
+            let formatted = crate::format::format(&text, &Config::default()).unwrap();
+            assert_eq!(formatted, text);
+        }
+
+        // A partial word at the end of a line is swapped as well
+        let text = "#option word=u32\n00: 04030201 0605\n06: 0807\n";
+        let mut binary = Vec::new();
+        hex_to_bin(text.as_bytes(), &mut binary, &Config::default()).unwrap();
+        assert_eq!(binary, [1, 2, 3, 4, 5, 6, 7, 8]);
     }
 
     #[test]
@@ -298,7 +984,7 @@ mod tests {
 
         // Hex back to binary
         let mut bin_output = Vec::new();
-        hex_to_bin(&hex_output[..], &mut bin_output).unwrap();
+        hex_to_bin(&hex_output[..], &mut bin_output, &config).unwrap();
 
         assert_eq!(bin_output, original);
     }
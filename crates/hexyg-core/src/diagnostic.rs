@@ -0,0 +1,119 @@
+//! Diagnostics collected while checking hex text
+
+use crate::error::Location;
+use crate::Error;
+use std::fmt;
+
+/// Severity of a diagnostic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Problem that prevents conversion
+    Error,
+    /// Suspicious input that is still converted
+    Warning,
+    /// Informational note
+    Info,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Info => write!(f, "info"),
+        }
+    }
+}
+
+/// Single problem found in the input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Message without the location prefix
+    pub message: String,
+    /// Position in the source, `None` for problems such as I/O errors
+    pub location: Option<Location>,
+}
+
+impl Diagnostic {
+    /// Error diagnostic at the given location
+    pub fn error(location: Location, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.into(),
+            location: Some(location),
+        }
+    }
+
+    /// Warning diagnostic at the given location
+    pub fn warning(location: Location, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(location, message)
+        }
+    }
+}
+
+impl From<Error> for Diagnostic {
+    fn from(err: Error) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message: err.message(),
+            location: err.location().cloned(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}: {}: {}", location, self.severity, self.message),
+            None => write!(f, "{}: {}", self.severity, self.message),
+        }
+    }
+}
+
+/// Result of checking a hex file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Diagnostics in the order they were found
+    pub diagnostics: Vec<Diagnostic>,
+    /// Whether checking stopped early because the error limit was reached
+    pub truncated: bool,
+}
+
+impl Report {
+    /// Number of diagnostics with [`Severity::Error`]
+    pub fn error_count(&self) -> usize {
+        self.count(Severity::Error)
+    }
+
+    /// Number of diagnostics with [`Severity::Warning`]
+    pub fn warning_count(&self) -> usize {
+        self.count(Severity::Warning)
+    }
+
+    /// Whether the input can be converted without errors
+    pub fn is_ok(&self) -> bool {
+        self.error_count() == 0
+    }
+
+    /// Attach a source file name to all located diagnostics
+    pub fn with_file(mut self, file: &str) -> Self {
+        for location in self
+            .diagnostics
+            .iter_mut()
+            .filter_map(|d| d.location.as_mut())
+        {
+            location.file = Some(file.to_string());
+        }
+        self
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|d| d.severity == severity)
+            .count()
+    }
+}
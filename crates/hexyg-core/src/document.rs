@@ -0,0 +1,1009 @@
+//! Sparse in-memory model of a hex document
+//!
+//! [`HexDocument`] keeps the bytes of a dump as non-overlapping chunks keyed by
+//! their start address, so documents with large gaps between addresses stay small.
+//! Chunks that touch after a write are merged, so every hole between two chunks is
+//! at least one byte long.
+//!
+//! Comments and annotations are kept as [`CellMetadata`] keyed by address and move
+//! with the bytes when data is inserted or deleted. [`Editor`] adds undo and redo on
+//! top of a document.
+//!
+//! Chunks either own their bytes or borrow them from a read-only [`Source`] such as
+//! a memory-mapped file ([`HexDocument::open_mapped`]); only modified pages of a
+//! source are copied, so disk images can be opened without loading them.
+//!
+//! With [`HexDocument::track_changes`] on, every edit is recorded as a [`Change`];
+//! [`Renderer`] turns the recorded changes into edits of the hex text.
+
+mod change;
+mod history;
+mod metadata;
+mod render;
+mod storage;
+
+pub use change::Change;
+pub use history::{Editor, DEFAULT_HISTORY_LIMIT};
+pub use metadata::{CellMetadata, Invalidated, InvalidationReason};
+pub use render::{Renderer, TextEdit};
+pub use storage::{Source, PAGE_SIZE};
+
+use crate::config::{Endian, Padding};
+use crate::convert::{end_address, write_header, AddressedWriter, LineLayout};
+use crate::parser::{Event, Parser};
+use crate::types::{value_size, Value};
+use crate::{Config, Error, Result};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use storage::Bytes;
+
+/// Fixed-size integer that can be read from and written to a document
+pub trait Scalar: Copy {
+    /// Size in bytes
+    const SIZE: usize;
+
+    /// Value from exactly [`Self::SIZE`] bytes
+    fn from_bytes(bytes: &[u8], endian: Endian) -> Self;
+
+    /// Bytes of the value in the given byte order
+    fn to_bytes(self, endian: Endian) -> Vec<u8>;
+}
+
+macro_rules! impl_scalar {
+    ($($t:ty),*) => {$(
+        impl Scalar for $t {
+            const SIZE: usize = std::mem::size_of::<$t>();
+
+            fn from_bytes(bytes: &[u8], endian: Endian) -> Self {
+                let bytes = bytes.try_into().expect("slice of Self::SIZE bytes");
+                match endian {
+                    Endian::Little => <$t>::from_le_bytes(bytes),
+                    Endian::Big => <$t>::from_be_bytes(bytes),
+                }
+            }
+
+            fn to_bytes(self, endian: Endian) -> Vec<u8> {
+                match endian {
+                    Endian::Little => self.to_le_bytes().to_vec(),
+                    Endian::Big => self.to_be_bytes().to_vec(),
+                }
+            }
+        }
+    )*};
+}
+
+impl_scalar!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+/// Sparse, randomly accessible contents of a hex file
+///
+/// Two documents are equal when their data, metadata and options are; recorded
+/// changes are not compared.
+#[derive(Debug, Clone, Default)]
+pub struct HexDocument {
+    /// Start address -> bytes; chunks neither overlap nor touch
+    chunks: BTreeMap<u64, Bytes>,
+    /// Address -> comments and type information
+    metadata: BTreeMap<u64, CellMetadata>,
+    /// Options of the document, `endian` is used for typed access
+    config: Config,
+    /// Edits not yet taken, `None` while changes are not tracked
+    changes: Option<Vec<Change>>,
+}
+
+impl PartialEq for HexDocument {
+    fn eq(&self, other: &Self) -> bool {
+        self.chunks == other.chunks
+            && self.metadata == other.metadata
+            && self.config == other.config
+    }
+}
+
+impl Eq for HexDocument {}
+
+impl HexDocument {
+    /// Empty document with default options
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Empty document with the given options
+    pub fn with_config(config: Config) -> Self {
+        HexDocument {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Read hex text into a document
+    ///
+    /// Addresses are checked like in [`crate::convert::hex_to_bin`], but gaps are kept
+    /// as holes instead of being filled. The document takes the effective options at
+    /// the end of the input.
+    ///
+    /// Annotations become type hints at the address they describe. A comment on a
+    /// data line is anchored at the line address, a comment on its own line at the
+    /// next byte.
+    pub fn from_reader<R: Read>(reader: R, config: &Config) -> Result<Self> {
+        let mut validator = AddressedWriter::new(io::sink(), config.padding);
+        let mut parser = Parser::with_config(BufReader::new(reader), config.clone());
+        let mut chunks: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+        let mut metadata: BTreeMap<u64, CellMetadata> = BTreeMap::new();
+        let mut address = 0;
+        // Address and source line of the current data line, next sequential annotation
+        let mut line_start = 0;
+        let mut data_line = 0;
+        let mut cursor = 0;
+        // Comments on their own lines, anchored once the next data line is known
+        let mut comments: Vec<String> = Vec::new();
+
+        while let Some(event) = parser.next() {
+            match event? {
+                Event::Option { .. } => validator.padding = parser.config().padding,
+                Event::Address {
+                    address: next,
+                    span,
+                } => {
+                    validator.seek(next, span)?;
+                    address = next;
+                    (line_start, cursor, data_line) = (next, next, span.line);
+                    add_comments(&mut metadata, next, comments.drain(..));
+                }
+                Event::Bytes { data, span } => {
+                    validator.write_all(&data)?;
+                    let start = address;
+                    address += data.len() as u64;
+                    (line_start, cursor, data_line) = (start, start, span.line);
+                    add_comments(&mut metadata, start, comments.drain(..));
+                    // Bytes follow each other without overlap, so only extend the last chunk
+                    match chunks.last_entry() {
+                        Some(mut last) if *last.key() + last.get().len() as u64 == start => {
+                            last.get_mut().extend_from_slice(&data);
+                        }
+                        _ if data.is_empty() => {}
+                        _ => {
+                            chunks.insert(start, data);
+                        }
+                    }
+                }
+                Event::Annotation(annotation) => {
+                    let at = match annotation.offset {
+                        Some(offset) => line_start + offset,
+                        None => cursor,
+                    };
+                    let ty = parser.types().resolve(&annotation.type_name)?;
+                    let size = value_size(&ty, &Value::from(&annotation.value));
+                    cursor = at + size;
+                    let cell = metadata.entry(at).or_default();
+                    cell.type_hint = Some(annotation.type_name);
+                    cell.struct_field = annotation.name;
+                    cell.size = size;
+                }
+                Event::Comment { text, span } if span.line == data_line => {
+                    add_comments(&mut metadata, line_start, [text]);
+                }
+                Event::Comment { text, .. } => comments.push(text),
+                _ => {}
+            }
+        }
+        add_comments(&mut metadata, address, comments);
+
+        Ok(HexDocument {
+            chunks: chunks
+                .into_iter()
+                .map(|(start, data)| (start, data.into()))
+                .collect(),
+            metadata,
+            config: parser.config().clone(),
+            changes: None,
+        })
+    }
+
+    /// Document with the contents of a binary file starting at `address`
+    pub fn from_binary<R: Read>(mut reader: R, address: u64, config: Config) -> Result<Self> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let mut document = Self::with_config(config);
+        document.write(address, &data)?;
+        Ok(document)
+    }
+
+    /// Document over the bytes of `source` starting at `address`, without copying them
+    pub fn from_source(source: Arc<dyn Source>, address: u64, config: Config) -> Self {
+        let mut document = Self::with_config(config);
+        if !source.bytes().is_empty() {
+            document.chunks.insert(address, Bytes::paged(source));
+        }
+        document
+    }
+
+    /// Document over a memory-mapped binary file starting at `address`
+    ///
+    /// The file is mapped read-only; modified pages are kept in memory. The file
+    /// must not be changed by other programs while the document is open.
+    pub fn open_mapped(path: impl AsRef<Path>, address: u64, config: Config) -> Result<Self> {
+        let file = File::open(path)?;
+        if file.metadata()?.len() == 0 {
+            return Ok(Self::with_config(config));
+        }
+        // SAFETY: the mapping is read-only, and the caller keeps the file unchanged
+        // while it is mapped
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        end_address(address, map.len() as u64)?;
+        Ok(Self::from_source(Arc::new(map), address, config))
+    }
+
+    /// Write the binary image to `path`, see [`write_binary`](Self::write_binary)
+    ///
+    /// The image is streamed into a temporary file next to `path` that then replaces
+    /// it, so the file a document was mapped from can be saved over.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let mut file = tempfile::NamedTempFile::new_in(directory)?;
+        self.write_binary(BufWriter::new(file.as_file_mut()))?;
+        file.persist(path).map_err(|error| error.error)?;
+        Ok(())
+    }
+
+    /// Write the document as hex text, starting with an `#option` header
+    ///
+    /// With `repeat_address` `every_line` or `on_discontinuity` every chunk starts with
+    /// an address line. With `once` and `never` the binary image is written as one
+    /// block from the first chunk or from address 0, holes filled with the padding
+    /// byte.
+    ///
+    /// Metadata is written on the line that shows its address: type hints as
+    /// annotations with the values read from the bytes, and comments at the end of
+    /// the line, all but the last as block comments. Annotations of struct types,
+    /// whose definitions are not kept, and metadata of addresses no line shows are
+    /// left out.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<()> {
+        write_header(&mut writer, &self.config)?;
+        let layout = LineLayout::new(&self.config, render::width(self));
+        for line in render::lines(self, 0..u64::MAX) {
+            render::write_line(&mut writer, self, line, &layout)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Write the binary image from address 0, filling holes with the padding byte
+    pub fn write_binary<W: Write>(&self, mut writer: W) -> Result<()> {
+        let Some(range) = self.bounds() else {
+            return Ok(());
+        };
+        let padding = self.padding_byte(0..range.end)?;
+        let mut position = 0;
+        for (&start, data) in &self.chunks {
+            io::copy(&mut io::repeat(padding).take(start - position), &mut writer)?;
+            for slice in data.slices(0..data.len()) {
+                writer.write_all(slice)?;
+            }
+            position = start + data.len();
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Byte that fills the holes of `range`; fails if it has holes and padding is
+    /// forbidden
+    pub(crate) fn padding_byte(&self, range: Range<u64>) -> Result<u8> {
+        match self.config.padding {
+            Padding::Value(byte) => Ok(byte),
+            Padding::Forbidden => match self.holes(range).next() {
+                Some((expected, address)) => Err(Error::Config(format!(
+                    "gap between {:08X} and {:08X} is not allowed with padding=forbidden",
+                    expected, address
+                ))),
+                None => Ok(0),
+            },
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Options of the document
+    ///
+    /// Changing them is not recorded as a [`Change`]; a [`Renderer`] has to be
+    /// created anew afterwards.
+    pub fn config_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    /// Start or stop recording edits as [`Change`]s
+    ///
+    /// Stopping drops the changes that were not taken yet.
+    pub fn track_changes(&mut self, enabled: bool) {
+        if enabled != self.changes.is_some() {
+            self.changes = enabled.then(Vec::new);
+        }
+    }
+
+    /// Changes recorded since the previous call, oldest first
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        self.changes
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn notify(&mut self, change: Change) {
+        if let Some(changes) = &mut self.changes {
+            changes.push(change);
+        }
+    }
+
+    /// Whether the document holds no bytes
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Number of bytes stored, not counting holes
+    pub fn len(&self) -> u64 {
+        self.chunks.values().map(Bytes::len).sum()
+    }
+
+    /// Range from the first to past the last stored byte
+    pub fn bounds(&self) -> Option<Range<u64>> {
+        let (&first, _) = self.chunks.first_key_value()?;
+        let (&last, data) = self.chunks.last_key_value()?;
+        Some(first..last + data.len())
+    }
+
+    /// Address ranges of the continuous chunks of data
+    pub fn chunks(&self) -> impl Iterator<Item = Range<u64>> + '_ {
+        self.chunks
+            .iter()
+            .map(|(&start, data)| start..start + data.len())
+    }
+
+    /// Holes between chunks inside `range`, as `(start, end)` pairs
+    pub fn holes(&self, range: Range<u64>) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let end = range.end;
+        let mut position = range.start;
+        self.chunks_in(range)
+            .map(|(start, data)| (start, start + data.len()))
+            .chain(std::iter::once((end, end)))
+            .filter_map(move |(start, stop)| {
+                let hole = (position < start).then_some((position, start.min(end)));
+                position = position.max(stop);
+                hole
+            })
+    }
+
+    pub fn get_u8(&self, address: u64) -> Option<u8> {
+        let (&start, data) = self.chunks.range(..=address).next_back()?;
+        data.get(address - start)
+    }
+
+    pub fn set_u8(&mut self, address: u64, value: u8) -> Result<()> {
+        self.write(address, &[value])
+    }
+
+    /// Bytes of `range`, or `None` if any of them falls into a hole
+    ///
+    /// The bytes are borrowed unless they span modified pages of a source.
+    pub fn read(&self, range: Range<u64>) -> Option<Cow<'_, [u8]>> {
+        if range.is_empty() {
+            return Some(Cow::Borrowed(&[]));
+        }
+        let (&start, data) = self.chunks.range(..=range.start).next_back()?;
+        data.read(range.start - start..range.end.checked_sub(start)?)
+    }
+
+    /// Write bytes at `address`, extending or merging chunks as needed
+    ///
+    /// Bytes that would end past the last u64 address are an error, and nothing is
+    /// written.
+    pub fn write(&mut self, address: u64, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = end_address(address, data.len() as u64)?;
+        self.write_chunks(address, data);
+        self.notify(Change::Modified(address..end));
+        Ok(())
+    }
+
+    fn write_chunks(&mut self, address: u64, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let end = address + data.len() as u64;
+        // The chunk that contains or ends right at `address` absorbs the write
+        let merged_start = match self.chunks.range(..=address).next_back() {
+            Some((&start, chunk)) if start + chunk.len() >= address => start,
+            _ => address,
+        };
+        let touching: Vec<u64> = self
+            .chunks
+            .range(merged_start..=end)
+            .map(|(&start, _)| start)
+            .collect();
+
+        let mut merged = Bytes::default();
+        for start in touching {
+            let chunk = self.chunks.remove(&start).expect("chunk listed above");
+            // A hole before the chunk lies inside the written range
+            merged.resize(start - merged_start);
+            merged.append(chunk);
+        }
+        merged.resize(merged.len().max(end - merged_start));
+        merged.write(address - merged_start, data);
+        self.chunks.insert(merged_start, merged);
+    }
+
+    /// Insert bytes at `address`, moving all later bytes and metadata up
+    ///
+    /// Typed values that the insertion cuts through lose their type information;
+    /// they are returned so that the caller can report them. Moving bytes or
+    /// metadata past the last u64 address is an error, and nothing is changed.
+    pub fn insert_bytes(&mut self, address: u64, data: &[u8]) -> Result<Vec<Invalidated>> {
+        let count = data.len() as u64;
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let end = self
+            .bounds()
+            .map_or(address, |bounds| bounds.end.max(address));
+        let last_metadata = self
+            .metadata
+            .range(address..)
+            .next_back()
+            .map(|(&at, _)| at);
+        if end.checked_add(count).is_none()
+            || last_metadata.is_some_and(|at| at.checked_add(count).is_none())
+        {
+            return Err(Error::OutOfRange(format!(
+                "inserting {} bytes at {:X} moves data past the end of u64 addresses",
+                count, address
+            )));
+        }
+        let invalidated = self.shift_up(address, count);
+        // Merges with the chunks around the insertion point
+        self.write_chunks(address, data);
+        self.notify(Change::Inserted { address, count });
+        Ok(invalidated)
+    }
+
+    /// Move bytes and metadata at or after `address` up, leaving a hole of `count` bytes
+    pub(crate) fn open_gap(&mut self, address: u64, count: u64) -> Vec<Invalidated> {
+        if count == 0 {
+            return Vec::new();
+        }
+        let invalidated = self.shift_up(address, count);
+        self.notify(Change::Inserted { address, count });
+        invalidated
+    }
+
+    fn shift_up(&mut self, address: u64, count: u64) -> Vec<Invalidated> {
+        self.split_chunk(address);
+        let moved = self.chunks.split_off(&address);
+        self.chunks.extend(
+            moved
+                .into_iter()
+                .map(|(start, chunk)| (start + count, chunk)),
+        );
+        metadata::rebase_insert(&mut self.metadata, address, count)
+    }
+
+    /// Remove the bytes of `range` without moving later bytes, leaving a hole
+    pub fn clear_range(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+        self.clear_chunks(range.clone());
+        self.notify(Change::Modified(range));
+    }
+
+    fn clear_chunks(&mut self, range: Range<u64>) {
+        self.split_chunk(range.start);
+        self.split_chunk(range.end);
+        let mut removed = self.chunks.split_off(&range.start);
+        let mut kept = removed.split_off(&range.end);
+        self.chunks.append(&mut kept);
+    }
+
+    /// Stored parts of `range` as `(address, bytes)` pairs
+    pub(crate) fn snapshot(&self, range: Range<u64>) -> Vec<(u64, Vec<u8>)> {
+        let (from, to) = (range.start, range.end);
+        self.chunks_in(range)
+            .map(|(start, data)| {
+                let first = from.max(start);
+                let last = to.min(start + data.len());
+                let bytes = data.read(first - start..last - start);
+                (first, bytes.expect("range inside the chunk").into_owned())
+            })
+            .collect()
+    }
+
+    /// Replace the contents of `range` with a [`snapshot`](Self::snapshot) of it
+    pub(crate) fn restore(&mut self, range: Range<u64>, snapshot: &[(u64, Vec<u8>)]) {
+        if range.is_empty() {
+            return;
+        }
+        self.clear_chunks(range.clone());
+        for (address, bytes) in snapshot {
+            self.write_chunks(*address, bytes);
+        }
+        self.notify(Change::Modified(range));
+    }
+
+    /// Delete `count` bytes at `start`, moving all later bytes and metadata down
+    ///
+    /// Holes inside the range shrink as well. Metadata inside the range is dropped
+    /// and typed values cut by the range lose their type information; both are
+    /// returned so that the caller can report them. A range that ends past the last
+    /// u64 address is an error.
+    pub fn delete_range(&mut self, start: u64, count: u64) -> Result<Vec<Invalidated>> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let end = end_address(start, count)?;
+        self.split_chunk(start);
+        self.split_chunk(end);
+        let mut deleted = self.chunks.split_off(&start);
+        let moved = deleted.split_off(&end);
+        self.chunks
+            .extend(moved.into_iter().map(|(at, chunk)| (at - count, chunk)));
+        self.merge_chunks(start);
+        self.notify(Change::Deleted { start, count });
+        Ok(metadata::rebase_delete(&mut self.metadata, start, count))
+    }
+
+    /// Metadata attached to `address`
+    pub fn metadata(&self, address: u64) -> Option<&CellMetadata> {
+        self.metadata.get(&address)
+    }
+
+    /// Attach metadata to `address`, replacing the previous one
+    pub fn set_metadata(&mut self, address: u64, metadata: CellMetadata) {
+        if metadata.is_empty() {
+            self.metadata.remove(&address);
+        } else {
+            self.metadata.insert(address, metadata);
+        }
+        self.notify(Change::Metadata(address));
+    }
+
+    pub fn remove_metadata(&mut self, address: u64) -> Option<CellMetadata> {
+        let removed = self.metadata.remove(&address);
+        if removed.is_some() {
+            self.notify(Change::Metadata(address));
+        }
+        removed
+    }
+
+    /// Metadata attached to addresses inside `range`
+    pub fn metadata_in(
+        &self,
+        range: Range<u64>,
+    ) -> impl Iterator<Item = (u64, &CellMetadata)> + '_ {
+        self.metadata
+            .range(range)
+            .map(|(&address, cell)| (address, cell))
+    }
+
+    /// Split the chunk that contains `address` so that a chunk starts there
+    fn split_chunk(&mut self, address: u64) {
+        if let Some((&start, chunk)) = self.chunks.range_mut(..address).next_back() {
+            if start + chunk.len() > address {
+                let tail = chunk.split_off(address - start);
+                self.chunks.insert(address, tail);
+            }
+        }
+    }
+
+    /// Merge the chunk starting at `address` into the chunk that ends there
+    fn merge_chunks(&mut self, address: u64) {
+        let Some((&start, previous)) = self.chunks.range(..address).next_back() else {
+            return;
+        };
+        if start + previous.len() == address {
+            if let Some(next) = self.chunks.remove(&address) {
+                self.chunks
+                    .get_mut(&start)
+                    .expect("chunk found above")
+                    .append(next);
+            }
+        }
+    }
+
+    /// Integer at `address` in the document byte order
+    pub fn read_value<T: Scalar>(&self, address: u64) -> Option<T> {
+        let bytes = self.read(address..address.checked_add(T::SIZE as u64)?)?;
+        Some(T::from_bytes(&bytes, self.config.endian))
+    }
+
+    /// Write an integer at `address` in the document byte order
+    pub fn write_value<T: Scalar>(&mut self, address: u64, value: T) -> Result<()> {
+        self.write(address, &value.to_bytes(self.config.endian))
+    }
+
+    /// Stored bytes of `range` with their addresses, skipping holes
+    pub fn bytes(&self, range: Range<u64>) -> impl Iterator<Item = (u64, u8)> + '_ {
+        let (from, to) = (range.start, range.end);
+        self.chunks_in(range).flat_map(move |(start, data)| {
+            let first = from.max(start);
+            let last = to.min(start + data.len());
+            let bytes = data
+                .slices(first - start..last - start)
+                .into_iter()
+                .flatten();
+            (first..).zip(bytes.copied())
+        })
+    }
+
+    /// All bytes of `range`, holes filled with `padding`
+    pub fn bytes_padded(&self, range: Range<u64>, padding: u8) -> impl Iterator<Item = u8> + '_ {
+        let mut stored = self.bytes(range.clone()).peekable();
+        range.map(
+            move |address| match stored.next_if(|&(a, _)| a == address) {
+                Some((_, byte)) => byte,
+                None => padding,
+            },
+        )
+    }
+
+    /// Chunks that overlap `range`
+    fn chunks_in(&self, range: Range<u64>) -> impl Iterator<Item = (u64, &Bytes)> + '_ {
+        let first = match self.chunks.range(..=range.start).next_back() {
+            Some((&start, data)) if start + data.len() > range.start => start,
+            _ => range.start,
+        };
+        let end = range.end.max(first);
+        self.chunks
+            .range(first..end)
+            .map(|(&start, data)| (start, data))
+    }
+}
+
+/// Append comments to the metadata at `address`, one per line
+fn add_comments(
+    metadata: &mut BTreeMap<u64, CellMetadata>,
+    address: u64,
+    comments: impl IntoIterator<Item = String>,
+) {
+    for text in comments {
+        let comment = &mut metadata.entry(address).or_default().comment;
+        match comment {
+            Some(existing) => {
+                existing.push('\n');
+                existing.push_str(&text);
+            }
+            None => *comment = Some(text),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(document: &HexDocument) -> Vec<(u64, Vec<u8>)> {
+        document
+            .chunks()
+            .map(|range| (range.start, document.read(range).unwrap().into_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn test_write_merges_chunks() {
+        let mut document = HexDocument::new();
+        document.write(0x10, &[1, 2]).unwrap();
+        document.write(0x20, &[5]).unwrap();
+        assert_eq!(chunks(&document), vec![(0x10, vec![1, 2]), (0x20, vec![5])]);
+
+        // extend at the end and at the start
+        document.set_u8(0x12, 3).unwrap();
+        document.set_u8(0x1F, 4).unwrap();
+        assert_eq!(
+            chunks(&document),
+            vec![(0x10, vec![1, 2, 3]), (0x1F, vec![4, 5])]
+        );
+
+        // fill the hole between two chunks
+        document.write(0x13, &[0; 12]).unwrap();
+        assert_eq!(chunks(&document).len(), 1);
+        assert_eq!(document.bounds(), Some(0x10..0x21));
+        assert_eq!(document.len(), 0x11);
+
+        // overwrite across the end of the chunk
+        document.write(0x20, &[9, 9]).unwrap();
+        assert_eq!(document.read(0x1F..0x22).as_deref(), Some(&[4, 9, 9][..]));
+        assert_eq!(document.get_u8(0x22), None);
+        assert_eq!(document.get_u8(0x0F), None);
+    }
+
+    #[test]
+    fn test_typed_access() {
+        let mut document = HexDocument::new();
+        document.write_value(0x100, 0x11223344u32).unwrap();
+        assert_eq!(
+            document.read(0x100..0x104).as_deref(),
+            Some(&[0x44, 0x33, 0x22, 0x11][..])
+        );
+        assert_eq!(document.read_value::<u16>(0x102), Some(0x1122));
+        assert_eq!(document.read_value::<u32>(0x102), None);
+
+        document.config_mut().endian = Endian::Big;
+        assert_eq!(document.read_value::<u16>(0x102), Some(0x2211));
+        document.write_value(0x104, -2i16).unwrap();
+        assert_eq!(
+            document.read(0x104..0x106).as_deref(),
+            Some(&[0xFF, 0xFE][..])
+        );
+        assert_eq!(document.read_value::<i16>(0x104), Some(-2));
+    }
+
+    #[test]
+    fn test_range_iterators() {
+        let mut document = HexDocument::new();
+        document.write(2, &[1, 2]).unwrap();
+        document.write(6, &[3]).unwrap();
+
+        let stored: Vec<(u64, u8)> = document.bytes(0..10).collect();
+        assert_eq!(stored, vec![(2, 1), (3, 2), (6, 3)]);
+        let stored: Vec<(u64, u8)> = document.bytes(3..6).collect();
+        assert_eq!(stored, vec![(3, 2)]);
+
+        let padded: Vec<u8> = document.bytes_padded(1..8, 0xEE).collect();
+        assert_eq!(padded, vec![0xEE, 1, 2, 0xEE, 0xEE, 3, 0xEE]);
+
+        let holes: Vec<(u64, u64)> = document.holes(0..10).collect();
+        assert_eq!(holes, vec![(0, 2), (4, 6), (7, 10)]);
+        let holes: Vec<(u64, u64)> = document.holes(3..7).collect();
+        assert_eq!(holes, vec![(4, 6)]);
+    }
+
+    #[test]
+    fn test_from_reader_and_write_to() {
+        let input = "#option padding=FF\n\
+                     00000010: 48 65 6C 6C 6F // hello\n\
+                     00000020: 01 02\n\
+                     \x20         03\n";
+        let document = HexDocument::from_reader(input.as_bytes(), &Config::default()).unwrap();
+        assert_eq!(
+            chunks(&document),
+            vec![(0x10, b"Hello".to_vec()), (0x20, vec![1, 2, 3])]
+        );
+        assert_eq!(document.config().padding, Padding::Value(0xFF));
+
+        let mut text = Vec::new();
+        document.write_to(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("#option padding=FF\n"));
+        // previews of short lines are aligned with the preview of a full line
+        assert!(text.ends_with(&format!(
+            "\n\n00000010: 48 65 6C 6C 6F{} [Hello] // hello\n00000020: 01 02 03{} [...]\n",
+            " ".repeat(33),
+            " ".repeat(39)
+        )));
+        let reread = HexDocument::from_reader(text.as_bytes(), &Config::default()).unwrap();
+        assert_eq!(chunks(&reread), chunks(&document));
+        assert_eq!(reread.metadata(0x10), document.metadata(0x10));
+
+        let mut binary = Vec::new();
+        document.write_binary(&mut binary).unwrap();
+        assert_eq!(binary.len(), 0x23);
+        assert_eq!(&binary[0x0E..0x12], &[0xFF, 0xFF, b'H', b'e']);
+
+        let mut binary = Vec::new();
+        let input = "00000000: 01\n00000004: 02\n";
+        crate::convert::hex_to_bin(input.as_bytes(), &mut binary, &Config::default()).unwrap();
+        let document = HexDocument::from_binary(&binary[..], 0x100, Config::default()).unwrap();
+        assert_eq!(chunks(&document), vec![(0x100, vec![1, 0, 0, 0, 2])]);
+    }
+
+    #[test]
+    fn test_write_binary_forbidden_padding() {
+        let mut document =
+            HexDocument::with_config(Config::default().with_padding(Padding::Forbidden));
+        document.write(0, &[1]).unwrap();
+        document.write(4, &[2]).unwrap();
+        let err = document.write_binary(io::sink()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Configuration error: gap between 00000001 and 00000004 is not allowed with padding=forbidden"
+        );
+    }
+
+    #[test]
+    fn test_mapped_document() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("image.bin");
+        let mut expected: Vec<u8> = (0..3 * PAGE_SIZE + 100).map(|i| i as u8).collect();
+        std::fs::write(&path, &expected).unwrap();
+
+        let mut document = HexDocument::open_mapped(&path, 0, Config::default()).unwrap();
+        assert_eq!(document.len(), expected.len() as u64);
+        assert!(matches!(document.read(8..16), Some(Cow::Borrowed(_))));
+
+        document.write(PAGE_SIZE - 1, &[0xAA, 0xBB]).unwrap();
+        expected[PAGE_SIZE as usize - 1..PAGE_SIZE as usize + 1].copy_from_slice(&[0xAA, 0xBB]);
+        document.insert_bytes(10, &[1, 2, 3]).unwrap();
+        expected.splice(10..10, [1, 2, 3]);
+        document.delete_range(2 * PAGE_SIZE, 50).unwrap();
+        expected.drain(2 * PAGE_SIZE as usize..2 * PAGE_SIZE as usize + 50);
+        document.write(expected.len() as u64, &[0xCC]).unwrap();
+        expected.push(0xCC);
+        assert_eq!(
+            document.read(0..document.len()).unwrap().into_owned(),
+            expected
+        );
+        assert_eq!(
+            document,
+            HexDocument::from_binary(&expected[..], 0, Config::default()).unwrap()
+        );
+
+        // saving over the mapped file replaces it
+        document.save(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_insert_and_delete_bytes() {
+        let mut document = HexDocument::new();
+        document.write(0, &[0, 1, 2, 3]).unwrap();
+        document.write(8, &[8, 9]).unwrap();
+
+        assert!(document.insert_bytes(2, &[0xA, 0xB]).unwrap().is_empty());
+        assert_eq!(
+            chunks(&document),
+            vec![(0, vec![0, 1, 0xA, 0xB, 2, 3]), (10, vec![8, 9])]
+        );
+
+        // inserting into a hole keeps it apart from the neighbours
+        document.insert_bytes(7, &[7]).unwrap();
+        assert_eq!(
+            chunks(&document),
+            vec![
+                (0, vec![0, 1, 0xA, 0xB, 2, 3]),
+                (7, vec![7]),
+                (11, vec![8, 9])
+            ]
+        );
+
+        // deleting across a hole joins what remains on both sides
+        document.delete_range(5, 7).unwrap();
+        assert_eq!(chunks(&document), vec![(0, vec![0, 1, 0xA, 0xB, 2, 9])]);
+
+        document.delete_range(0, 2).unwrap();
+        assert_eq!(chunks(&document), vec![(0, vec![0xA, 0xB, 2, 9])]);
+        document.delete_range(10, 5).unwrap();
+        assert_eq!(document.len(), 4);
+    }
+
+    #[test]
+    fn test_edits_at_the_end_of_the_address_space() {
+        let mut document = HexDocument::new();
+        let last = u64::MAX - 2;
+        document.write(last, &[1, 2]).unwrap();
+        assert_eq!(document.bounds(), Some(last..u64::MAX));
+        assert_eq!(document.read_value::<u16>(last), Some(0x0201));
+        assert_eq!(document.read_value::<u32>(last), None);
+
+        let out_of_range = |result: Result<()>| matches!(result, Err(Error::OutOfRange(_)));
+        assert!(out_of_range(document.write(last, &[1, 2, 3])));
+        assert!(out_of_range(document.set_u8(u64::MAX, 1)));
+        assert!(out_of_range(document.write_value(u64::MAX - 1, 1u16)));
+        assert!(out_of_range(document.delete_range(last, 3).map(drop)));
+        assert!(out_of_range(document.insert_bytes(0, &[0]).map(drop)));
+        assert_eq!(chunks(&document), vec![(last, vec![1, 2])]);
+
+        // metadata past the data moves too
+        document.delete_range(last, 2).unwrap();
+        document.set_metadata(u64::MAX, CellMetadata::comment("end"));
+        assert!(out_of_range(document.insert_bytes(5, &[0]).map(drop)));
+        assert!(document.insert_bytes(u64::MAX, &[]).unwrap().is_empty());
+        assert!(document.metadata(u64::MAX).is_some());
+
+        let mut editor = Editor::new(document);
+        assert!(out_of_range(editor.write(u64::MAX, &[1])));
+        assert!(out_of_range(editor.delete_range(u64::MAX, 1).map(drop)));
+        assert!(!editor.can_undo());
+    }
+
+    #[test]
+    fn test_metadata_rebase() {
+        let mut document = HexDocument::new();
+        document.write(0, &[0; 16]).unwrap();
+        document.set_metadata(0, CellMetadata::comment("// header"));
+        document.set_metadata(4, CellMetadata::typed("u32", 4).with_struct_field("size"));
+        document.set_metadata(8, CellMetadata::typed("u16", 2));
+        document.set_metadata(12, CellMetadata::comment("// tail"));
+
+        // insertion inside the u32 drops its type, later metadata moves up
+        let invalidated = document.insert_bytes(6, &[0xFF]).unwrap();
+        assert_eq!(invalidated.len(), 1);
+        assert_eq!(invalidated[0].address, 4);
+        assert_eq!(invalidated[0].reason, InvalidationReason::Split);
+        assert_eq!(
+            invalidated[0].metadata.struct_field.as_deref(),
+            Some("size")
+        );
+        assert!(document.metadata(4).is_none());
+        assert_eq!(
+            document.metadata(9).unwrap().type_hint.as_deref(),
+            Some("u16")
+        );
+        assert_eq!(
+            document.metadata(13).unwrap().comment.as_deref(),
+            Some("// tail")
+        );
+
+        // insertion right before a value moves it without invalidating
+        assert!(document.insert_bytes(9, &[0xFF]).unwrap().is_empty());
+        assert_eq!(document.metadata(10).unwrap().size, 2);
+
+        // deletion drops metadata inside and splits the value it starts in
+        document.set_metadata(
+            1,
+            CellMetadata {
+                comment: Some("// kept".to_string()),
+                ..CellMetadata::typed("u32", 4)
+            },
+        );
+        let invalidated = document.delete_range(3, 8).unwrap();
+        let reasons: Vec<(u64, InvalidationReason)> =
+            invalidated.iter().map(|i| (i.address, i.reason)).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (1, InvalidationReason::Split),
+                (10, InvalidationReason::Deleted)
+            ]
+        );
+        assert_eq!(
+            document.metadata(1),
+            Some(&CellMetadata::comment("// kept"))
+        );
+        let addresses: Vec<u64> = document.metadata_in(0..100).map(|(a, _)| a).collect();
+        assert_eq!(addresses, vec![0, 1, 6]);
+    }
+
+    #[test]
+    fn test_from_reader_metadata() {
+        let input = "#struct pair { a:u8, b:u8 }\n\
+                     // header comment\n\
+                     00000010: 01 00 02 03 | u16=1 (p):pair={a=2, b=3} // values\n\
+                     00000014: 41 42 43 | +01:utf8=\"BC\"\n";
+        let document = HexDocument::from_reader(input.as_bytes(), &Config::default()).unwrap();
+
+        let cell = document.metadata(0x10).unwrap();
+        assert_eq!(
+            cell.comment.as_deref(),
+            Some("// header comment\n// values")
+        );
+        assert_eq!(cell.type_hint.as_deref(), Some("u16"));
+        assert_eq!(cell.size, 2);
+        let cell = document.metadata(0x12).unwrap();
+        assert_eq!(cell.type_hint.as_deref(), Some("pair"));
+        assert_eq!(cell.struct_field.as_deref(), Some("p"));
+        assert_eq!(cell.size, 2);
+        assert_eq!(
+            document.metadata(0x15),
+            Some(&CellMetadata::typed("utf8", 2))
+        );
+    }
+
+    #[test]
+    fn test_from_reader_errors() {
+        let input = "00000010: 01 02\n00000008: 03\n";
+        let err = HexDocument::from_reader(input.as_bytes(), &Config::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2, column 1: address 00000008 goes backwards from 00000010"
+        );
+    }
+}
@@ -0,0 +1,19 @@
+//! Notifications about edits of a document
+
+use std::ops::Range;
+
+/// Edit of a [`HexDocument`](super::HexDocument), in the order it happened
+///
+/// Addresses are those of the document right after the edit. Metadata moved or
+/// dropped by an insertion or deletion is not reported separately.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// Bytes of the range were written or cleared; other bytes did not move
+    Modified(Range<u64>),
+    /// `count` bytes were inserted at `address`, later bytes moved up
+    Inserted { address: u64, count: u64 },
+    /// `count` bytes were deleted at `start`, later bytes moved down
+    Deleted { start: u64, count: u64 },
+    /// Metadata at the address was set or removed
+    Metadata(u64),
+}
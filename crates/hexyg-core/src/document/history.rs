@@ -0,0 +1,568 @@
+//! Undo and redo of document edits
+
+use super::{CellMetadata, Change, HexDocument, Invalidated};
+use crate::convert::end_address;
+use crate::Result;
+use std::collections::VecDeque;
+
+/// Default limit for the memory held by undo and redo history
+pub const DEFAULT_HISTORY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Reversible change of a document
+#[derive(Debug, Clone)]
+enum Operation {
+    /// `after` was written at `address`; `before` holds what the range contained
+    Write {
+        address: u64,
+        before: Vec<(u64, Vec<u8>)>,
+        after: Vec<u8>,
+    },
+    Insert {
+        address: u64,
+        data: Vec<u8>,
+        invalidated: Vec<Invalidated>,
+    },
+    Delete {
+        start: u64,
+        count: u64,
+        removed: Vec<(u64, Vec<u8>)>,
+        invalidated: Vec<Invalidated>,
+    },
+    Metadata {
+        address: u64,
+        before: Option<CellMetadata>,
+        after: Option<CellMetadata>,
+    },
+}
+
+impl Operation {
+    fn apply(&self, document: &mut HexDocument) {
+        match self {
+            Operation::Write { address, after, .. } => {
+                document
+                    .write(*address, after)
+                    .expect("write applied before");
+            }
+            Operation::Insert { address, data, .. } => {
+                document
+                    .insert_bytes(*address, data)
+                    .expect("insertion applied before");
+            }
+            Operation::Delete { start, count, .. } => {
+                document
+                    .delete_range(*start, *count)
+                    .expect("deletion applied before");
+            }
+            Operation::Metadata { address, after, .. } => {
+                put_metadata(document, *address, after.clone())
+            }
+        }
+    }
+
+    fn revert(&self, document: &mut HexDocument) {
+        match self {
+            Operation::Write {
+                address,
+                before,
+                after,
+            } => document.restore(*address..*address + after.len() as u64, before),
+            Operation::Insert {
+                address,
+                data,
+                invalidated,
+            } => {
+                document
+                    .delete_range(*address, data.len() as u64)
+                    .expect("inserted bytes are in range");
+                restore_metadata(document, invalidated);
+            }
+            Operation::Delete {
+                start,
+                count,
+                removed,
+                invalidated,
+            } => {
+                document.open_gap(*start, *count);
+                document.restore(*start..*start + count, removed);
+                restore_metadata(document, invalidated);
+            }
+            Operation::Metadata {
+                address, before, ..
+            } => put_metadata(document, *address, before.clone()),
+        }
+    }
+
+    /// Approximate memory held by the operation
+    fn size(&self) -> usize {
+        let chunks =
+            |chunks: &[(u64, Vec<u8>)]| chunks.iter().map(|(_, d)| d.len() + 32).sum::<usize>();
+        let cell = |cell: &CellMetadata| {
+            [&cell.comment, &cell.type_hint, &cell.struct_field]
+                .iter()
+                .map(|text| text.as_ref().map_or(0, String::len))
+                .sum::<usize>()
+                + std::mem::size_of::<CellMetadata>()
+        };
+        let invalidated =
+            |list: &[Invalidated]| list.iter().map(|i| cell(&i.metadata)).sum::<usize>();
+        std::mem::size_of::<Operation>()
+            + match self {
+                Operation::Write { before, after, .. } => chunks(before) + after.len(),
+                Operation::Insert {
+                    data,
+                    invalidated: list,
+                    ..
+                } => data.len() + invalidated(list),
+                Operation::Delete {
+                    removed,
+                    invalidated: list,
+                    ..
+                } => chunks(removed) + invalidated(list),
+                Operation::Metadata { before, after, .. } => {
+                    before.as_ref().map_or(0, cell) + after.as_ref().map_or(0, cell)
+                }
+            }
+    }
+}
+
+fn put_metadata(document: &mut HexDocument, address: u64, metadata: Option<CellMetadata>) {
+    match metadata {
+        Some(metadata) => document.set_metadata(address, metadata),
+        None => {
+            document.remove_metadata(address);
+        }
+    }
+}
+
+fn restore_metadata(document: &mut HexDocument, invalidated: &[Invalidated]) {
+    for entry in invalidated {
+        document.set_metadata(entry.address, entry.metadata.clone());
+    }
+}
+
+/// Operations undone and redone as one step
+#[derive(Debug, Default)]
+struct Transaction {
+    operations: Vec<Operation>,
+    size: usize,
+}
+
+impl Transaction {
+    fn push(&mut self, operation: Operation) {
+        self.size += operation.size();
+        self.operations.push(operation);
+    }
+}
+
+/// [`HexDocument`] with undo and redo
+///
+/// Every edit is one undo step unless it is made between [`Editor::begin`] and
+/// [`Editor::commit`], in which case the whole group is undone at once. Consecutive
+/// single-byte writes at adjacent addresses, as produced by typing, are merged into
+/// one step. Old steps are dropped when the history exceeds its memory limit.
+#[derive(Debug)]
+pub struct Editor {
+    document: HexDocument,
+    undo: VecDeque<Transaction>,
+    redo: Vec<Transaction>,
+    /// Transaction being grouped and its nesting depth
+    open: Option<Transaction>,
+    depth: usize,
+    /// Whether the next single-byte write may extend the last undo step
+    coalesce: bool,
+    /// Memory held by `undo` and `redo`
+    used: usize,
+    limit: usize,
+}
+
+impl Editor {
+    pub fn new(document: HexDocument) -> Self {
+        Editor {
+            document,
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            open: None,
+            depth: 0,
+            coalesce: false,
+            used: 0,
+            limit: DEFAULT_HISTORY_LIMIT,
+        }
+    }
+
+    /// Limit the memory held by the history, in bytes
+    pub fn with_memory_limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self.trim();
+        self
+    }
+
+    pub fn document(&self) -> &HexDocument {
+        &self.document
+    }
+
+    pub fn into_document(self) -> HexDocument {
+        self.document
+    }
+
+    /// Start or stop recording edits of the document, see [`HexDocument::track_changes`]
+    ///
+    /// Undo and redo are recorded like any other edit.
+    pub fn track_changes(&mut self, enabled: bool) {
+        self.document.track_changes(enabled);
+    }
+
+    /// Changes of the document recorded since the previous call
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        self.document.take_changes()
+    }
+
+    /// Start a group of edits undone as one step; groups may be nested
+    pub fn begin(&mut self) {
+        self.coalesce = false;
+        self.depth += 1;
+        self.open.get_or_insert_with(Transaction::default);
+    }
+
+    /// Finish the group started by the matching [`Editor::begin`]
+    pub fn commit(&mut self) {
+        if self.depth == 0 {
+            return;
+        }
+        self.depth -= 1;
+        if self.depth == 0 {
+            if let Some(transaction) = self.open.take() {
+                self.push_undo(transaction);
+            }
+        }
+    }
+
+    /// Write bytes at `address`, see [`HexDocument::write`]
+    pub fn write(&mut self, address: u64, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let range = address..end_address(address, data.len() as u64)?;
+        let before = self.document.snapshot(range);
+        self.document.write(address, data)?;
+
+        if data.len() == 1
+            && self.coalesce
+            && self.extend_last_write(address, before.clone(), data[0])
+        {
+            return Ok(());
+        }
+        self.record(Operation::Write {
+            address,
+            before,
+            after: data.to_vec(),
+        });
+        self.coalesce = data.len() == 1 && self.open.is_none();
+        Ok(())
+    }
+
+    pub fn set_u8(&mut self, address: u64, value: u8) -> Result<()> {
+        self.write(address, &[value])
+    }
+
+    /// Insert bytes at `address`, see [`HexDocument::insert_bytes`]
+    pub fn insert_bytes(&mut self, address: u64, data: &[u8]) -> Result<Vec<Invalidated>> {
+        let invalidated = self.document.insert_bytes(address, data)?;
+        if !data.is_empty() {
+            self.record(Operation::Insert {
+                address,
+                data: data.to_vec(),
+                invalidated: invalidated.clone(),
+            });
+        }
+        Ok(invalidated)
+    }
+
+    /// Delete `count` bytes at `start`, see [`HexDocument::delete_range`]
+    pub fn delete_range(&mut self, start: u64, count: u64) -> Result<Vec<Invalidated>> {
+        let removed = self.document.snapshot(start..end_address(start, count)?);
+        let invalidated = self.document.delete_range(start, count)?;
+        if count > 0 {
+            self.record(Operation::Delete {
+                start,
+                count,
+                removed,
+                invalidated: invalidated.clone(),
+            });
+        }
+        Ok(invalidated)
+    }
+
+    /// Attach metadata to `address`, see [`HexDocument::set_metadata`]
+    pub fn set_metadata(&mut self, address: u64, metadata: CellMetadata) {
+        let before = self.document.metadata(address).cloned();
+        self.document.set_metadata(address, metadata);
+        let after = self.document.metadata(address).cloned();
+        self.record(Operation::Metadata {
+            address,
+            before,
+            after,
+        });
+    }
+
+    pub fn remove_metadata(&mut self, address: u64) -> Option<CellMetadata> {
+        let before = self.document.remove_metadata(address);
+        if before.is_some() {
+            self.record(Operation::Metadata {
+                address,
+                before: before.clone(),
+                after: None,
+            });
+        }
+        before
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty() || self.open.is_some()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Revert the last step; an open group is committed first
+    ///
+    /// Returns `false` if there is nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        self.close_group();
+        self.coalesce = false;
+        let Some(transaction) = self.undo.pop_back() else {
+            return false;
+        };
+        for operation in transaction.operations.iter().rev() {
+            operation.revert(&mut self.document);
+        }
+        self.redo.push(transaction);
+        true
+    }
+
+    /// Apply the last undone step again
+    ///
+    /// Returns `false` if there is nothing to redo.
+    pub fn redo(&mut self) -> bool {
+        self.close_group();
+        self.coalesce = false;
+        let Some(transaction) = self.redo.pop() else {
+            return false;
+        };
+        for operation in &transaction.operations {
+            operation.apply(&mut self.document);
+        }
+        self.undo.push_back(transaction);
+        true
+    }
+
+    fn close_group(&mut self) {
+        if self.depth > 0 {
+            self.depth = 1;
+            self.commit();
+        }
+    }
+
+    /// Add an applied operation to the open group or as a new step
+    fn record(&mut self, operation: Operation) {
+        self.coalesce = false;
+        for transaction in self.redo.drain(..) {
+            self.used -= transaction.size;
+        }
+        match &mut self.open {
+            Some(transaction) => transaction.push(operation),
+            None => {
+                let mut transaction = Transaction::default();
+                transaction.push(operation);
+                self.push_undo(transaction);
+            }
+        }
+    }
+
+    fn push_undo(&mut self, transaction: Transaction) {
+        if transaction.operations.is_empty() {
+            return;
+        }
+        self.used += transaction.size;
+        self.undo.push_back(transaction);
+        self.trim();
+    }
+
+    /// Drop the oldest steps until the history fits into the limit
+    fn trim(&mut self) {
+        while self.used > self.limit {
+            let dropped = match self.undo.pop_front() {
+                Some(transaction) => transaction,
+                None => match self.redo.is_empty() {
+                    // Redo steps are dropped from the farthest one
+                    false => self.redo.remove(0),
+                    true => break,
+                },
+            };
+            self.used -= dropped.size;
+        }
+    }
+
+    /// Append a typed byte to the last single-byte write step
+    fn extend_last_write(&mut self, address: u64, before: Vec<(u64, Vec<u8>)>, byte: u8) -> bool {
+        let Some(transaction) = self.undo.back_mut() else {
+            return false;
+        };
+        let [Operation::Write {
+            address: start,
+            before: previous,
+            after,
+        }] = transaction.operations.as_mut_slice()
+        else {
+            return false;
+        };
+        if *start + after.len() as u64 != address {
+            return false;
+        }
+        after.push(byte);
+        for (at, bytes) in before {
+            match previous.last_mut() {
+                Some((last, data)) if *last + data.len() as u64 == at => data.extend(bytes),
+                _ => previous.push((at, bytes)),
+            }
+        }
+        transaction.size += 1;
+        self.used += 1;
+        for transaction in self.redo.drain(..) {
+            self.used -= transaction.size;
+        }
+        self.trim();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor() -> Editor {
+        let mut document = HexDocument::new();
+        document.write(0, &[0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+        document.set_metadata(4, CellMetadata::typed("u32", 4));
+        Editor::new(document)
+    }
+
+    #[test]
+    fn test_undo_redo_each_edit() {
+        let mut editor = editor();
+        let original = editor.document().clone();
+
+        editor.write(6, &[0xAA, 0xBB, 0xCC]).unwrap();
+        let written = editor.document().clone();
+        editor.insert_bytes(5, &[0xEE]).unwrap();
+        editor.delete_range(1, 2).unwrap();
+        editor.set_metadata(0, CellMetadata::comment("// start"));
+        editor.remove_metadata(0);
+        let edited = editor.document().clone();
+
+        for _ in 0..4 {
+            assert!(editor.undo());
+        }
+        assert_eq!(editor.document(), &written);
+        assert!(editor.undo());
+        assert_eq!(editor.document(), &original);
+        assert!(!editor.undo());
+
+        while editor.redo() {}
+        assert_eq!(editor.document(), &edited);
+        assert!(!editor.can_redo());
+    }
+
+    #[test]
+    fn test_undo_restores_holes_and_metadata() {
+        let mut editor = editor();
+        editor.write(20, &[9]).unwrap();
+        let original = editor.document().clone();
+
+        // writing into the hole merges chunks, undo brings the hole back
+        editor.write(8, &[0; 12]).unwrap();
+        assert_eq!(editor.document().chunks().count(), 1);
+        // deletion across the typed value and a hole
+        let invalidated = editor.delete_range(6, 16).unwrap();
+        assert_eq!(invalidated.len(), 1);
+        assert!(editor.document().metadata(4).is_none());
+
+        editor.undo();
+        editor.undo();
+        assert_eq!(editor.document(), &original);
+        assert_eq!(editor.document().chunks().count(), 2);
+    }
+
+    #[test]
+    fn test_transactions() {
+        let mut editor = editor();
+        let original = editor.document().clone();
+
+        editor.begin();
+        editor.write(0, &[0xFF]).unwrap();
+        editor.begin();
+        editor.insert_bytes(2, b"pasted").unwrap();
+        editor.commit();
+        editor.set_metadata(2, CellMetadata::comment("// paste"));
+        assert!(editor.can_undo());
+        editor.commit();
+
+        assert!(editor.undo());
+        assert_eq!(editor.document(), &original);
+        assert!(!editor.can_undo());
+
+        // undo closes a group that is still open
+        editor.begin();
+        editor.write(0, &[1]).unwrap();
+        editor.write(1, &[1]).unwrap();
+        assert!(editor.undo());
+        assert_eq!(editor.document(), &original);
+    }
+
+    #[test]
+    fn test_typing_coalesces() {
+        let mut editor = editor();
+        let original = editor.document().clone();
+
+        for (i, byte) in b"typed text".iter().enumerate() {
+            editor.set_u8(6 + i as u64, *byte).unwrap();
+        }
+        assert_eq!(
+            editor.document().read(6..16).as_deref(),
+            Some(&b"typed text"[..])
+        );
+        let typed = editor.document().clone();
+
+        // a jump starts a new step
+        editor.set_u8(0, 0xFF).unwrap();
+        assert!(editor.undo());
+        assert_eq!(editor.document(), &typed);
+        assert!(editor.undo());
+        assert_eq!(editor.document(), &original);
+
+        // typing after undo does not join the undone step
+        editor.redo();
+        editor.set_u8(16, b'!').unwrap();
+        editor.undo();
+        assert_eq!(editor.document(), &typed);
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let mut editor = editor().with_memory_limit(4096);
+        let original = editor.document().clone();
+        editor.write(0, &[1; 1000]).unwrap();
+        let first = editor.document().clone();
+        editor.write(0, &[2; 1000]).unwrap();
+        editor.write(0, &[3; 1000]).unwrap();
+        editor.write(0, &[4; 1000]).unwrap();
+
+        let mut steps = 0;
+        while editor.undo() {
+            steps += 1;
+        }
+        assert!(steps < 4);
+        assert_ne!(editor.document(), &original);
+        assert_ne!(editor.document(), &first);
+    }
+}
@@ -0,0 +1,135 @@
+//! Comments and type information attached to document addresses
+
+use std::collections::BTreeMap;
+
+/// Metadata of the value starting at an address
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CellMetadata {
+    /// Comment anchored at the address
+    pub comment: Option<String>,
+    /// Type of the value, e.g. `u32`, `utf8` or a struct name
+    pub type_hint: Option<String>,
+    /// Name of the value, e.g. a struct field or `(name)` of an annotation
+    pub struct_field: Option<String>,
+    /// Number of bytes covered by the typed value, 0 without a type hint
+    pub size: u64,
+}
+
+impl CellMetadata {
+    /// Metadata with only a comment
+    pub fn comment(text: impl Into<String>) -> Self {
+        CellMetadata {
+            comment: Some(text.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Metadata with only a type hint for a value of `size` bytes
+    pub fn typed(type_hint: impl Into<String>, size: u64) -> Self {
+        CellMetadata {
+            type_hint: Some(type_hint.into()),
+            size,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_struct_field(mut self, name: impl Into<String>) -> Self {
+        self.struct_field = Some(name.into());
+        self
+    }
+
+    /// Whether nothing is attached
+    pub fn is_empty(&self) -> bool {
+        self.comment.is_none() && self.type_hint.is_none() && self.struct_field.is_none()
+    }
+
+    /// Whether a typed value at `address` covers `at` but does not start there
+    fn straddles(&self, address: u64, at: u64) -> bool {
+        address < at && address + self.size > at
+    }
+
+    fn clear_type(&mut self) {
+        self.type_hint = None;
+        self.struct_field = None;
+        self.size = 0;
+    }
+}
+
+/// Why an edit changed metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidationReason {
+    /// The address was inside a deleted range, all metadata is dropped
+    Deleted,
+    /// The edit cut through the typed value, its type information is dropped
+    /// while the comment stays
+    Split,
+}
+
+/// Metadata dropped by an edit, reported to the caller
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invalidated {
+    /// Address of the metadata before the edit
+    pub address: u64,
+    /// Metadata as it was before the edit
+    pub metadata: CellMetadata,
+    pub reason: InvalidationReason,
+}
+
+/// Move metadata at or after `address` up by `count` bytes
+pub(super) fn rebase_insert(
+    metadata: &mut BTreeMap<u64, CellMetadata>,
+    address: u64,
+    count: u64,
+) -> Vec<Invalidated> {
+    let mut invalidated = Vec::new();
+    split_straddling(metadata, address, &mut invalidated);
+
+    let moved = metadata.split_off(&address);
+    metadata.extend(moved.into_iter().map(|(at, cell)| (at + count, cell)));
+    invalidated
+}
+
+/// Drop metadata inside `start..start + count` and move later metadata down
+pub(super) fn rebase_delete(
+    metadata: &mut BTreeMap<u64, CellMetadata>,
+    start: u64,
+    count: u64,
+) -> Vec<Invalidated> {
+    let mut invalidated = Vec::new();
+    split_straddling(metadata, start, &mut invalidated);
+
+    let mut deleted = metadata.split_off(&start);
+    let moved = deleted.split_off(&(start + count));
+    invalidated.extend(deleted.into_iter().map(|(address, metadata)| Invalidated {
+        address,
+        metadata,
+        reason: InvalidationReason::Deleted,
+    }));
+    metadata.extend(moved.into_iter().map(|(at, cell)| (at - count, cell)));
+    invalidated
+}
+
+/// Drop type information of values that start before `at` and continue past it
+fn split_straddling(
+    metadata: &mut BTreeMap<u64, CellMetadata>,
+    at: u64,
+    invalidated: &mut Vec<Invalidated>,
+) {
+    let straddling: Vec<u64> = metadata
+        .range(..at)
+        .filter(|(&address, cell)| cell.straddles(address, at))
+        .map(|(&address, _)| address)
+        .collect();
+    for address in straddling {
+        let cell = metadata.get_mut(&address).expect("address listed above");
+        invalidated.push(Invalidated {
+            address,
+            metadata: cell.clone(),
+            reason: InvalidationReason::Split,
+        });
+        cell.clear_type();
+        if cell.is_empty() {
+            metadata.remove(&address);
+        }
+    }
+}
@@ -0,0 +1,582 @@
+//! Incremental rendering of a document as hex text
+
+use super::{Change, HexDocument};
+use crate::config::RepeatAddress;
+use crate::convert::{address_width, write_header, LineLayout};
+use crate::format::{annotations_text, inline_comments, Anchored};
+use crate::preview::preview_text;
+use crate::types::{decode, TypeRegistry};
+use crate::Result;
+use std::borrow::Cow;
+use std::io::Write;
+use std::ops::Range;
+
+/// Data line of the hex text showing `len` bytes from `address`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Line {
+    address: u64,
+    len: usize,
+    /// Whether the line starts a block of continuous data, which shows its address
+    /// unless `repeat_address` is `never`
+    first: bool,
+}
+
+impl Line {
+    fn end(&self) -> u64 {
+        self.address + self.len as u64
+    }
+}
+
+/// Blocks of continuous data that overlap `range`: the chunks, or a single block
+/// of the binary image when `repeat_address` is `once` or `never`
+fn blocks(document: &HexDocument, range: Range<u64>) -> Vec<Range<u64>> {
+    let repeat = document.config.repeat_address;
+    if !repeat.is_continuous() {
+        return document
+            .chunks_in(range)
+            .map(|(start, data)| start..start + data.len())
+            .collect();
+    }
+    let bounds = document.bounds().map(|bounds| match repeat {
+        RepeatAddress::Never => 0..bounds.end,
+        _ => bounds,
+    });
+    bounds.into_iter().collect()
+}
+
+/// Lines of the document that overlap `range`
+///
+/// Every block of continuous data is split into lines of `line_length` bytes from its
+/// start.
+pub(super) fn lines(document: &HexDocument, range: Range<u64>) -> impl Iterator<Item = Line> {
+    let line_length = document.config.line_length.max(1) as u64;
+    let (from, to) = (range.start, range.end);
+    blocks(document, range)
+        .into_iter()
+        .flat_map(move |block| {
+            let (start, end) = (block.start, block.end);
+            let first = (from.max(start).min(end) - start) / line_length;
+            let last = (to.min(end).max(start) - start).div_ceil(line_length);
+            (first..last).map(move |i| {
+                let address = start + i * line_length;
+                Line {
+                    address,
+                    len: (end.min(address + line_length) - address) as usize,
+                    first: i == 0,
+                }
+            })
+        })
+        .filter(move |line| from < to && line.address < to && line.end() > from)
+}
+
+/// Number of hex digits of the addresses of `document`
+pub(super) fn width(document: &HexDocument) -> usize {
+    let last_address = document.bounds().map_or(0, |bounds| bounds.end - 1);
+    address_width(&document.config, last_address)
+}
+
+pub(super) fn write_line<W: Write>(
+    writer: &mut W,
+    document: &HexDocument,
+    line: Line,
+    layout: &LineLayout,
+) -> Result<()> {
+    let range = line.address..line.end();
+    let data = match document.read(range.clone()) {
+        Some(data) => data,
+        None => {
+            let padding = document.padding_byte(range.clone())?;
+            Cow::Owned(document.bytes_padded(range.clone(), padding).collect())
+        }
+    };
+    let config = &document.config;
+    let show_address = match config.repeat_address {
+        RepeatAddress::Never => false,
+        RepeatAddress::Once | RepeatAddress::OnDiscontinuity => line.first,
+        RepeatAddress::EveryLine => true,
+    };
+
+    let mut extras = Vec::new();
+    if config.show_preview {
+        extras.push(format!("[{}]", preview_text(&data, config)));
+    }
+    let annotations = annotations_text(&annotations(document, range.clone()), range.clone());
+    if !annotations.is_empty() {
+        extras.push(format!("| {}", annotations));
+    }
+    extras.extend(inline_comments(
+        document
+            .metadata_in(range)
+            .filter_map(|(_, cell)| cell.comment.as_deref()),
+    ));
+    writeln!(
+        writer,
+        "{}",
+        layout.line(line.address, show_address, &data, &extras)?
+    )?;
+    Ok(())
+}
+
+/// Annotations of the typed values that start in `range`, with their values read
+/// from the document
+///
+/// Values of struct types, whose definitions the document does not keep, and values
+/// over holes are left out.
+fn annotations(document: &HexDocument, range: Range<u64>) -> Vec<Anchored> {
+    let types = TypeRegistry::new(&document.config);
+    document
+        .metadata_in(range)
+        .filter_map(|(address, cell)| {
+            let type_hint = cell.type_hint.as_deref()?;
+            let ty = types.resolve(type_hint).ok()?;
+            let bytes = document.read(address..address + cell.size)?;
+            let value = decode(&ty, &bytes).ok()?;
+            let body = match &cell.struct_field {
+                Some(name) => format!("({}):{}={}", name, type_hint, value),
+                None => format!("{}={}", type_hint, value),
+            };
+            Some(Anchored {
+                address,
+                size: cell.size,
+                has_offset: false,
+                body,
+            })
+        })
+        .collect()
+}
+
+/// Replacement of whole lines of the previous text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextEdit {
+    /// Lines of the previous text that are replaced, counted from 0
+    pub lines: Range<usize>,
+    /// New text of those lines; every line ends with a newline
+    pub text: String,
+}
+
+/// Hex text of a document kept in step with its [`Change`]s
+///
+/// The renderer remembers which bytes every line of the text shows, so that after an
+/// edit only the lines that differ are rendered again. The text is the one written
+/// by [`HexDocument::write_to`].
+///
+/// Overwriting bytes re-renders just the lines that show them and the lines of
+/// annotations whose values cover them; setting or removing metadata re-renders the
+/// line that shows its address. Inserting or deleting
+/// bytes moves every later line to a new address, so the text is re-rendered from the
+/// edit to the end. An edit that changes the width of addresses, or the extent of
+/// the data when it is written as one block, re-renders every line.
+#[derive(Debug, Clone)]
+pub struct Renderer {
+    /// Number of `#option` lines before the data
+    header: usize,
+    /// Data lines in the order of the text
+    lines: Vec<Line>,
+    /// What the layout of every line depends on
+    layout: Layout,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Layout {
+    width: usize,
+    /// Extent of the data when it is written as one block
+    block: Option<Range<u64>>,
+}
+
+impl Layout {
+    fn of(document: &HexDocument) -> Self {
+        Layout {
+            width: width(document),
+            block: blocks(document, 0..u64::MAX)
+                .into_iter()
+                .next()
+                .filter(|_| document.config.repeat_address.is_continuous()),
+        }
+    }
+}
+
+impl Renderer {
+    /// Layout of the current text of `document`
+    pub fn new(document: &HexDocument) -> Result<Self> {
+        let mut header = Vec::new();
+        write_header(&mut header, &document.config)?;
+        Ok(Renderer {
+            header: header.iter().filter(|&&byte| byte == b'\n').count(),
+            lines: lines(document, 0..u64::MAX).collect(),
+            layout: Layout::of(document),
+        })
+    }
+
+    /// Number of lines of the text
+    pub fn line_count(&self) -> usize {
+        self.header + self.lines.len()
+    }
+
+    /// Lines of the text that show bytes of `range`
+    pub fn lines_of(&self, range: Range<u64>) -> Range<usize> {
+        let (first, last) = self.overlapping(range);
+        self.header + first..self.header + last
+    }
+
+    /// Whole text of the document; `document` must be the one the renderer follows
+    pub fn render(&self, document: &HexDocument) -> Result<String> {
+        let mut text = Vec::new();
+        write_header(&mut text, &document.config)?;
+        let layout = LineLayout::new(&document.config, self.layout.width);
+        for &line in &self.lines {
+            write_line(&mut text, document, line, &layout)?;
+        }
+        Ok(String::from_utf8(text).expect("hex text is UTF-8"))
+    }
+
+    /// Follow `changes` taken from `document` and return the edits of the text
+    ///
+    /// Edits are sorted, do not overlap and use line numbers of the previous text,
+    /// so they should be applied from the last one to the first.
+    pub fn update(&mut self, document: &HexDocument, changes: &[Change]) -> Result<Vec<TextEdit>> {
+        let layout = Layout::of(document);
+        let mut dirty: Vec<Range<u64>> = if layout != self.layout && !changes.is_empty() {
+            self.layout = layout;
+            std::iter::once(0..u64::MAX).collect()
+        } else {
+            changes
+                .iter()
+                .filter_map(|change| self.dirty(document, change))
+                .collect()
+        };
+        dirty.sort_by_key(|range| range.start);
+
+        let mut regions: Vec<Range<u64>> = Vec::new();
+        for range in dirty {
+            let mut range = self.expand(document, range);
+            while let Some(last) = regions.last() {
+                if range.start >= last.end {
+                    break;
+                }
+                let start = last.start.min(range.start);
+                range = self.expand(document, start..range.end.max(last.end));
+                regions.pop();
+            }
+            regions.push(range);
+        }
+
+        let layout = LineLayout::new(&document.config, self.layout.width);
+        let mut edits = Vec::with_capacity(regions.len());
+        for range in regions.into_iter().rev() {
+            let (first, last) = self.overlapping(range.clone());
+            let new: Vec<Line> = lines(document, range).collect();
+            let mut text = Vec::new();
+            for &line in &new {
+                write_line(&mut text, document, line, &layout)?;
+            }
+            self.lines.splice(first..last, new);
+            edits.push(TextEdit {
+                lines: self.header + first..self.header + last,
+                text: String::from_utf8(text).expect("hex text is UTF-8"),
+            });
+        }
+        edits.reverse();
+        Ok(edits)
+    }
+
+    /// Addresses whose lines may have changed
+    fn dirty(&self, document: &HexDocument, change: &Change) -> Option<Range<u64>> {
+        match *change {
+            Change::Modified(ref range) if range.is_empty() => None,
+            Change::Modified(ref range) => {
+                // Annotations show the values of the bytes they cover
+                let start = document
+                    .metadata_in(0..range.start)
+                    .filter(|&(address, cell)| address + cell.size > range.start)
+                    .map(|(address, _)| address)
+                    .fold(range.start, u64::min);
+                if self.covers(range.clone()) && document.read(range.clone()).is_some() {
+                    return Some(start..range.end);
+                }
+                // Chunks were extended, merged or split: the lines after the edit are
+                // laid out anew up to the end of the resulting chunk, and the line
+                // before it may have been extended or cut
+                let end = [range.end - 1, range.end]
+                    .into_iter()
+                    .filter_map(|address| chunk_end(document, address))
+                    .fold(range.end, u64::max);
+                Some(start.min(range.start.saturating_sub(1))..end)
+            }
+            Change::Inserted { address: at, .. } | Change::Deleted { start: at, .. } => {
+                Some(at.saturating_sub(1)..u64::MAX)
+            }
+            Change::Metadata(address) => Some(address..address + 1),
+        }
+    }
+
+    /// Grow `range` until it starts and ends at line boundaries of both the
+    /// previous and the current layout
+    fn expand(&self, document: &HexDocument, mut range: Range<u64>) -> Range<u64> {
+        loop {
+            let mut start = range.start;
+            let mut end = range.end;
+            let around_start = lines(document, start..start + 1).chain(self.line_at(start));
+            for line in around_start {
+                start = start.min(line.address);
+            }
+            if end != u64::MAX {
+                for line in lines(document, end - 1..end).chain(self.line_at(end - 1)) {
+                    end = end.max(line.end());
+                }
+            }
+            if (start..end) == range {
+                return range;
+            }
+            range = start..end;
+        }
+    }
+
+    /// Previous line that shows `address`
+    fn line_at(&self, address: u64) -> Option<Line> {
+        let index = self.lines.partition_point(|line| line.end() <= address);
+        self.lines
+            .get(index)
+            .filter(|line| line.address <= address)
+            .copied()
+    }
+
+    /// Indexes of the previous lines that overlap `range`
+    fn overlapping(&self, range: Range<u64>) -> (usize, usize) {
+        let first = self.lines.partition_point(|line| line.end() <= range.start);
+        let last = self.lines.partition_point(|line| line.address < range.end);
+        (first, last.max(first))
+    }
+
+    /// Whether the previous lines show every byte of `range`
+    fn covers(&self, range: Range<u64>) -> bool {
+        let (first, last) = self.overlapping(range.clone());
+        let mut position = range.start;
+        for line in &self.lines[first..last] {
+            if line.address > position {
+                return false;
+            }
+            position = line.end();
+        }
+        position >= range.end
+    }
+}
+
+/// End of the chunk that contains `address`
+fn chunk_end(document: &HexDocument, address: u64) -> Option<u64> {
+    let (&start, data) = document.chunks.range(..=address).next_back()?;
+    let end = start + data.len();
+    (end > address).then_some(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::CellMetadata;
+    use crate::Config;
+
+    /// Apply edits to `text` and check that the result matches a full render
+    fn apply(text: &str, edits: &[TextEdit]) -> String {
+        let mut lines: Vec<String> = text.lines().map(|line| format!("{line}\n")).collect();
+        for edit in edits.iter().rev() {
+            lines.splice(edit.lines.clone(), [edit.text.clone()]);
+        }
+        lines.concat()
+    }
+
+    fn full_text(document: &HexDocument) -> String {
+        let mut text = Vec::new();
+        document.write_to(&mut text).unwrap();
+        String::from_utf8(text).unwrap()
+    }
+
+    fn document() -> HexDocument {
+        let mut document = HexDocument::new();
+        document.write(0, &(0..40).collect::<Vec<u8>>()).unwrap();
+        document.write(0x100, &[0xAA; 20]).unwrap();
+        document.track_changes(true);
+        document
+    }
+
+    #[test]
+    fn test_changes() {
+        let mut document = document();
+        document.set_u8(3, 9).unwrap();
+        document.insert_bytes(1, &[1, 2]).unwrap();
+        document.delete_range(0, 1).unwrap();
+        document.clear_range(4..6);
+        document.set_metadata(2, CellMetadata::comment("x"));
+        assert!(document.remove_metadata(7).is_none());
+        assert_eq!(
+            document.take_changes(),
+            vec![
+                Change::Modified(3..4),
+                Change::Inserted {
+                    address: 1,
+                    count: 2
+                },
+                Change::Deleted { start: 0, count: 1 },
+                Change::Modified(4..6),
+                Change::Metadata(2),
+            ]
+        );
+        assert!(document.take_changes().is_empty());
+
+        document.track_changes(false);
+        document.set_u8(3, 9).unwrap();
+        assert!(document.take_changes().is_empty());
+    }
+
+    #[test]
+    fn test_overwrite_renders_one_line() {
+        let mut document = document();
+        let mut renderer = Renderer::new(&document).unwrap();
+        let text = renderer.render(&document).unwrap();
+        assert_eq!(text, full_text(&document));
+        let line = renderer.lines_of(0x11..0x12);
+        assert_eq!(line.len(), 1);
+        assert!(text
+            .lines()
+            .nth(line.start)
+            .unwrap()
+            .starts_with("00000010: 10 11"));
+
+        document.write(0x11, &[0xFF, 0xFE]).unwrap();
+        let changes = document.take_changes();
+        let edits = renderer.update(&document, &changes).unwrap();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].lines, line);
+        assert!(edits[0].text.starts_with("00000010: 10 FF FE 13"));
+        assert_eq!(apply(&text, &edits), full_text(&document));
+    }
+
+    #[test]
+    fn test_metadata_renders_line() {
+        let mut document = document();
+        let mut renderer = Renderer::new(&document).unwrap();
+        let mut text = renderer.render(&document).unwrap();
+        let line = renderer.lines_of(0x1E..0x1F);
+
+        document.set_metadata(0x1E, CellMetadata::typed("u32", 4).with_struct_field("len"));
+        document.set_metadata(0x10, CellMetadata::comment("// first"));
+        document.set_metadata(0x11, CellMetadata::comment("second"));
+        let changes = document.take_changes();
+        let edits = renderer.update(&document, &changes).unwrap();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].lines, line);
+        text = apply(&text, &edits);
+        assert_eq!(text, full_text(&document));
+        let rendered = text.lines().nth(line.start).unwrap();
+        assert!(
+            rendered.ends_with("| +0E(len):u32=555753246 /* first */ // second"),
+            "{rendered}"
+        );
+
+        // the value also shows the bytes of the next line
+        document.set_u8(0x21, 0).unwrap();
+        let changes = document.take_changes();
+        let edits = renderer.update(&document, &changes).unwrap();
+        assert_eq!(edits[0].lines.start, line.start);
+        text = apply(&text, &edits);
+        assert_eq!(text, full_text(&document));
+        assert!(text.contains("+0E(len):u32=2105118"));
+
+        // the annotations and comments are read back
+        let reread = HexDocument::from_reader(text.as_bytes(), &Config::default()).unwrap();
+        assert_eq!(reread.metadata(0x1E), document.metadata(0x1E));
+        assert_eq!(
+            reread
+                .metadata(0x10)
+                .and_then(|cell| cell.comment.as_deref()),
+            Some("/* first */\n// second")
+        );
+    }
+
+    #[test]
+    fn test_layout_changes() {
+        let mut document = document();
+        let mut renderer = Renderer::new(&document).unwrap();
+        let mut text = renderer.render(&document).unwrap();
+
+        let steps: [fn(&mut HexDocument); 6] = [
+            // fill the hole, merging both chunks
+            |document| document.write(0x28, &[0; 0xD8]).unwrap(),
+            |document| document.clear_range(0x0C..0x30),
+            |document| document.set_u8(0x200, 1).unwrap(),
+            |document| {
+                document.insert_bytes(0x05, &[7; 3]).unwrap();
+            },
+            |document| {
+                document.delete_range(0x02, 0x40).unwrap();
+            },
+            |document| {
+                document.set_u8(0x01, 2).unwrap();
+                document.write(0x0B, &[1, 2, 3]).unwrap();
+                document.set_metadata(0x100, CellMetadata::comment("x"));
+            },
+        ];
+        for step in steps {
+            step(&mut document);
+            let changes = document.take_changes();
+            let edits = renderer.update(&document, &changes).unwrap();
+            text = apply(&text, &edits);
+            assert_eq!(text, full_text(&document));
+            assert_eq!(renderer.line_count(), text.lines().count());
+        }
+    }
+
+    #[test]
+    fn test_address_modes() {
+        for repeat in [RepeatAddress::Once, RepeatAddress::Never] {
+            let mut document = document();
+            let config = document.config_mut();
+            config.repeat_address = repeat;
+            config.address_size = crate::config::AddressSize::Stretch;
+            let mut renderer = Renderer::new(&document).unwrap();
+            let mut text = renderer.render(&document).unwrap();
+
+            // the text is the binary image, holes filled with padding
+            let mut image = Vec::new();
+            document.write_binary(&mut image).unwrap();
+            let mut decoded = Vec::new();
+            crate::convert::hex_to_bin(text.as_bytes(), &mut decoded, document.config()).unwrap();
+            assert_eq!(decoded, image);
+            let addresses = text
+                .lines()
+                .filter(|line| line.starts_with(|c: char| c.is_ascii_hexdigit()))
+                .count();
+            assert_eq!(addresses, usize::from(repeat == RepeatAddress::Once));
+
+            let steps: [fn(&mut HexDocument); 4] = [
+                |document| document.write(0x30, &[1; 4]).unwrap(),
+                // grows the addresses to four digits
+                |document| document.set_u8(0x1000, 1).unwrap(),
+                |document| document.clear_range(0x20..0x28),
+                |document| {
+                    document.delete_range(0x00, 0x10).unwrap();
+                },
+            ];
+            for step in steps {
+                step(&mut document);
+                let changes = document.take_changes();
+                let edits = renderer.update(&document, &changes).unwrap();
+                text = apply(&text, &edits);
+                assert_eq!(text, full_text(&document));
+            }
+        }
+    }
+
+    #[test]
+    fn test_undo_changes() {
+        let mut editor = crate::document::Editor::new(document());
+        let mut renderer = Renderer::new(editor.document()).unwrap();
+        let text = renderer.render(editor.document()).unwrap();
+
+        editor.insert_bytes(0x10, &[1, 2, 3]).unwrap();
+        editor.write(0x30, &[5; 0x10]).unwrap();
+        editor.undo();
+        editor.undo();
+        let changes = editor.take_changes();
+        let edits = renderer.update(editor.document(), &changes).unwrap();
+        assert_eq!(apply(&text, &edits), text);
+    }
+}
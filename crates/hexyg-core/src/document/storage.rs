@@ -0,0 +1,386 @@
+//! Bytes of document chunks, owned or paged over a read-only source
+//!
+//! A chunk is a list of pieces. Bytes written by the document are owned; bytes of a
+//! [`Source`] such as a memory-mapped file are borrowed from it, and a page of the
+//! source is copied into an overlay only when it is modified.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// Size of the overlay pages that hold modified bytes of a source
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Read-only bytes a document can be opened over without copying them
+pub trait Source: fmt::Debug + Send + Sync {
+    fn bytes(&self) -> &[u8];
+}
+
+impl Source for memmap2::Mmap {
+    fn bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Source for Vec<u8> {
+    fn bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Continuous bytes of a chunk
+#[derive(Debug, Clone, Default)]
+pub(super) struct Bytes {
+    pieces: Vec<Piece>,
+}
+
+#[derive(Debug, Clone)]
+enum Piece {
+    Owned(Vec<u8>),
+    /// Bytes `range` of the source; modified pages, keyed by their index in the
+    /// source, override it
+    Paged {
+        source: Arc<dyn Source>,
+        range: Range<u64>,
+        pages: BTreeMap<u64, Box<[u8]>>,
+    },
+}
+
+impl Piece {
+    fn len(&self) -> u64 {
+        match self {
+            Piece::Owned(data) => data.len() as u64,
+            Piece::Paged { range, .. } => range.end - range.start,
+        }
+    }
+
+    /// Slices that make up `range` of the piece, in order
+    fn slices(&self, range: Range<u64>) -> Vec<&[u8]> {
+        let (source, offset, pages) = match self {
+            Piece::Owned(data) => return vec![&data[range.start as usize..range.end as usize]],
+            Piece::Paged {
+                source,
+                range: piece,
+                pages,
+            } => (source.bytes(), piece.start, pages),
+        };
+        let (mut position, end) = (offset + range.start, offset + range.end);
+        let mut slices = Vec::new();
+        while position < end {
+            let next = pages.range(position / PAGE_SIZE..).next();
+            let (from, to, bytes) = match next {
+                Some((&page, data)) if page * PAGE_SIZE <= position => {
+                    let start = page * PAGE_SIZE;
+                    (
+                        position - start,
+                        end.min(start + PAGE_SIZE) - start,
+                        &data[..],
+                    )
+                }
+                Some((&page, _)) => (position, end.min(page * PAGE_SIZE), source),
+                None => (position, end, source),
+            };
+            slices.push(&bytes[from as usize..to as usize]);
+            position += to - from;
+        }
+        slices
+    }
+
+    /// Overwrite bytes from `offset`; they must lie inside the piece
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        let (source, start, pages) = match self {
+            Piece::Owned(bytes) => {
+                bytes[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+                return;
+            }
+            Piece::Paged {
+                source,
+                range,
+                pages,
+            } => (source.bytes(), range.start, pages),
+        };
+        let mut position = start + offset;
+        let mut data = data;
+        while !data.is_empty() {
+            let page = position / PAGE_SIZE;
+            let page_start = page * PAGE_SIZE;
+            let bytes = pages.entry(page).or_insert_with(|| {
+                let page_end = (page_start + PAGE_SIZE).min(source.len() as u64);
+                source[page_start as usize..page_end as usize].into()
+            });
+            let from = (position - page_start) as usize;
+            let count = data.len().min(bytes.len() - from);
+            bytes[from..from + count].copy_from_slice(&data[..count]);
+            data = &data[count..];
+            position += count as u64;
+        }
+    }
+
+    /// Split off the bytes from `at` into a new piece
+    fn split_off(&mut self, at: u64) -> Piece {
+        match self {
+            Piece::Owned(data) => Piece::Owned(data.split_off(at as usize)),
+            Piece::Paged {
+                source,
+                range,
+                pages,
+            } => {
+                let middle = range.start + at;
+                let tail = middle..range.end;
+                range.end = middle;
+                // The page around the split point is kept by both pieces
+                let tail_pages = pages.split_off(&(middle / PAGE_SIZE));
+                if !middle.is_multiple_of(PAGE_SIZE) {
+                    if let Some(data) = tail_pages.get(&(middle / PAGE_SIZE)) {
+                        pages.insert(middle / PAGE_SIZE, data.clone());
+                    }
+                }
+                Piece::Paged {
+                    source: source.clone(),
+                    range: tail,
+                    pages: tail_pages,
+                }
+            }
+        }
+    }
+
+    /// Append `next` to the piece if it continues it
+    fn try_append(&mut self, next: Piece) -> Option<Piece> {
+        match (self, next) {
+            (Piece::Owned(data), Piece::Owned(next)) => {
+                data.extend(next);
+                None
+            }
+            (
+                Piece::Paged {
+                    source,
+                    range,
+                    pages,
+                },
+                Piece::Paged {
+                    source: next_source,
+                    range: next_range,
+                    pages: next_pages,
+                },
+            ) if Arc::ptr_eq(source, &next_source) && range.end == next_range.start => {
+                range.end = next_range.end;
+                // A shared boundary page holds the same bytes of both pieces only up
+                // to the boundary, so the two copies are combined
+                for (page, data) in next_pages {
+                    match pages.get_mut(&page) {
+                        Some(existing) => {
+                            let from = (next_range.start - page * PAGE_SIZE) as usize;
+                            existing[from..].copy_from_slice(&data[from..]);
+                        }
+                        None => {
+                            pages.insert(page, data);
+                        }
+                    }
+                }
+                None
+            }
+            (_, next) => Some(next),
+        }
+    }
+}
+
+impl Bytes {
+    /// Bytes of a whole source
+    pub(super) fn paged(source: Arc<dyn Source>) -> Self {
+        let len = source.bytes().len() as u64;
+        Bytes {
+            pieces: vec![Piece::Paged {
+                source,
+                range: 0..len,
+                pages: BTreeMap::new(),
+            }],
+        }
+    }
+
+    pub(super) fn len(&self) -> u64 {
+        self.pieces.iter().map(Piece::len).sum()
+    }
+
+    pub(super) fn get(&self, offset: u64) -> Option<u8> {
+        self.read(offset..offset + 1).map(|bytes| bytes[0])
+    }
+
+    /// Bytes of `range`, borrowed when they are stored in one place
+    pub(super) fn read(&self, range: Range<u64>) -> Option<Cow<'_, [u8]>> {
+        if range.end > self.len() || range.start > range.end {
+            return None;
+        }
+        let mut slices = self.slices(range);
+        Some(match slices.len() {
+            0 => Cow::Borrowed(&[]),
+            1 => Cow::Borrowed(slices.remove(0)),
+            _ => Cow::Owned(slices.concat()),
+        })
+    }
+
+    /// Slices that make up `range`, in order
+    pub(super) fn slices(&self, range: Range<u64>) -> Vec<&[u8]> {
+        let mut slices = Vec::new();
+        let mut start = 0;
+        for piece in &self.pieces {
+            let end = start + piece.len();
+            if start < range.end && end > range.start {
+                let from = range.start.max(start) - start;
+                let to = range.end.min(end) - start;
+                slices.extend(piece.slices(from..to));
+            }
+            start = end;
+        }
+        slices
+    }
+
+    /// Overwrite bytes from `offset`; they must lie inside the chunk
+    pub(super) fn write(&mut self, offset: u64, data: &[u8]) {
+        let end = offset + data.len() as u64;
+        let mut start = 0;
+        for piece in &mut self.pieces {
+            let piece_end = start + piece.len();
+            if start < end && piece_end > offset {
+                let from = offset.max(start);
+                let to = end.min(piece_end);
+                piece.write(
+                    from - start,
+                    &data[(from - offset) as usize..(to - offset) as usize],
+                );
+            }
+            start = piece_end;
+        }
+    }
+
+    /// Grow the chunk to `len` bytes with zeros
+    pub(super) fn resize(&mut self, len: u64) {
+        let missing = len.saturating_sub(self.len());
+        if missing > 0 {
+            self.append(vec![0; missing as usize].into());
+        }
+    }
+
+    /// Split off the bytes from `at` into a new chunk
+    pub(super) fn split_off(&mut self, at: u64) -> Bytes {
+        let mut start = 0;
+        for (index, piece) in self.pieces.iter_mut().enumerate() {
+            let end = start + piece.len();
+            if at < end {
+                let mut tail = Vec::new();
+                if at > start {
+                    tail.push(piece.split_off(at - start));
+                    tail.extend(self.pieces.drain(index + 1..));
+                } else {
+                    tail.extend(self.pieces.drain(index..));
+                }
+                return Bytes { pieces: tail };
+            }
+            start = end;
+        }
+        Bytes::default()
+    }
+
+    /// Append the bytes of `next`, joining pieces that continue each other
+    pub(super) fn append(&mut self, next: Bytes) {
+        for piece in next.pieces {
+            if piece.len() == 0 {
+                continue;
+            }
+            let rest = match self.pieces.last_mut() {
+                Some(last) => last.try_append(piece),
+                None => Some(piece),
+            };
+            self.pieces.extend(rest);
+        }
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(data: Vec<u8>) -> Self {
+        Bytes {
+            pieces: vec![Piece::Owned(data)],
+        }
+    }
+}
+
+impl PartialEq for Bytes {
+    fn eq(&self, other: &Self) -> bool {
+        let ours = self.slices(0..self.len());
+        let theirs = other.slices(0..other.len());
+        self.len() == other.len() && ours.into_iter().flatten().eq(theirs.into_iter().flatten())
+    }
+}
+
+impl Eq for Bytes {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(len: usize) -> Arc<dyn Source> {
+        Arc::new((0..len).map(|i| i as u8).collect::<Vec<u8>>())
+    }
+
+    fn content(bytes: &Bytes) -> Vec<u8> {
+        bytes.read(0..bytes.len()).unwrap().into_owned()
+    }
+
+    #[test]
+    fn test_paged_overlay() {
+        let source = source(3 * PAGE_SIZE as usize + 10);
+        let mut bytes = Bytes::paged(source.clone());
+        let mut expected = source.bytes().to_vec();
+
+        // untouched bytes are borrowed from the source
+        assert!(matches!(
+            bytes.read(5..PAGE_SIZE + 5),
+            Some(Cow::Borrowed(_))
+        ));
+
+        let at = PAGE_SIZE - 2;
+        bytes.write(at, &[0xAA; 4]);
+        expected[at as usize..at as usize + 4].fill(0xAA);
+        assert_eq!(content(&bytes), expected);
+        let Piece::Paged { pages, .. } = &bytes.pieces[0] else {
+            panic!("paged piece expected");
+        };
+        assert_eq!(pages.keys().copied().collect::<Vec<_>>(), vec![0, 1]);
+        assert!(matches!(
+            bytes.read(2 * PAGE_SIZE..2 * PAGE_SIZE + 8),
+            Some(Cow::Borrowed(_))
+        ));
+        assert_eq!(bytes.read(at..at + 4).unwrap(), &[0xAA; 4][..]);
+    }
+
+    #[test]
+    fn test_split_and_append() {
+        let source = source(2 * PAGE_SIZE as usize);
+        let mut bytes = Bytes::paged(source.clone());
+        bytes.write(10, &[1, 2, 3]);
+        let expected = content(&bytes);
+
+        // split inside a modified page, modify both halves, join them again
+        let mut tail = bytes.split_off(11);
+        assert_eq!(content(&bytes), expected[..11]);
+        assert_eq!(content(&tail), expected[11..]);
+        bytes.write(5, &[7]);
+        tail.write(0, &[8]);
+        bytes.append(tail);
+        assert_eq!(bytes.pieces.len(), 1);
+        let mut expected = expected;
+        expected[5] = 7;
+        expected[11] = 8;
+        assert_eq!(content(&bytes), expected);
+
+        // owned bytes between two parts of the source
+        let tail = bytes.split_off(PAGE_SIZE);
+        bytes.append(vec![0xEE; 3].into());
+        bytes.append(tail);
+        assert_eq!(bytes.pieces.len(), 3);
+        expected.splice(PAGE_SIZE as usize..PAGE_SIZE as usize, [0xEE; 3]);
+        assert_eq!(content(&bytes), expected);
+        assert_eq!(bytes, Bytes::from(expected));
+    }
+}
@@ -1,9 +1,36 @@
 //! Error types for hexyg-core
 
+use crate::lexer::Span;
+use std::fmt;
 
 /// Result type alias for hexyg operations
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Position of an error in the source text
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Location {
+    /// Name of the source file, if known
+    pub file: Option<String>,
+    /// Byte range, line and column in the source
+    pub span: Span,
+}
+
+impl Location {
+    /// Location in an unnamed source
+    pub fn new(span: Span) -> Self {
+        Location { file: None, span }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}:{}", file, self.span.line, self.span.column),
+            None => write!(f, "line {}, column {}", self.span.line, self.span.column),
+        }
+    }
+}
+
 /// Error types for hexyg operations
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -11,23 +38,121 @@ pub enum Error {
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
 
-    /// Invalid hex character
-    #[error("Invalid hex character '{0}' at position {1}")]
-    InvalidHexChar(char, usize),
+    /// Address gap while padding is forbidden
+    #[error("{location}: gap between {expected:08X} and {address:08X} is not allowed with padding=forbidden")]
+    AddressGap {
+        address: u64,
+        expected: u64,
+        location: Location,
+    },
+
+    /// Address points into data that has already been written
+    #[error("{location}: address {address:08X} overlaps data written up to {expected:08X}")]
+    AddressOverlap {
+        address: u64,
+        expected: u64,
+        location: Location,
+    },
 
-    /// Invalid hex sequence (odd length)
-    #[error("Invalid hex sequence: odd number of hex digits")]
-    OddHexLength,
+    /// Address is lower than the previously declared one
+    #[error("{location}: address {address:08X} goes backwards from {previous:08X}")]
+    AddressBackwards {
+        address: u64,
+        previous: u64,
+        location: Location,
+    },
 
     /// UTF-8 conversion error
     #[error("UTF-8 conversion error: {0}")]
     Utf8(#[from] std::string::FromUtf8Error),
 
-    /// Parse error
-    #[error("Parse error: {0}")]
-    Parse(String),
+    /// Syntax error or invalid directive in the hex text
+    #[error("{location}: {message}")]
+    Parse { message: String, location: Location },
+
+    /// Annotation value that does not match its type
+    #[error("{0}")]
+    Value(String),
 
     /// Configuration error
     #[error("Configuration error: {0}")]
     Config(String),
+
+    /// Address outside of the data, such as a `--from`/`--to` position
+    #[error("Address out of range: {0}")]
+    OutOfRange(String),
+}
+
+impl Error {
+    /// Parse error at the given span
+    pub fn parse(span: Span, message: impl Into<String>) -> Self {
+        Error::Parse {
+            message: message.into(),
+            location: Location::new(span),
+        }
+    }
+
+    /// Position of the error in the source text, if it has one
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Error::AddressGap { location, .. }
+            | Error::AddressOverlap { location, .. }
+            | Error::AddressBackwards { location, .. }
+            | Error::Parse { location, .. } => Some(location),
+            _ => None,
+        }
+    }
+
+    /// Attach a source file name to a located error
+    pub fn with_file(mut self, file: impl Into<String>) -> Self {
+        match &mut self {
+            Error::AddressGap { location, .. }
+            | Error::AddressOverlap { location, .. }
+            | Error::AddressBackwards { location, .. }
+            | Error::Parse { location, .. } => location.file = Some(file.into()),
+            _ => {}
+        }
+        self
+    }
+
+    /// Error message without the location prefix
+    pub fn message(&self) -> String {
+        let text = self.to_string();
+        match self.location() {
+            Some(location) => text
+                .strip_prefix(&format!("{}: ", location))
+                .map(str::to_string)
+                .unwrap_or(text),
+            None => text,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_located_error_display() {
+        let span = Span {
+            start: 40,
+            end: 42,
+            line: 10,
+            column: 14,
+        };
+        let err = Error::parse(span, "expected hex byte, found 'ZZ'");
+        assert_eq!(
+            err.to_string(),
+            "line 10, column 14: expected hex byte, found 'ZZ'"
+        );
+        assert_eq!(err.message(), "expected hex byte, found 'ZZ'");
+
+        let err = err.with_file("dump.hex");
+        assert_eq!(
+            err.to_string(),
+            "dump.hex:10:14: expected hex byte, found 'ZZ'"
+        );
+        assert_eq!(err.location().unwrap().span, span);
+        assert_eq!(err.message(), "expected hex byte, found 'ZZ'");
+    }
 }
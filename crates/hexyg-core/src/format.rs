@@ -0,0 +1,551 @@
+//! Formatting of hex text that keeps comments, annotations and directives
+//!
+//! [`format`] works on the [`syntax`] tree of a file. Directives, comment lines and
+//! empty lines are kept as they are. Data lines that follow each other form a run;
+//! the bytes of a run are laid out again by the effective `line_length` and
+//! `block_length`, previews are recomputed, and annotations and trailing comments move
+//! to the line that now holds the bytes they describe.
+
+use crate::config::RepeatAddress;
+use crate::convert::{address_width, AddressedWriter, LineLayout};
+use crate::lexer::TokenKind;
+use crate::parser::{decode_bytes, parse_address, Event, Parser};
+use crate::preview::preview_text;
+use crate::syntax::{self, NodeKind, SyntaxElement, SyntaxNode, SyntaxToken};
+use crate::types::{value_size, Value};
+use crate::{Config, Error, Result};
+use std::io;
+use std::ops::Range;
+
+/// Reformat hex text
+///
+/// `config` holds the options in effect before the first `#option` of the text. The
+/// text must be valid: the first syntax, address or type error is returned and nothing
+/// is formatted.
+///
+/// A run of data lines is split into lines of `line_length` bytes from its first
+/// address, or from every address where the data does not continue. Addresses follow
+/// `repeat_address` as in encoded text: the first line of the text and the first line
+/// after a gap show theirs unless it is `never`, other lines only with `every_line`.
+/// An address is always kept where the bytes would move without it. Data columns are
+/// padded to the width of a full line, so previews, annotations and comments line
+/// up. Previews are written only for runs that had them. An annotation keeps its
+/// form; a sequential one gets an explicit `+offset` when it no longer follows the
+/// previous annotation of its new line. When the comments of several lines end up
+/// on one line, all line comments but the last become block comments, so they stay
+/// apart.
+pub fn format(text: &str, config: &Config) -> Result<String> {
+    let semantics = analyze(text, config)?;
+    let mut formatter = Formatter {
+        out: String::new(),
+        config: config.clone(),
+        configs: semantics.configs.into_iter(),
+        annotations: semantics.annotations.into_iter(),
+        last_address: semantics.last_address,
+        position: 0,
+        end: None,
+        run: Vec::new(),
+        preview: false,
+    };
+
+    let root = syntax::parse(text);
+    let mut line: Vec<&SyntaxElement> = Vec::new();
+    for child in &root.children {
+        match child {
+            SyntaxElement::Token(token) if token.kind == TokenKind::Newline => {
+                formatter.line(&std::mem::take(&mut line))?;
+            }
+            _ => line.push(child),
+        }
+    }
+    if !line.is_empty() {
+        formatter.line(&line)?;
+    }
+    formatter.flush()?;
+    Ok(formatter.out)
+}
+
+/// What the parser knows about the text: effective options after every `#option`
+/// and the address and size of every annotation, in order
+struct Semantics {
+    configs: Vec<Config>,
+    annotations: Vec<(u64, u64)>,
+    /// Address of the last byte, which stretched addresses are as wide as
+    last_address: u64,
+}
+
+fn analyze(text: &str, config: &Config) -> Result<Semantics> {
+    let mut validator = AddressedWriter::new(io::sink(), config.padding);
+    let mut parser = Parser::with_config(text.as_bytes(), config.clone());
+    let mut semantics = Semantics {
+        configs: Vec::new(),
+        annotations: Vec::new(),
+        last_address: 0,
+    };
+    let (mut address, mut line_start, mut cursor) = (0, 0, 0);
+    while let Some(event) = parser.next() {
+        match event? {
+            Event::Option { .. } => {
+                validator.padding = parser.config().padding;
+                semantics.configs.push(parser.config().clone());
+            }
+            Event::Address {
+                address: next,
+                span,
+            } => {
+                validator.seek(next, span)?;
+                (address, line_start, cursor) = (next, next, next);
+            }
+            Event::Bytes { data, .. } => {
+                validator.write_all(&data)?;
+                (line_start, cursor) = (address, address);
+                address += data.len() as u64;
+            }
+            Event::Annotation(annotation) => {
+                let at = match annotation.offset {
+                    Some(offset) => line_start + offset,
+                    None => cursor,
+                };
+                let ty = parser.types().resolve(&annotation.type_name)?;
+                let size = value_size(&ty, &Value::from(&annotation.value));
+                cursor = at + size;
+                semantics.annotations.push((at, size));
+            }
+            _ => {}
+        }
+    }
+    semantics.last_address = validator.position().saturating_sub(1);
+    Ok(semantics)
+}
+
+/// Annotation anchored at the address of the value it describes
+pub(crate) struct Anchored {
+    pub(crate) address: u64,
+    pub(crate) size: u64,
+    /// Whether it was written with an explicit `+offset`
+    pub(crate) has_offset: bool,
+    /// Text after the offset, e.g. `(name):u16=3` or `u16=3`
+    pub(crate) body: String,
+}
+
+/// Bytes with continuous addresses and what is anchored to them
+struct Segment {
+    start: u64,
+    data: Vec<u8>,
+    annotations: Vec<Anchored>,
+    /// Trailing comments anchored at the start address of their source line
+    comments: Vec<(u64, String)>,
+}
+
+impl Segment {
+    fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+}
+
+struct Formatter {
+    out: String,
+    /// Effective options
+    config: Config,
+    configs: std::vec::IntoIter<Config>,
+    annotations: std::vec::IntoIter<(u64, u64)>,
+    last_address: u64,
+    /// Address of the next byte
+    position: u64,
+    /// Address after the last formatted data line, `None` before the first one
+    end: Option<u64>,
+    run: Vec<Segment>,
+    /// Whether a line of the run had a preview
+    preview: bool,
+}
+
+impl Formatter {
+    /// Format one line of the root node, without its newline
+    fn line(&mut self, elements: &[&SyntaxElement]) -> Result<()> {
+        let data_line = elements.iter().find_map(|element| match element {
+            SyntaxElement::Node(node) if node.kind == NodeKind::DataLine => Some(node),
+            _ => None,
+        });
+        if let Some(node) = data_line {
+            return self.data_line(node);
+        }
+
+        self.flush()?;
+        let mut text = String::new();
+        for element in elements {
+            match element {
+                SyntaxElement::Node(node) => {
+                    if node.kind == NodeKind::Option {
+                        self.config = self.configs.next().expect("option seen by the parser");
+                    }
+                    text.push_str(&node.text());
+                }
+                SyntaxElement::Token(token) => text.push_str(&token.text),
+            }
+        }
+        for line in text.split('\n') {
+            self.out.push_str(line.trim_end());
+            self.out.push('\n');
+        }
+        Ok(())
+    }
+
+    fn data_line(&mut self, node: &SyntaxNode) -> Result<()> {
+        if let Some(address) = node.nodes(NodeKind::Address).next() {
+            let token = address.tokens()[0];
+            self.position = parse_address(&token.text).expect("address checked by the parser");
+        }
+        let start = self.position;
+        if self.run.last().is_none_or(|segment| segment.end() != start) {
+            self.run.push(Segment {
+                start,
+                data: Vec::new(),
+                annotations: Vec::new(),
+                comments: Vec::new(),
+            });
+        }
+        let segment = self.run.last_mut().expect("segment pushed above");
+
+        let mut data = Vec::new();
+        for token in node.nodes(NodeKind::HexData).flat_map(SyntaxNode::tokens) {
+            if token.kind == TokenKind::HexSequence {
+                decode_bytes(&token.text, self.config.radix, &mut data)
+                    .expect("bytes checked by the parser");
+            }
+        }
+        self.config.word.swap(&mut data, self.config.endian);
+        segment.data.extend(data);
+        self.position = segment.end();
+
+        for element in &node.children {
+            match element {
+                SyntaxElement::Token(token) if token.kind == TokenKind::Preview => {
+                    self.preview = true;
+                }
+                SyntaxElement::Token(token) if token.kind.is_comment() => {
+                    segment.comments.push((start, token.text.clone()));
+                }
+                SyntaxElement::Node(annotations) if annotations.kind == NodeKind::Annotations => {
+                    for element in &annotations.children {
+                        match element {
+                            SyntaxElement::Node(annotation) => {
+                                let (address, size) = self
+                                    .annotations
+                                    .next()
+                                    .expect("annotation seen by the parser");
+                                if address < segment.start {
+                                    let span = annotation.tokens()[0].span;
+                                    return Err(Error::parse(
+                                        span,
+                                        "annotation refers to bytes before its data line",
+                                    ));
+                                }
+                                segment
+                                    .annotations
+                                    .push(anchored(annotation, address, size));
+                            }
+                            SyntaxElement::Token(token) if token.kind.is_comment() => {
+                                segment.comments.push((start, token.text.clone()));
+                            }
+                            SyntaxElement::Token(_) => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the lines of the current run
+    fn flush(&mut self) -> Result<()> {
+        let config = &self.config;
+        let line_length = config.line_length.max(1);
+        let layout = LineLayout::new(config, address_width(config, self.last_address));
+        for segment in std::mem::take(&mut self.run) {
+            // Without an address, a line continues from the previous data, or from 0
+            let expected = match (self.end, config.repeat_address) {
+                (Some(end), _) => Some(end),
+                (None, RepeatAddress::Never) => Some(0),
+                (None, _) => None,
+            };
+            let first_address = expected != Some(segment.start);
+            self.end = Some(segment.end());
+            let mut lines: Vec<Range<usize>> = (0..segment.data.len())
+                .step_by(line_length)
+                .map(|from| from..segment.data.len().min(from + line_length))
+                .collect();
+            if lines.is_empty() {
+                lines.push(0..0);
+            }
+            let count = lines.len();
+            for (i, range) in lines.into_iter().enumerate() {
+                let address = segment.start + range.start as u64;
+                // The last line also takes what is anchored past the end of the data
+                let end = if i + 1 == count {
+                    u64::MAX
+                } else {
+                    segment.start + range.end as u64
+                };
+                let show_address = match config.repeat_address {
+                    RepeatAddress::EveryLine => true,
+                    _ => i == 0 && first_address,
+                };
+                let data = &segment.data[range];
+
+                let mut extras = Vec::new();
+                if self.preview {
+                    extras.push(format!("[{}]", preview_text(data, config)));
+                }
+                let annotations = annotations_text(&segment.annotations, address..end);
+                if !annotations.is_empty() {
+                    extras.push(format!("| {}", annotations));
+                }
+                let mut comments: Vec<String> = segment
+                    .comments
+                    .iter()
+                    .filter(|(at, _)| (address..end).contains(at))
+                    .map(|(_, text)| text.clone())
+                    .collect();
+                separate_comments(&mut comments);
+                extras.extend(comments);
+                self.out
+                    .push_str(&layout.line(address, show_address, data, &extras)?);
+                self.out.push('\n');
+            }
+        }
+        self.preview = false;
+        Ok(())
+    }
+}
+
+fn anchored(node: &SyntaxNode, address: u64, size: u64) -> Anchored {
+    let tokens = node.tokens();
+    let has_offset = tokens[0].kind == TokenKind::Plus;
+    let mut rest: &[&SyntaxToken] = &tokens;
+    if has_offset {
+        // `+`, the offset and the whitespace around it
+        let offset = rest
+            .iter()
+            .position(|token| token.kind == TokenKind::HexSequence)
+            .expect("offset checked by the parser");
+        rest = &rest[offset + 1..];
+    }
+    let mut body = String::new();
+    let mut after_newline = false;
+    for token in rest {
+        match token.kind {
+            TokenKind::Newline => {
+                body.push(' ');
+                after_newline = true;
+            }
+            TokenKind::Whitespace if after_newline => {}
+            _ => {
+                body.push_str(&token.text);
+                after_newline = false;
+            }
+        }
+    }
+    Anchored {
+        address,
+        size,
+        has_offset,
+        body: body.trim().to_string(),
+    }
+}
+
+/// Annotations anchored inside `range`, written relative to its start
+///
+/// An annotation that follows the previous one is written sequentially, others get
+/// a `+offset`.
+pub(crate) fn annotations_text(annotations: &[Anchored], range: Range<u64>) -> String {
+    let mut cursor = range.start;
+    let mut texts = Vec::new();
+    for annotation in annotations
+        .iter()
+        .filter(|annotation| range.contains(&annotation.address))
+    {
+        let text = if !annotation.has_offset && annotation.address == cursor {
+            annotation.body.clone()
+        } else {
+            let offset = annotation.address - range.start;
+            if annotation.body.starts_with(['(', ':']) {
+                format!("+{:02X}{}", offset, annotation.body)
+            } else {
+                format!("+{:02X}:{}", offset, annotation.body)
+            }
+        };
+        texts.push(text);
+        cursor = annotation.address + annotation.size;
+    }
+    texts.join(" ")
+}
+
+/// Comments written at the end of one data line
+///
+/// Every comment is kept apart: all but the last become block comments, and block
+/// comments are joined into one line. Text without comment markers is a line
+/// comment.
+pub(crate) fn inline_comments<'a>(texts: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut comments: Vec<String> = Vec::new();
+    let mut open = false;
+    for line in texts.into_iter().flat_map(str::lines).map(str::trim) {
+        if line.is_empty() {
+            continue;
+        }
+        match comments.last_mut() {
+            Some(comment) if open => {
+                comment.push(' ');
+                comment.push_str(line);
+            }
+            _ if line.starts_with("//") || line.starts_with("/*") => {
+                comments.push(line.to_string())
+            }
+            _ => comments.push(format!("// {}", line)),
+        }
+        let comment = comments.last().expect("comment pushed above");
+        open = comment.starts_with("/*") && !comment[2..].contains("*/");
+    }
+    separate_comments(&mut comments);
+    comments
+}
+
+/// Turn all line comments but the last into block comments, so that comments
+/// written one after another on a line stay apart
+fn separate_comments(comments: &mut [String]) {
+    let count = comments.len();
+    for comment in comments.iter_mut().take(count.saturating_sub(1)) {
+        if let Some(text) = comment.strip_prefix("//") {
+            *comment = format!("/* {} */", text.trim().replace("*/", "* /"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HexDocument;
+
+    fn fmt(text: &str) -> String {
+        format(text, &Config::default()).unwrap()
+    }
+
+    /// Comments of `text` as the parser reads them
+    fn comments(text: &str) -> usize {
+        Parser::new(text.as_bytes())
+            .filter(|event| matches!(event, Ok(Event::Comment { .. })))
+            .count()
+    }
+
+    #[test]
+    fn test_reflow_keeps_comments_and_annotations() {
+        let input = "\
+#option line_length=4
+// header comment
+
+00000010: 41 42 // first
+          43 44 45 46 47 | +0:u8=67 u16=17733
+00000020:  // empty address line
+20 21 [old]
+#option line_length=8
+00000030: 01020304 05060708 | +6(x):u8=7 u8=8 /* block */
+";
+        let expected = "\
+#option line_length=4
+// header comment
+
+00000010: 41 42 43 44 [ABCD] | +02:u8=67 u16=17733 // first
+00000014: 45 46 47    [EFG]
+00000020: 20 21       [ !] // empty address line
+#option line_length=8
+00000030: 01 02 03 04 05 06 07 08 | +06(x):u8=7 u8=8 /* block */
+";
+        assert_eq!(fmt(input), expected);
+        assert_eq!(fmt(expected), expected);
+
+        // formatting does not change the data or the annotated values
+        let config = Config::default();
+        let before = HexDocument::from_reader(input.as_bytes(), &config).unwrap();
+        let after = HexDocument::from_reader(expected.as_bytes(), &config).unwrap();
+        let cells = |document: &HexDocument| {
+            document
+                .metadata_in(0..u64::MAX)
+                .filter(|(_, cell)| cell.type_hint.is_some())
+                .map(|(address, cell)| (address, cell.type_hint.clone(), cell.size))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            before.chunks().collect::<Vec<_>>(),
+            after.chunks().collect::<Vec<_>>()
+        );
+        assert_eq!(cells(&before), cells(&after));
+    }
+
+    #[test]
+    fn test_reference_corpus() {
+        let input = include_str!("../../../tests/corpus/valid/01_reference.hex");
+        let output = fmt(input);
+        assert_eq!(fmt(&output), output);
+        assert!(output.contains("#struct data {\n    name_x:u32,\n"));
+        assert!(output.contains(
+            "00000100: 00 01 02 03 04 05 06 07                         // this representation"
+        ));
+        assert!(output.contains(
+            "00000218: 01 00 00 00 02 00 03 00 4C 6F 72 65 6D 20 69 70 \
+             | +00:data={name_x=1, name_y=2, name_z=3} +08:utf8=\"Lorem ipsum dolo\" // json-like\n"
+        ));
+        assert!(output.contains("00000108: 08 09 0A 0B 0C 0D 0E 0F\n// same as"));
+        assert_eq!(
+            output.lines().filter(|line| line.starts_with("//")).count(),
+            input.lines().filter(|line| line.starts_with("//")).count()
+        );
+        // comments of merged lines stay apart
+        assert!(output.contains("/* by addresses */ // sequentially without addresses\n"));
+        assert_eq!(comments(&output), comments(input));
+
+        let mut before = Vec::new();
+        let mut after = Vec::new();
+        crate::convert::hex_to_bin(input.as_bytes(), &mut before, &Config::default()).unwrap();
+        crate::convert::hex_to_bin(output.as_bytes(), &mut after, &Config::default()).unwrap();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn test_encoded_text_is_formatted() {
+        let data: Vec<u8> = (0..40).collect();
+        for repeat in [
+            RepeatAddress::Never,
+            RepeatAddress::Once,
+            RepeatAddress::OnDiscontinuity,
+            RepeatAddress::EveryLine,
+        ] {
+            for start in [0, 0x10] {
+                let config = Config::default()
+                    .with_repeat_address(repeat)
+                    .with_start_address(start);
+                let mut text = Vec::new();
+                crate::convert::bin_to_hex(&data[..], &mut text, &config).unwrap();
+                let text = String::from_utf8(text).unwrap();
+                assert_eq!(fmt(&text), text, "repeat_address={repeat}");
+            }
+        }
+
+        // a block that continues after a comment line needs no address, a gap does
+        let input =
+            "#option repeat_address=once\n00000000: 00 01\n// c\n00000002: 02\n00000008: 08\n";
+        assert_eq!(
+            fmt(input),
+            "#option repeat_address=once\n00000000: 00 01\n// c\n          02\n00000008: 08\n"
+        );
+        let input = "#option repeat_address=never\n00000004: 04\n";
+        assert_eq!(fmt(input), input);
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        let err = format("00: 01\n00: 02\n", &Config::default()).unwrap_err();
+        assert!(err.to_string().contains("overlap"), "{err}");
+        let err = format("00: 0\n", &Config::default()).unwrap_err();
+        assert!(err.to_string().contains("odd number"), "{err}");
+    }
+}
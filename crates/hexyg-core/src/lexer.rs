@@ -0,0 +1,432 @@
+//! Lexer for the hexyg text format
+//!
+//! Produces spanned tokens following `grammar/hexyg.g4`. Unlike the ANTLR grammar,
+//! whitespace, newlines and comments are emitted as tokens instead of being skipped,
+//! so concatenating the text of all tokens gives back the original input.
+
+/// Location of a token or syntax element in the source text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    /// Byte offset of the first byte
+    pub start: usize,
+    /// Byte offset after the last byte
+    pub end: usize,
+    /// Line number of the first byte (1-based)
+    pub line: usize,
+    /// Column of the first byte in characters (1-based)
+    pub column: usize,
+}
+
+impl Span {
+    /// Length in bytes
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Whether the span covers no bytes
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Span from the start of `self` to the end of `other`
+    pub fn to(&self, other: Span) -> Span {
+        Span {
+            end: other.end.max(self.end),
+            ..*self
+        }
+    }
+}
+
+/// Token kinds of the hexyg grammar
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// Spaces and tabs
+    Whitespace,
+    /// `\n` or `\r\n`
+    Newline,
+    /// `// ...` up to the end of line
+    LineComment,
+    /// `/* ... */`, possibly spanning several lines
+    BlockComment,
+    /// `/*` without closing `*/` before the end of input
+    UnterminatedBlockComment,
+    /// `#option`
+    DirectiveOption,
+    /// `#struct`
+    DirectiveStruct,
+    /// Any other `#name`
+    Directive,
+    /// `[...]` on a single line
+    Preview,
+    Colon,
+    Equals,
+    Plus,
+    Pipe,
+    Comma,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    /// `"..."` with escape sequences
+    String,
+    /// `"...` without closing quote before the end of line
+    UnterminatedString,
+    /// `[0-9A-Fa-f]+`
+    HexSequence,
+    /// `[a-zA-Z_][a-zA-Z0-9_]*`
+    Identifier,
+    /// `[a-zA-Z][a-zA-Z0-9_+\-./]*`, e.g. a file name in an option value
+    UnquotedValue,
+    /// Character that does not start any token
+    Unknown,
+}
+
+impl TokenKind {
+    /// Whitespace and comments, which carry no syntax
+    pub fn is_trivia(self) -> bool {
+        matches!(
+            self,
+            TokenKind::Whitespace
+                | TokenKind::LineComment
+                | TokenKind::BlockComment
+                | TokenKind::UnterminatedBlockComment
+        )
+    }
+
+    /// Line and block comments
+    pub fn is_comment(self) -> bool {
+        matches!(
+            self,
+            TokenKind::LineComment | TokenKind::BlockComment | TokenKind::UnterminatedBlockComment
+        )
+    }
+}
+
+/// Token with its location
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+/// Iterator over the tokens of a source text
+///
+/// The lexer never fails: characters that do not form a valid token are reported
+/// as [`TokenKind::Unknown`] and left for the parser to diagnose.
+pub struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Lexer<'a> {
+    /// Create a lexer for a complete source text
+    pub fn new(src: &'a str) -> Self {
+        Self::with_position(src, 0, 1)
+    }
+
+    /// Create a lexer for a fragment that starts at a line boundary of a larger input
+    ///
+    /// `offset` is the byte offset and `line` the line number of the fragment start;
+    /// spans of the produced tokens are relative to the whole input.
+    pub fn with_position(src: &'a str, offset: usize, line: usize) -> Self {
+        Lexer {
+            src,
+            pos: 0,
+            offset,
+            line,
+            column: 1,
+        }
+    }
+
+    /// Source text of a token produced by this lexer
+    pub fn text(&self, token: &Token) -> &'a str {
+        &self.src[token.span.start - self.offset..token.span.end - self.offset]
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn scan_token(&self) -> (TokenKind, usize) {
+        let rest = self.rest();
+        let bytes = rest.as_bytes();
+        let first = bytes[0];
+        let single = |kind| (kind, 1);
+
+        match first {
+            b' ' | b'\t' => (
+                TokenKind::Whitespace,
+                count_while(bytes, |b| b == b' ' || b == b'\t'),
+            ),
+            b'\n' => (TokenKind::Newline, 1),
+            b'\r' if bytes.get(1) == Some(&b'\n') => (TokenKind::Newline, 2),
+            b'\r' => (TokenKind::Whitespace, 1),
+            b'/' if bytes.get(1) == Some(&b'/') => {
+                let mut len = rest.find('\n').unwrap_or(rest.len());
+                if len > 0 && bytes[len - 1] == b'\r' {
+                    len -= 1;
+                }
+                (TokenKind::LineComment, len)
+            }
+            b'/' if bytes.get(1) == Some(&b'*') => match rest[2..].find("*/") {
+                Some(end) => (TokenKind::BlockComment, end + 4),
+                None => (TokenKind::UnterminatedBlockComment, rest.len()),
+            },
+            b'#' => {
+                let len = 1 + count_while(&bytes[1..], is_ident_continue);
+                let kind = match &rest[..len] {
+                    "#option" => TokenKind::DirectiveOption,
+                    "#struct" => TokenKind::DirectiveStruct,
+                    _ => TokenKind::Directive,
+                };
+                (kind, len)
+            }
+            b'[' => match bytes[1..]
+                .iter()
+                .position(|&b| b == b']' || b == b'\n' || b == b'\r')
+            {
+                Some(end) if bytes[1 + end] == b']' => (TokenKind::Preview, end + 2),
+                _ => single(TokenKind::LBracket),
+            },
+            b']' => single(TokenKind::RBracket),
+            b':' => single(TokenKind::Colon),
+            b'=' => single(TokenKind::Equals),
+            b'+' => single(TokenKind::Plus),
+            b'|' => single(TokenKind::Pipe),
+            b',' => single(TokenKind::Comma),
+            b'{' => single(TokenKind::LBrace),
+            b'}' => single(TokenKind::RBrace),
+            b'(' => single(TokenKind::LParen),
+            b')' => single(TokenKind::RParen),
+            b'"' => scan_string(bytes),
+            b if b.is_ascii_alphanumeric() || b == b'_' => scan_word(bytes),
+            _ => {
+                let len = rest.chars().next().map_or(1, char::len_utf8);
+                (TokenKind::Unknown, len)
+            }
+        }
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        if self.pos >= self.src.len() {
+            return None;
+        }
+
+        let (kind, len) = self.scan_token();
+        let text = &self.src[self.pos..self.pos + len];
+        let token = Token {
+            kind,
+            span: Span {
+                start: self.offset + self.pos,
+                end: self.offset + self.pos + len,
+                line: self.line,
+                column: self.column,
+            },
+        };
+
+        self.pos += len;
+        match text.rfind('\n') {
+            Some(last) => {
+                self.line += text.matches('\n').count();
+                self.column = 1 + text[last + 1..].chars().count();
+            }
+            None => self.column += text.chars().count(),
+        }
+
+        Some(token)
+    }
+}
+
+/// Whether `text` is a valid identifier (`[a-zA-Z_][a-zA-Z0-9_]*`)
+///
+/// Identifiers made only of hex letters (such as `face`) are lexed as
+/// [`TokenKind::HexSequence`], so the parser accepts those where a name is expected.
+pub fn is_identifier(text: &str) -> bool {
+    let mut bytes = text.bytes();
+    matches!(bytes.next(), Some(b) if b.is_ascii_alphabetic() || b == b'_')
+        && bytes.all(is_ident_continue)
+}
+
+fn is_ident_continue(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+fn count_while(bytes: &[u8], pred: impl Fn(u8) -> bool) -> usize {
+    bytes.iter().take_while(|&&b| pred(b)).count()
+}
+
+fn scan_string(bytes: &[u8]) -> (TokenKind, usize) {
+    let mut i = 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => return (TokenKind::String, i + 1),
+            b'\\' if i + 1 < bytes.len() && bytes[i + 1] != b'\n' => i += 2,
+            b'\n' | b'\r' => break,
+            _ => i += 1,
+        }
+    }
+    (TokenKind::UnterminatedString, i.min(bytes.len()))
+}
+
+/// Longest match of HEX_SEQUENCE, IDENTIFIER and UNQUOTED_VALUE, in that priority on ties
+fn scan_word(bytes: &[u8]) -> (TokenKind, usize) {
+    let hex = count_while(bytes, |b| b.is_ascii_hexdigit());
+    let ident = if bytes[0].is_ascii_digit() {
+        0
+    } else {
+        count_while(bytes, is_ident_continue)
+    };
+    let unquoted = if bytes[0].is_ascii_alphabetic() {
+        let mut len = 0;
+        while len < bytes.len() {
+            let b = bytes[len];
+            // A comment start ends the value even without whitespace before it
+            let comment = b == b'/' && matches!(bytes.get(len + 1), Some(b'/') | Some(b'*'));
+            if comment || !(is_ident_continue(b) || matches!(b, b'+' | b'-' | b'.' | b'/')) {
+                break;
+            }
+            len += 1;
+        }
+        len
+    } else {
+        0
+    };
+
+    if hex >= ident && hex >= unquoted {
+        (TokenKind::HexSequence, hex)
+    } else if ident >= unquoted {
+        (TokenKind::Identifier, ident)
+    } else {
+        (TokenKind::UnquotedValue, unquoted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(src: &str) -> Vec<(TokenKind, &str)> {
+        let lexer = Lexer::new(src);
+        let tokens: Vec<Token> = Lexer::new(src).collect();
+        tokens
+            .iter()
+            .filter(|t| t.kind != TokenKind::Whitespace)
+            .map(|t| (t.kind, lexer.text(t)))
+            .collect()
+    }
+
+    #[test]
+    fn test_lossless() {
+        let src = include_str!("../../../tests/corpus/valid/01_reference.hex");
+        let lexer = Lexer::new(src);
+        let text: String = Lexer::new(src).map(|t| lexer.text(&t)).collect();
+        assert_eq!(text, src);
+        assert!(Lexer::new(src).all(|t| t.kind != TokenKind::Unknown));
+    }
+
+    #[test]
+    fn test_data_line() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds("00000000: 4C 6F [a|b // c:d] | +0:u16=28492 // tail\n"),
+            vec![
+                (HexSequence, "00000000"),
+                (Colon, ":"),
+                (HexSequence, "4C"),
+                (HexSequence, "6F"),
+                (Preview, "[a|b // c:d]"),
+                (Pipe, "|"),
+                (Plus, "+"),
+                (HexSequence, "0"),
+                (Colon, ":"),
+                (Identifier, "u16"),
+                (Equals, "="),
+                (HexSequence, "28492"),
+                (LineComment, "// tail"),
+                (Newline, "\n"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_words() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds("FF data lorem.bin ZZ 0C0D face"),
+            vec![
+                (HexSequence, "FF"),
+                (Identifier, "data"),
+                (UnquotedValue, "lorem.bin"),
+                (Identifier, "ZZ"),
+                (HexSequence, "0C0D"),
+                (HexSequence, "face"),
+            ]
+        );
+        assert!(is_identifier("face"));
+        assert!(!is_identifier("0C"));
+    }
+
+    #[test]
+    fn test_comments_and_strings() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds("01 /* a\nb */ 02 \"x\\\"y\" \"open\r\n/* never"),
+            vec![
+                (HexSequence, "01"),
+                (BlockComment, "/* a\nb */"),
+                (HexSequence, "02"),
+                (String, "\"x\\\"y\""),
+                (UnterminatedString, "\"open"),
+                (Newline, "\r\n"),
+                (UnterminatedBlockComment, "/* never"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_directives_and_preview_fallback() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds("#option #struct #include [open\n"),
+            vec![
+                (DirectiveOption, "#option"),
+                (DirectiveStruct, "#struct"),
+                (Directive, "#include"),
+                (LBracket, "["),
+                (Identifier, "open"),
+                (Newline, "\n"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_positions() {
+        let tokens: Vec<Token> = Lexer::with_position("ab /* x\ny */ ёz", 100, 7)
+            .filter(|t| t.kind != TokenKind::Whitespace)
+            .collect();
+        assert_eq!(
+            tokens[0].span,
+            Span {
+                start: 100,
+                end: 102,
+                line: 7,
+                column: 1
+            }
+        );
+        assert_eq!(tokens[1].span.line, 7);
+        assert_eq!(tokens[1].span.column, 4);
+        assert_eq!(tokens[2].kind, TokenKind::Unknown);
+        assert_eq!(tokens[2].span.line, 8);
+        assert_eq!(tokens[2].span.column, 6);
+        assert_eq!(tokens[3].span.column, 7);
+    }
+}
@@ -2,9 +2,31 @@
 //!
 //! Core functionality for bidirectional conversion between binary data and hex text format.
 
+pub mod check;
 pub mod config;
 pub mod convert;
+pub mod diagnostic;
+pub mod document;
 pub mod error;
+pub mod format;
+pub mod lexer;
+pub mod options;
+pub mod parser;
+pub mod preview;
+pub mod settings;
+pub mod slice;
+pub mod syntax;
+pub mod template;
+pub mod types;
 
+pub use check::{check, check_with, CheckOptions};
 pub use config::Config;
+pub use diagnostic::{Diagnostic, Report, Severity};
+pub use document::HexDocument;
 pub use error::{Error, Result};
+pub use format::format;
+pub use options::OptionSpec;
+pub use settings::Settings;
+pub use slice::{Position, Slice};
+pub use template::Template;
+pub use types::{Type, TypeRegistry, Value};
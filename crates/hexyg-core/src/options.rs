@@ -0,0 +1,473 @@
+//! Registry of the options of hex files
+//!
+//! Every option that `#option` lines, configuration files, `HEXYG_*` variables and
+//! command line flags can set is declared once in [`OPTIONS`]: its name, value
+//! syntax, default and the conversions it applies to. [`Config::apply_option`]
+//! validates values with it, the CLI generates its flags from it, and editors take
+//! completions and suggestions for misspelled names from it.
+//!
+//! [`Config::apply_option`]: crate::Config::apply_option
+
+use crate::config::Padding;
+use crate::{Error, Result};
+use std::fmt;
+
+/// Syntax of an option value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueKind {
+    /// Any text
+    Text,
+    /// Decimal number of at least `min`
+    Number { min: usize },
+    /// `true` or `false`; `yes`, `no`, `1` and `0` are accepted too
+    Bool,
+    /// One of the listed names, in any case
+    Choice(&'static [&'static str]),
+    /// Empty or spaces and tabs only
+    Whitespace,
+    /// Hex byte `00`..`FF`, or `forbidden`
+    Byte,
+    /// One printable character other than a square bracket
+    Char,
+}
+
+impl fmt::Display for ValueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueKind::Text => write!(f, "text"),
+            ValueKind::Number { min: 0 } => write!(f, "number"),
+            ValueKind::Number { min } => write!(f, "number >= {}", min),
+            ValueKind::Bool => write!(f, "true, false"),
+            ValueKind::Choice(values) => write!(f, "{}", values.join(", ")),
+            ValueKind::Whitespace => write!(f, "spaces and tabs"),
+            ValueKind::Byte => write!(f, "00..FF, forbidden"),
+            ValueKind::Char => write!(f, "character"),
+        }
+    }
+}
+
+/// Conversions an option takes effect in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Layout of the hex text written by `encode` and `fmt`
+    Encode,
+    /// Reading hex text
+    Decode,
+    /// Both writing and reading hex text
+    Both,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Encode => write!(f, "encode"),
+            Direction::Decode => write!(f, "decode"),
+            Direction::Both => write!(f, "encode, decode"),
+        }
+    }
+}
+
+/// Declaration of an option
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptionSpec {
+    /// Name in `#option` lines and configuration files
+    pub name: &'static str,
+    /// Other accepted names, such as old spellings
+    pub aliases: &'static [&'static str],
+    pub kind: ValueKind,
+    /// Canonical default value, empty for an unset option
+    pub default: &'static str,
+    pub applies_to: Direction,
+    /// Long command line flag of `encode`, `None` if the option has none
+    pub flag: Option<&'static str>,
+    /// Whether the option is left out of generated headers while it has its default
+    pub omit_default: bool,
+    /// One-line description for help texts and editors
+    pub help: &'static str,
+}
+
+/// Options in the order `bin_to_hex` writes them to the header
+pub const OPTIONS: &[OptionSpec] = &[
+    OptionSpec {
+        name: "bin_filename",
+        aliases: &[],
+        kind: ValueKind::Text,
+        default: "",
+        applies_to: Direction::Encode,
+        flag: None,
+        omit_default: false,
+        help: "Name of the binary file the hex text was generated from",
+    },
+    OptionSpec {
+        name: "encoding",
+        aliases: &[],
+        kind: ValueKind::Choice(&["utf8"]),
+        default: "utf8",
+        applies_to: Direction::Both,
+        flag: None,
+        omit_default: false,
+        help: "Text encoding of the hex file",
+    },
+    OptionSpec {
+        name: "endian",
+        aliases: &[],
+        kind: ValueKind::Choice(&["LITTLE_ENDIAN", "BIG_ENDIAN"]),
+        default: "LITTLE_ENDIAN",
+        applies_to: Direction::Both,
+        flag: None,
+        omit_default: false,
+        help: "Byte order of annotation values",
+    },
+    OptionSpec {
+        name: "address_size",
+        aliases: &[],
+        kind: ValueKind::Choice(&[
+            "u8", "u16", "u24", "u32", "u40", "u48", "u64", "u128", "stretch",
+        ]),
+        default: "u32",
+        applies_to: Direction::Both,
+        flag: Some("address-size"),
+        omit_default: false,
+        help: "Address size",
+    },
+    OptionSpec {
+        name: "address_size_step",
+        aliases: &[],
+        kind: ValueKind::Number { min: 1 },
+        default: "1",
+        applies_to: Direction::Encode,
+        flag: Some("address-size-step"),
+        omit_default: false,
+        help: "Digits the width of a stretched address is a multiple of",
+    },
+    OptionSpec {
+        name: "padding",
+        aliases: &[],
+        kind: ValueKind::Byte,
+        default: "00",
+        applies_to: Direction::Both,
+        flag: None,
+        omit_default: false,
+        help: "Byte that fills gaps between addresses, or forbidden to disallow gaps",
+    },
+    OptionSpec {
+        name: "line_length",
+        aliases: &[],
+        kind: ValueKind::Number { min: 1 },
+        default: "16",
+        applies_to: Direction::Encode,
+        flag: Some("line-length"),
+        omit_default: false,
+        help: "Line length in bytes",
+    },
+    OptionSpec {
+        name: "radix",
+        aliases: &[],
+        kind: ValueKind::Choice(&["2", "8", "10", "16"]),
+        default: "16",
+        applies_to: Direction::Both,
+        flag: Some("radix"),
+        omit_default: true,
+        help: "Radix of the data bytes",
+    },
+    OptionSpec {
+        name: "word",
+        aliases: &[],
+        kind: ValueKind::Choice(&["u8", "u16", "u32", "u64"]),
+        default: "u8",
+        applies_to: Direction::Both,
+        flag: Some("word"),
+        omit_default: true,
+        help: "Words the bytes of a line are grouped into, written in the endianness",
+    },
+    OptionSpec {
+        name: "block_length",
+        aliases: &[],
+        kind: ValueKind::Number { min: 0 },
+        default: "1",
+        applies_to: Direction::Encode,
+        flag: Some("block-length"),
+        omit_default: false,
+        help: "Split line into blocks of N bytes, 0 for one block",
+    },
+    OptionSpec {
+        name: "byte_separator",
+        aliases: &[],
+        kind: ValueKind::Whitespace,
+        default: "",
+        applies_to: Direction::Encode,
+        flag: Some("byte-separator"),
+        omit_default: false,
+        help: "Separator of bytes in a block",
+    },
+    OptionSpec {
+        name: "block_separator",
+        aliases: &[],
+        kind: ValueKind::Whitespace,
+        default: " ",
+        applies_to: Direction::Encode,
+        flag: Some("block-separator"),
+        omit_default: false,
+        help: "Separator of blocks in a line",
+    },
+    OptionSpec {
+        name: "repeat_address",
+        aliases: &["repeat_adress"],
+        kind: ValueKind::Choice(&["never", "once", "on_discontinuity", "every_line"]),
+        default: "every_line",
+        applies_to: Direction::Encode,
+        flag: Some("repeat-address"),
+        omit_default: false,
+        help: "Data lines that start with their address",
+    },
+    OptionSpec {
+        name: "preview",
+        aliases: &[],
+        kind: ValueKind::Bool,
+        default: "true",
+        applies_to: Direction::Encode,
+        flag: Some("preview"),
+        omit_default: false,
+        help: "Show ASCII preview in square brackets",
+    },
+    OptionSpec {
+        name: "preview_encoding",
+        aliases: &[],
+        kind: ValueKind::Choice(&[
+            "ascii",
+            "latin1",
+            "cp437",
+            "cp1251",
+            "koi8-r",
+            "ebcdic-037",
+            "utf8",
+            "utf16le",
+            "utf16be",
+        ]),
+        default: "ascii",
+        applies_to: Direction::Encode,
+        flag: Some("preview-encoding"),
+        omit_default: true,
+        help: "Character encoding of the preview",
+    },
+    OptionSpec {
+        name: "preview_placeholder",
+        aliases: &[],
+        kind: ValueKind::Char,
+        default: ".",
+        applies_to: Direction::Encode,
+        flag: Some("preview-placeholder"),
+        omit_default: true,
+        help: "Preview character of bytes that are not printable characters",
+    },
+    OptionSpec {
+        name: "preview_control_pictures",
+        aliases: &[],
+        kind: ValueKind::Bool,
+        default: "false",
+        applies_to: Direction::Encode,
+        flag: Some("preview-control-pictures"),
+        omit_default: true,
+        help: "Show control characters as Unicode control pictures in the preview",
+    },
+];
+
+/// Declaration of the option with name or alias `name`
+pub fn find(name: &str) -> Option<&'static OptionSpec> {
+    OPTIONS
+        .iter()
+        .find(|spec| spec.name == name || spec.aliases.contains(&name))
+}
+
+/// Name of the option that an unknown `name` is most likely a misspelling of
+pub fn suggest(name: &str) -> Option<&'static str> {
+    let limit = (name.len() / 3).max(1);
+    OPTIONS
+        .iter()
+        .flat_map(|spec| {
+            std::iter::once(spec.name)
+                .chain(spec.aliases.iter().copied())
+                .map(move |candidate| (edit_distance(name, candidate), spec.name))
+        })
+        .filter(|&(distance, _)| distance <= limit)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, name)| name)
+}
+
+/// Error for an unknown option `name`, with a suggestion if there is one
+pub fn unknown(name: &str) -> Error {
+    Error::Config(unknown_message(name))
+}
+
+/// Message about an unknown option `name`, with a suggestion if there is one
+pub(crate) fn unknown_message(name: &str) -> String {
+    match suggest(name) {
+        Some(suggestion) => format!("unknown option '{}', did you mean '{}'?", name, suggestion),
+        None => format!("unknown option '{}'", name),
+    }
+}
+
+impl OptionSpec {
+    /// Values an editor or a shell can complete
+    pub fn values(&self) -> &'static [&'static str] {
+        match self.kind {
+            ValueKind::Choice(values) => values,
+            ValueKind::Bool => &["true", "false"],
+            _ => &[],
+        }
+    }
+
+    /// Check that `value` has the syntax of the option
+    pub fn validate(&self, value: &str) -> Result<()> {
+        match self.kind {
+            ValueKind::Text => Ok(()),
+            ValueKind::Number { .. } => self.number(value).map(drop),
+            ValueKind::Bool => self.bool(value).map(drop),
+            ValueKind::Choice(values) => {
+                if values.iter().any(|v| v.eq_ignore_ascii_case(value.trim())) {
+                    Ok(())
+                } else {
+                    Err(self.invalid(value, &format!("expected {}", self.kind)))
+                }
+            }
+            ValueKind::Whitespace => self.whitespace(value).map(drop),
+            ValueKind::Byte => value.parse::<Padding>().map(drop),
+            ValueKind::Char => self.char(value).map(drop),
+        }
+    }
+
+    /// Value of a number option
+    pub(crate) fn number(&self, value: &str) -> Result<usize> {
+        let number: usize = value
+            .parse()
+            .map_err(|_| self.invalid(value, "expected a number"))?;
+        match self.kind {
+            ValueKind::Number { min } if number < min => Err(Error::Config(if min == 1 {
+                format!("{} must be positive", self.name)
+            } else {
+                format!("{} must be at least {}", self.name, min)
+            })),
+            _ => Ok(number),
+        }
+    }
+
+    /// Value of a boolean option
+    pub(crate) fn bool(&self, value: &str) -> Result<bool> {
+        match value.to_ascii_lowercase().as_str() {
+            "true" | "yes" | "1" => Ok(true),
+            "false" | "no" | "0" => Ok(false),
+            _ => Err(self.invalid(value, "expected true or false")),
+        }
+    }
+
+    /// Value of a whitespace option
+    pub(crate) fn whitespace(&self, value: &str) -> Result<String> {
+        if value.chars().all(|c| c == ' ' || c == '\t') {
+            Ok(value.to_string())
+        } else {
+            Err(Error::Config(format!(
+                "invalid {} {:?}: expected spaces and tabs only",
+                self.name, value
+            )))
+        }
+    }
+
+    /// Value of a character option
+    pub(crate) fn char(&self, value: &str) -> Result<char> {
+        let mut chars = value.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if !c.is_control() && !c.is_whitespace() && c != '[' && c != ']' => {
+                Ok(c)
+            }
+            _ => Err(self.invalid(
+                value,
+                "expected one printable character other than '[' and ']'",
+            )),
+        }
+    }
+
+    fn invalid(&self, value: &str, expected: &str) -> Error {
+        Error::Config(format!("invalid {} '{}': {}", self.name, value, expected))
+    }
+}
+
+/// Levenshtein distance of two names
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    #[test]
+    fn test_registry() {
+        let config = Config::default();
+        for spec in OPTIONS {
+            let default = config.option_value(spec.name).unwrap_or_default();
+            assert_eq!(spec.default, default, "default of {}", spec.name);
+            spec.validate(spec.default).unwrap();
+            // every declared option is implemented by Config
+            let mut applied = Config::default();
+            applied.apply_option(spec.name, spec.default).unwrap();
+            let value = applied.option_value(spec.name).unwrap_or_default();
+            assert_eq!(value, default, "{} applied", spec.name);
+        }
+        assert_eq!(find("repeat_adress").unwrap().name, "repeat_address");
+        assert!(find("author").is_none());
+
+        assert_eq!(suggest("line_lenght"), Some("line_length"));
+        assert_eq!(suggest("endianess"), Some("endian"));
+        assert_eq!(suggest("author"), None);
+        assert_eq!(
+            unknown("blok_length").to_string(),
+            "Configuration error: unknown option 'blok_length', did you mean 'block_length'?"
+        );
+
+        let spec = find("repeat_address").unwrap();
+        assert_eq!(spec.values().len(), 4);
+        assert!(spec.validate("EVERY_LINE").is_ok());
+        assert!(spec.validate("sometimes").is_err());
+    }
+
+    #[test]
+    fn test_docs_table() {
+        // The table of file options in the docs lists the registry
+        let rows: Vec<String> = OPTIONS
+            .iter()
+            .map(|spec| {
+                let default = match spec.default.trim() {
+                    "" => format!("{:?}", spec.default),
+                    default => default.to_string(),
+                };
+                format!(
+                    "| `{}` | {} | `{}` | {} |",
+                    spec.name, spec.kind, default, spec.applies_to
+                )
+            })
+            .collect();
+        for (path, text) in [
+            (
+                "specs/02_options.md",
+                include_str!("../../../specs/02_options.md"),
+            ),
+            ("docs/USAGE.md", include_str!("../../../docs/USAGE.md")),
+        ] {
+            for row in &rows {
+                assert!(text.contains(row.as_str()), "{} lacks {}", path, row);
+            }
+        }
+    }
+}
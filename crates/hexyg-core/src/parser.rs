@@ -0,0 +1,1069 @@
+//! Pull-based event parser for the hexyg text format
+//!
+//! The parser reads its input line by line and produces a stream of [`Event`]s.
+//! Only the current line (or a multi-line block comment) is kept in memory, so it
+//! can be used both for streaming conversion and for building an in-memory model.
+
+use crate::config::Radix;
+use crate::lexer::{is_identifier, Lexer, Span, Token, TokenKind};
+use crate::options;
+use crate::types::TypeRegistry;
+use crate::{Config, Error, Result};
+use std::collections::VecDeque;
+use std::io::BufRead;
+
+/// Field of a `#struct` definition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructField {
+    pub name: String,
+    pub type_name: String,
+    pub span: Span,
+}
+
+/// Value literal of an annotation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Literal {
+    /// Decimal integer, e.g. `255`
+    Integer(u64),
+    /// Quoted string with escapes resolved
+    String(String),
+    /// Struct value, e.g. `{name_x=1, name_y=2}`
+    Struct(Vec<(String, Literal)>),
+}
+
+/// Annotation after `|` on a data line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Annotation {
+    /// Offset from the line address (`+03`), `None` for sequential annotations
+    pub offset: Option<u64>,
+    /// Optional name in parentheses (`(name_x)`)
+    pub name: Option<String>,
+    /// Type specifier, e.g. `u32`, `utf8` or a struct name
+    pub type_name: String,
+    pub value: Literal,
+    pub span: Span,
+}
+
+/// Syntax event produced by the parser
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// `#option name=value`
+    Option {
+        name: String,
+        value: String,
+        span: Span,
+    },
+    /// `#struct name { field:type, ... }`
+    Struct {
+        name: String,
+        fields: Vec<StructField>,
+        span: Span,
+    },
+    /// Address label at the start of a data line, e.g. `00000100:`
+    Address { address: u64, span: Span },
+    /// Hex bytes of one data line
+    Bytes { data: Vec<u8>, span: Span },
+    /// Preview text without the square brackets
+    Preview { text: String, span: Span },
+    /// Single annotation from the `| ...` part of a data line
+    Annotation(Annotation),
+    /// Line or block comment, including the comment markers
+    Comment { text: String, span: Span },
+}
+
+/// Pull-based parser producing [`Event`]s from hex text
+///
+/// `#option` directives are applied to the parser's [`Config`] as they appear, so
+/// after an event is returned, [`Parser::config`] holds the effective options at
+/// that point of the input. Likewise, `#struct` definitions are registered in
+/// [`Parser::types`], so a struct must be defined before it is used.
+///
+/// After a syntax error the parser skips the rest of the line and continues, so a
+/// consumer may either stop at the first `Err` or collect all of them.
+pub struct Parser<R> {
+    reader: R,
+    config: Config,
+    types: TypeRegistry,
+    /// Current chunk of input: one line, or several lines for a block comment
+    chunk: String,
+    /// Byte offset of the chunk in the input
+    chunk_offset: usize,
+    /// Line number of the chunk start
+    chunk_line: usize,
+    tokens: VecDeque<Token>,
+    events: VecDeque<Result<Event>>,
+    /// Inside a `#struct` body, which may span several lines
+    in_struct: bool,
+    /// Radix of the data lines read so far, `None` before the first one
+    data_radix: Option<Radix>,
+    /// Whether an `#option address_size` was read, so addresses must fit into it
+    address_size_declared: bool,
+    finished: bool,
+}
+
+impl<R: BufRead> Parser<R> {
+    /// Create a parser reading hex text from `reader` with default options
+    pub fn new(reader: R) -> Self {
+        Self::with_config(reader, Config::default())
+    }
+
+    /// Create a parser starting from the given options
+    pub fn with_config(reader: R, config: Config) -> Self {
+        Parser {
+            reader,
+            types: TypeRegistry::new(&config),
+            config,
+            chunk: String::new(),
+            chunk_offset: 0,
+            chunk_line: 1,
+            tokens: VecDeque::new(),
+            events: VecDeque::new(),
+            in_struct: false,
+            data_radix: None,
+            address_size_declared: false,
+            finished: false,
+        }
+    }
+
+    /// Effective options after the directives parsed so far
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Types defined so far, with the byte order of the effective options
+    pub fn types(&self) -> &TypeRegistry {
+        &self.types
+    }
+
+    /// Read the next chunk of input and lex it; returns `false` at end of input
+    fn fill(&mut self) -> Result<bool> {
+        self.chunk_offset += self.chunk.len();
+        self.chunk_line += self.chunk.matches('\n').count();
+        self.chunk.clear();
+
+        let mut in_comment = false;
+        let mut raw = Vec::new();
+        loop {
+            let start = self.chunk.len();
+            raw.clear();
+            if self.reader.read_until(b'\n', &mut raw)? == 0 {
+                break;
+            }
+            match std::str::from_utf8(&raw) {
+                Ok(line) => self.chunk.push_str(line),
+                Err(_) => {
+                    // Blank out the line but keep its length, so that offsets stay exact
+                    let span = Span {
+                        start: self.chunk_offset + start,
+                        end: self.chunk_offset + start + raw.len(),
+                        line: self.chunk_line + self.chunk.matches('\n').count(),
+                        column: 1,
+                    };
+                    self.events
+                        .push_back(Err(self.error(span, "line is not valid UTF-8")));
+                    let newline = raw.last() == Some(&b'\n');
+                    let blank = raw.len() - usize::from(newline);
+                    self.chunk.extend(std::iter::repeat_n(' ', blank));
+                    if newline {
+                        self.chunk.push('\n');
+                    }
+                }
+            }
+            if in_comment && !self.chunk[start..].contains("*/") {
+                continue;
+            }
+            in_comment = matches!(
+                self.lexer().last(),
+                Some(Token {
+                    kind: TokenKind::UnterminatedBlockComment,
+                    ..
+                })
+            );
+            if !in_comment {
+                break;
+            }
+        }
+
+        self.tokens = self.lexer().collect();
+        Ok(!self.tokens.is_empty())
+    }
+
+    /// Skip the rest of the current line after an error
+    ///
+    /// Inside a `#struct` body everything up to the closing brace is skipped, unless
+    /// a new directive starts first.
+    fn recover(&mut self) -> Result<()> {
+        if std::mem::take(&mut self.in_struct) {
+            while let Some(token) = self.peek()? {
+                match token.kind {
+                    TokenKind::DirectiveOption | TokenKind::DirectiveStruct => return Ok(()),
+                    TokenKind::RBrace => {
+                        self.bump();
+                        break;
+                    }
+                    _ => {
+                        self.bump();
+                    }
+                }
+            }
+        }
+        while let Some(token) = self.bump() {
+            if token.kind == TokenKind::Newline {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn lexer(&self) -> Lexer<'_> {
+        Lexer::with_position(&self.chunk, self.chunk_offset, self.chunk_line)
+    }
+
+    /// Text of a token from the current chunk
+    fn text(&self, token: &Token) -> &str {
+        &self.chunk[token.span.start - self.chunk_offset..token.span.end - self.chunk_offset]
+    }
+
+    fn peek(&mut self) -> Result<Option<Token>> {
+        if self.tokens.is_empty() && !self.fill()? {
+            return Ok(None);
+        }
+        Ok(self.tokens.front().copied())
+    }
+
+    fn peek_kind(&mut self) -> Result<Option<TokenKind>> {
+        Ok(self.peek()?.map(|t| t.kind))
+    }
+
+    fn bump(&mut self) -> Option<Token> {
+        self.tokens.pop_front()
+    }
+
+    /// Skip whitespace and comments, emitting comment events
+    fn skip_trivia(&mut self, newlines: bool) -> Result<()> {
+        while let Some(token) = self.peek()? {
+            if token.kind.is_comment() {
+                let text = self.text(&token).to_string();
+                self.events.push_back(Ok(Event::Comment {
+                    text,
+                    span: token.span,
+                }));
+            } else if !(token.kind.is_trivia() || newlines && token.kind == TokenKind::Newline) {
+                break;
+            }
+            self.bump();
+        }
+        Ok(())
+    }
+
+    /// Next token of the current line after whitespace, without consuming anything
+    fn lookahead(&self) -> Option<Token> {
+        self.tokens
+            .iter()
+            .skip(1)
+            .find(|t| !t.kind.is_trivia())
+            .copied()
+    }
+
+    fn error(&self, span: Span, message: impl std::fmt::Display) -> Error {
+        Error::parse(span, message.to_string())
+    }
+
+    /// Error for an unexpected token, or for the end of input if there is none
+    fn unexpected(&self, expected: &str) -> Error {
+        match self.tokens.front().copied() {
+            Some(token) if token.kind == TokenKind::Newline => {
+                let span = Span {
+                    end: token.span.start,
+                    ..token.span
+                };
+                self.error(span, format!("{}, found end of line", expected))
+            }
+            Some(token) => self.error(
+                token.span,
+                format!("{}, found '{}'", expected, self.text(&token)),
+            ),
+            None => {
+                let span = Span {
+                    start: self.chunk_offset + self.chunk.len(),
+                    end: self.chunk_offset + self.chunk.len(),
+                    line: self.chunk_line + self.chunk.matches('\n').count(),
+                    column: 1,
+                };
+                self.error(span, format!("{}, found end of input", expected))
+            }
+        }
+    }
+
+    /// Consume a token of the given kind
+    fn expect(&mut self, kind: TokenKind, expected: &str) -> Result<Token> {
+        match self.peek()? {
+            Some(token) if token.kind == kind => {
+                self.bump();
+                Ok(token)
+            }
+            _ => Err(self.unexpected(expected)),
+        }
+    }
+
+    /// Consume a name; hex-looking identifiers such as `face` are accepted too
+    fn expect_name(&mut self, expected: &str) -> Result<(String, Span)> {
+        match self.peek()? {
+            Some(token)
+                if matches!(token.kind, TokenKind::Identifier | TokenKind::HexSequence)
+                    && is_identifier(self.text(&token)) =>
+            {
+                self.bump();
+                Ok((self.text(&token).to_string(), token.span))
+            }
+            _ => Err(self.unexpected(expected)),
+        }
+    }
+
+    /// Expect the end of the current line after optional whitespace and comments
+    fn expect_line_end(&mut self) -> Result<()> {
+        self.skip_trivia(false)?;
+        match self.peek_kind()? {
+            None => Ok(()),
+            Some(TokenKind::Newline) => {
+                self.bump();
+                Ok(())
+            }
+            Some(TokenKind::LBracket) => Err(self.unexpected("unclosed preview")),
+            Some(_) => Err(self.unexpected("expected end of line")),
+        }
+    }
+
+    /// Parse one directive or data line; returns `false` at end of input
+    fn parse_statement(&mut self) -> Result<bool> {
+        self.skip_trivia(true)?;
+        let Some(token) = self.peek()? else {
+            return Ok(false);
+        };
+
+        match token.kind {
+            TokenKind::DirectiveOption => self.parse_option()?,
+            TokenKind::DirectiveStruct => self.parse_struct()?,
+            TokenKind::Directive => {
+                let message = format!("unknown directive '{}'", self.text(&token));
+                return Err(self.error(token.span, message));
+            }
+            TokenKind::HexSequence | TokenKind::Preview | TokenKind::Pipe => {
+                self.parse_data_line()?
+            }
+            _ => return Err(self.unexpected("expected directive or data line")),
+        }
+        Ok(true)
+    }
+
+    /// `#option name=value`
+    fn parse_option(&mut self) -> Result<()> {
+        let start = self.bump().map(|t| t.span).unwrap_or_default();
+        self.skip_trivia(false)?;
+        let (name, _) = self.expect_name("expected option name")?;
+        self.skip_trivia(false)?;
+        self.expect(TokenKind::Equals, "expected '='")?;
+        self.skip_trivia(false)?;
+
+        let mut value = String::new();
+        let mut end = start;
+        match self.peek()? {
+            Some(token) if token.kind == TokenKind::String => {
+                self.bump();
+                value = unescape(self.text(&token)).map_err(|e| self.error(token.span, e))?;
+                end = token.span;
+            }
+            _ => {
+                // Unquoted value: everything up to whitespace, comment or end of line
+                while let Some(token) = self.peek()? {
+                    if token.kind.is_trivia() || token.kind == TokenKind::Newline {
+                        break;
+                    }
+                    self.bump();
+                    value.push_str(self.text(&token));
+                    end = token.span;
+                }
+                if value.is_empty() {
+                    return Err(self.unexpected("expected option value"));
+                }
+            }
+        }
+
+        let span = start.to(end);
+        let radix = self.config.radix;
+        if let Err(e) = self.config.apply_directive(&name, &value) {
+            let message = match e {
+                Error::Config(message) => message,
+                other => other.to_string(),
+            };
+            return Err(self.error(span, message));
+        }
+        // A file has one radix, declared before its data
+        if let Some(data_radix) = self.data_radix.filter(|&r| r != self.config.radix) {
+            let message = format!(
+                "radix={} after data lines in radix {}: a file cannot mix radices",
+                self.config.radix, data_radix
+            );
+            self.config.radix = radix;
+            return Err(self.error(span, message));
+        }
+        if options::find(&name).is_some_and(|spec| spec.name == "address_size") {
+            self.address_size_declared = true;
+        }
+        self.types.set_endian(self.config.endian);
+        self.events
+            .push_back(Ok(Event::Option { name, value, span }));
+        self.expect_line_end()
+    }
+
+    /// `#struct name { field:type, ... }`
+    fn parse_struct(&mut self) -> Result<()> {
+        let start = self.bump().map(|t| t.span).unwrap_or_default();
+        self.in_struct = true;
+        self.skip_trivia(true)?;
+        let (name, _) = self.expect_name("expected struct name")?;
+        self.skip_trivia(true)?;
+        self.expect(TokenKind::LBrace, "expected '{'")?;
+
+        let mut fields = Vec::new();
+        let end = loop {
+            self.skip_trivia(true)?;
+            if let Some(token) = self.peek()? {
+                if token.kind == TokenKind::RBrace {
+                    self.bump();
+                    break token.span;
+                }
+            }
+            let (field_name, field_span) = self.expect_name("expected field name or '}'")?;
+            self.skip_trivia(true)?;
+            self.expect(TokenKind::Colon, "expected ':'")?;
+            self.skip_trivia(true)?;
+            let (type_name, type_span) = self.expect_name("expected field type")?;
+            fields.push(StructField {
+                name: field_name,
+                type_name,
+                span: field_span.to(type_span),
+            });
+            self.skip_trivia(true)?;
+            match self.peek_kind()? {
+                Some(TokenKind::Comma) => {
+                    self.bump();
+                }
+                Some(TokenKind::RBrace) => {}
+                _ => return Err(self.unexpected("expected ',' or '}'")),
+            }
+        };
+
+        self.in_struct = false;
+        let span = start.to(end);
+        let event = self
+            .types
+            .define_struct(&name, &fields, span)
+            .map(|_| Event::Struct { name, fields, span });
+        self.events.push_back(event);
+        self.expect_line_end()
+    }
+
+    /// `(address:)? hex_data preview? annotations?`
+    fn parse_data_line(&mut self) -> Result<()> {
+        if let Some(token) = self.peek()? {
+            let is_address = token.kind == TokenKind::HexSequence
+                && matches!(self.lookahead(), Some(t) if t.kind == TokenKind::Colon);
+            if is_address {
+                self.bump();
+                let address = parse_address(self.text(&token))
+                    .ok_or_else(|| self.error(token.span, "address does not fit into 64 bits"))?;
+                let address_size = self.config.address_size;
+                if self.address_size_declared && !address_size.fits(address) {
+                    let message = format!(
+                        "address {:X} does not fit into address_size={}",
+                        address, address_size
+                    );
+                    return Err(self.error(token.span, message));
+                }
+                self.skip_trivia(false)?;
+                let colon = self.expect(TokenKind::Colon, "expected ':'")?;
+                self.events.push_back(Ok(Event::Address {
+                    address,
+                    span: token.span.to(colon.span),
+                }));
+            }
+        }
+
+        let mut data = Vec::new();
+        let mut span: Option<Span> = None;
+        loop {
+            self.skip_trivia(false)?;
+            let Some(token) = self.peek()? else { break };
+            match token.kind {
+                TokenKind::HexSequence => {
+                    self.bump();
+                    let radix = self.config.radix;
+                    self.data_radix = Some(radix);
+                    decode_bytes(self.text(&token), radix, &mut data)
+                        .map_err(|e| self.error(token.span, e))?;
+                    span = Some(span.map_or(token.span, |s| s.to(token.span)));
+                }
+                TokenKind::Identifier | TokenKind::UnquotedValue | TokenKind::Unknown => {
+                    let expected = format!("expected {} byte", self.config.radix.name());
+                    return Err(self.unexpected(&expected));
+                }
+                _ => break,
+            }
+        }
+        if let Some(span) = span {
+            self.config.word.swap(&mut data, self.config.endian);
+            self.events.push_back(Ok(Event::Bytes { data, span }));
+        }
+
+        if let Some(token) = self.peek()? {
+            if token.kind == TokenKind::Preview {
+                self.bump();
+                let text = self.text(&token);
+                let text = text[1..text.len() - 1].to_string();
+                self.events.push_back(Ok(Event::Preview {
+                    text,
+                    span: token.span,
+                }));
+                self.skip_trivia(false)?;
+            }
+        }
+
+        if self.peek_kind()? == Some(TokenKind::Pipe) {
+            self.bump();
+            loop {
+                self.skip_trivia(false)?;
+                match self.peek_kind()? {
+                    None | Some(TokenKind::Newline) => break,
+                    Some(_) => {
+                        let annotation = self.parse_annotation()?;
+                        // Structs must be defined before they are used
+                        let event = match self.types.resolve(&annotation.type_name) {
+                            Ok(_) => Ok(Event::Annotation(annotation)),
+                            Err(e) => Err(self.error(annotation.span, e)),
+                        };
+                        self.events.push_back(event);
+                    }
+                }
+            }
+        }
+
+        self.expect_line_end()
+    }
+
+    /// `(+offset)? ((name))? :type=value` or `type=value`
+    fn parse_annotation(&mut self) -> Result<Annotation> {
+        let start = self.peek()?.map(|t| t.span).unwrap_or_default();
+        let mut offset = None;
+        let mut name = None;
+
+        if self.peek_kind()? == Some(TokenKind::Plus) {
+            self.bump();
+            self.skip_trivia(false)?;
+            let token = self.expect(TokenKind::HexSequence, "expected hex offset")?;
+            offset = Some(
+                parse_address(self.text(&token))
+                    .ok_or_else(|| self.error(token.span, "offset does not fit into 64 bits"))?,
+            );
+            self.skip_trivia(false)?;
+        }
+        if self.peek_kind()? == Some(TokenKind::LParen) {
+            self.bump();
+            self.skip_trivia(false)?;
+            name = Some(self.expect_name("expected annotation name")?.0);
+            self.skip_trivia(false)?;
+            self.expect(TokenKind::RParen, "expected ')'")?;
+            self.skip_trivia(false)?;
+        }
+        if offset.is_some() || name.is_some() || self.peek_kind()? == Some(TokenKind::Colon) {
+            self.expect(TokenKind::Colon, "expected ':'")?;
+            self.skip_trivia(false)?;
+        }
+
+        let (type_name, _) = self.expect_name("expected annotation type")?;
+        self.skip_trivia(false)?;
+        self.expect(TokenKind::Equals, "expected '='")?;
+        self.skip_trivia(false)?;
+        let (value, end) = self.parse_literal()?;
+
+        Ok(Annotation {
+            offset,
+            name,
+            type_name,
+            value,
+            span: start.to(end),
+        })
+    }
+
+    /// Decimal integer, string or struct value
+    fn parse_literal(&mut self) -> Result<(Literal, Span)> {
+        let Some(token) = self.peek()? else {
+            return Err(self.unexpected("expected value"));
+        };
+        match token.kind {
+            TokenKind::HexSequence => {
+                self.bump();
+                let text = self.text(&token);
+                let value = text.parse::<u64>().map_err(|_| {
+                    self.error(token.span, format!("invalid decimal value '{}'", text))
+                })?;
+                Ok((Literal::Integer(value), token.span))
+            }
+            TokenKind::String => {
+                self.bump();
+                let value = unescape(self.text(&token)).map_err(|e| self.error(token.span, e))?;
+                Ok((Literal::String(value), token.span))
+            }
+            TokenKind::UnterminatedString => Err(self.unexpected("unterminated string")),
+            TokenKind::LBrace => {
+                self.bump();
+                let mut fields = Vec::new();
+                let end = loop {
+                    self.skip_trivia(true)?;
+                    if let Some(close) = self.peek()? {
+                        if close.kind == TokenKind::RBrace {
+                            self.bump();
+                            break close.span;
+                        }
+                    }
+                    let (field, _) = self.expect_name("expected field name or '}'")?;
+                    self.skip_trivia(true)?;
+                    self.expect(TokenKind::Equals, "expected '='")?;
+                    self.skip_trivia(true)?;
+                    let (value, _) = self.parse_literal()?;
+                    fields.push((field, value));
+                    self.skip_trivia(true)?;
+                    match self.peek_kind()? {
+                        Some(TokenKind::Comma) => {
+                            self.bump();
+                        }
+                        Some(TokenKind::RBrace) => {}
+                        _ => return Err(self.unexpected("expected ',' or '}'")),
+                    }
+                };
+                Ok((Literal::Struct(fields), token.span.to(end)))
+            }
+            _ => Err(self.unexpected("expected value")),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Parser<R> {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Result<Event>> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Some(event);
+            }
+            if self.finished {
+                return None;
+            }
+            match self.parse_statement() {
+                Ok(true) => {}
+                Ok(false) => self.finished = true,
+                Err(e) => {
+                    // Recover at the next line, unless reading the input failed
+                    self.finished = matches!(e, Error::Io(_));
+                    self.events.push_back(Err(e));
+                    if let Err(e) = self.recover() {
+                        self.finished = true;
+                        self.events.push_back(Err(e));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Parse a hex address or offset, allowing leading zeros beyond 16 digits
+pub(crate) fn parse_address(text: &str) -> Option<u64> {
+    let digits = text.trim_start_matches('0');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 16).ok()
+}
+
+/// Decode a sequence of bytes of `radix.digits()` digits each
+pub(crate) fn decode_bytes(
+    text: &str,
+    radix: Radix,
+    out: &mut Vec<u8>,
+) -> std::result::Result<(), String> {
+    let width = radix.digits();
+    if !text.len().is_multiple_of(width) {
+        return Err(match radix {
+            Radix::Hex => format!("odd number of hex digits in '{}'", text),
+            _ => format!(
+                "expected {} {} digits per byte in '{}' (radix={})",
+                width,
+                radix.name(),
+                text,
+                radix
+            ),
+        });
+    }
+    for chunk in text.as_bytes().chunks(width) {
+        let digits = std::str::from_utf8(chunk).map_err(|e| e.to_string())?;
+        let byte = u8::from_str_radix(digits, radix.base()).map_err(|_| match radix {
+            Radix::Hex => format!("invalid hex byte '{}'", digits),
+            _ => format!(
+                "invalid {} byte '{}' (radix={})",
+                radix.name(),
+                digits,
+                radix
+            ),
+        })?;
+        out.push(byte);
+    }
+    Ok(())
+}
+
+/// Resolve escape sequences of a quoted string literal (including the quotes)
+fn unescape(literal: &str) -> std::result::Result<String, String> {
+    let inner = &literal[1..literal.len() - 1];
+    let mut result = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('t') => result.push('\t'),
+            Some('0') => result.push('\0'),
+            Some('\\') => result.push('\\'),
+            Some('"') => result.push('"'),
+            Some('\'') => result.push('\''),
+            Some('u') => {
+                let rest = chars.as_str();
+                let code = rest
+                    .strip_prefix('{')
+                    .and_then(|r| r.split_once('}'))
+                    .and_then(|(hex, _)| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| "invalid unicode escape, expected \\u{XXXX}".to_string())?;
+                result.push(code);
+                let skip = rest.find('}').unwrap_or(0) + 1;
+                chars = rest[skip..].chars();
+            }
+            Some(other) => return Err(format!("unknown escape sequence '\\{}'", other)),
+            None => return Err("unfinished escape sequence".to_string()),
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Vec<Event> {
+        Parser::new(text.as_bytes())
+            .collect::<Result<Vec<_>>>()
+            .unwrap()
+    }
+
+    fn parse_err(text: &str) -> String {
+        Parser::new(text.as_bytes())
+            .find_map(|e| e.err())
+            .unwrap()
+            .to_string()
+    }
+
+    fn bytes(events: &[Event]) -> Vec<u8> {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                Event::Bytes { data, .. } => Some(data.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_bytes() {
+        let mut out = Vec::new();
+        decode_bytes("48656C6c6F", Radix::Hex, &mut out).unwrap();
+        assert_eq!(out, b"Hello");
+        assert!(decode_bytes("ABC", Radix::Hex, &mut out).is_err());
+
+        let mut out = Vec::new();
+        decode_bytes("0100100001101001", Radix::Binary, &mut out).unwrap();
+        decode_bytes("110151", Radix::Octal, &mut out).unwrap();
+        decode_bytes("072105", Radix::Decimal, &mut out).unwrap();
+        assert_eq!(out, b"HiHiHi");
+        assert_eq!(
+            decode_bytes("0100100", Radix::Binary, &mut out).unwrap_err(),
+            "expected 8 binary digits per byte in '0100100' (radix=2)"
+        );
+        assert_eq!(
+            decode_bytes("256", Radix::Decimal, &mut out).unwrap_err(),
+            "invalid decimal byte '256' (radix=10)"
+        );
+        assert!(decode_bytes("400", Radix::Octal, &mut out).is_err());
+        assert!(decode_bytes("08", Radix::Hex, &mut out).is_ok());
+    }
+
+    #[test]
+    fn test_radix() {
+        let events = parse("#option radix=2\n00000000: 01001000 01101001\n00000010\n");
+        assert_eq!(bytes(&events), b"Hi\x02");
+
+        assert_eq!(
+            parse_err("#option radix=10\n072 4C\n"),
+            "line 2, column 5: expected 3 decimal digits per byte in '4C' (radix=10)"
+        );
+        assert_eq!(
+            parse_err("#option radix=10\n072 ZZZ\n"),
+            "line 2, column 5: expected decimal byte, found 'ZZZ'"
+        );
+        assert_eq!(
+            parse_err("00 01\n#option radix=8\n001\n"),
+            "line 2, column 1: radix=8 after data lines in radix 16: a file cannot mix radices"
+        );
+        // declaring the same radix again is not mixing
+        assert_eq!(
+            bytes(&parse("#option radix=8\n001\n#option radix=8\n002\n")),
+            [1, 2]
+        );
+    }
+
+    #[test]
+    fn test_data_line_events() {
+        let events = parse("00000010: 48 65 6C [H|e // l:] | +0:u16=25928 // c\n");
+        assert_eq!(events.len(), 5);
+        assert!(matches!(events[0], Event::Address { address: 0x10, .. }));
+        assert!(matches!(&events[1], Event::Bytes { data, .. } if data == b"Hel"));
+        assert!(matches!(&events[2], Event::Preview { text, .. } if text == "H|e // l:"));
+        assert_eq!(
+            events[3],
+            Event::Annotation(Annotation {
+                offset: Some(0),
+                name: None,
+                type_name: "u16".to_string(),
+                value: Literal::Integer(25928),
+                span: match &events[3] {
+                    Event::Annotation(a) => a.span,
+                    _ => unreachable!(),
+                },
+            })
+        );
+        assert!(matches!(&events[4], Event::Comment { text, .. } if text == "// c"));
+    }
+
+    #[test]
+    fn test_comments_inside_data() {
+        let events = parse("00 /* one */ 01 /* multi\nline 02 */ 03\n04 // 05\n");
+        assert_eq!(bytes(&events), vec![0x00, 0x01, 0x03, 0x04]);
+    }
+
+    #[test]
+    fn test_directives() {
+        let events = parse(
+            "#option bin_filename=lorem.bin\n#option padding=00 // FF\n#option name=\"a b\"\n\
+             #struct data {\n    name_x:u32,\n    face:u16, // c\n}\n",
+        );
+        let options: Vec<(&str, &str)> = events
+            .iter()
+            .filter_map(|e| match e {
+                Event::Option { name, value, .. } => Some((name.as_str(), value.as_str())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            options,
+            vec![
+                ("bin_filename", "lorem.bin"),
+                ("padding", "00"),
+                ("name", "a b")
+            ]
+        );
+        let fields = events
+            .iter()
+            .find_map(|e| match e {
+                Event::Struct { name, fields, .. } if name == "data" => Some(fields),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[1].name, "face");
+        assert_eq!(fields[1].type_name, "u16");
+    }
+
+    #[test]
+    fn test_struct_definitions() {
+        let mut parser = Parser::new(
+            "#option endian=BIG_ENDIAN\n\
+             #struct point { x:u16, y:u16 }\n\
+             #struct line { from:point, to:point }\n\
+             #struct line { a:u8 }\n\
+             00 | +0:line={from={x=1, y=2}, to={x=3, y=4}}\n"
+                .as_bytes(),
+        );
+        let events: Vec<Result<Event>> = parser.by_ref().collect();
+        assert!(matches!(&events[2], Ok(Event::Struct { name, .. }) if name == "line"));
+        assert_eq!(
+            events[3].as_ref().unwrap_err().to_string(),
+            "line 4, column 1: struct 'line' is already defined"
+        );
+        assert!(matches!(&events[5], Ok(Event::Annotation(_))));
+
+        let Ok(crate::types::Type::Struct(line)) = parser.types().resolve("line") else {
+            panic!("line is not a struct");
+        };
+        assert_eq!(line.size(), 8);
+        assert_eq!(line.field("to").unwrap().offset, 4);
+        assert_eq!(parser.types().struct_fields("line").unwrap().len(), 2);
+        assert_eq!(parser.types().endian(), crate::config::Endian::Big);
+    }
+
+    #[test]
+    fn test_options_update_config() {
+        let mut parser = Parser::new(
+            "#option endian=BIG_ENDIAN\n00\n#option line_length=8\n#option author=x\n\
+             #option repeat_adress=once\n"
+                .as_bytes(),
+        );
+        assert!(matches!(parser.next(), Some(Ok(Event::Option { .. }))));
+        assert_eq!(parser.config().endian, crate::config::Endian::Big);
+        assert_eq!(parser.config().line_length, 16);
+        assert!(matches!(parser.next(), Some(Ok(Event::Bytes { .. }))));
+        assert!(matches!(parser.next(), Some(Ok(Event::Option { .. }))));
+        assert_eq!(parser.config().line_length, 8);
+        assert!(matches!(parser.next(), Some(Ok(Event::Option { .. }))));
+        assert_eq!(
+            parser.config().extra_options,
+            vec![("author".to_string(), "x".to_string())]
+        );
+        // an old spelling sets the option it stands for
+        assert!(matches!(parser.next(), Some(Ok(Event::Option { .. }))));
+        assert_eq!(
+            parser.config().repeat_address,
+            crate::config::RepeatAddress::Once
+        );
+
+        assert_eq!(
+            parse_err("\n#option padding=XYZ\n"),
+            "line 2, column 1: invalid padding 'XYZ': expected a hex byte (00..FF) or 'forbidden'"
+        );
+    }
+
+    #[test]
+    fn test_annotations() {
+        let events = parse(
+            "#struct pair { z:u8 }\n#struct data { x:u8, s:u8, inner:pair }\n\
+             01 00 02 00 | u16=1 (y):u16=2\n00 | +00:data={x=1, s=\"a\\n\", inner={z=2}} :u8=0\n",
+        );
+        let annotations: Vec<&Annotation> = events
+            .iter()
+            .filter_map(|e| match e {
+                Event::Annotation(a) => Some(a),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(annotations.len(), 4);
+        assert_eq!(annotations[0].offset, None);
+        assert_eq!(annotations[1].name.as_deref(), Some("y"));
+        assert_eq!(
+            annotations[2].value,
+            Literal::Struct(vec![
+                ("x".to_string(), Literal::Integer(1)),
+                ("s".to_string(), Literal::String("a\n".to_string())),
+                (
+                    "inner".to_string(),
+                    Literal::Struct(vec![("z".to_string(), Literal::Integer(2))])
+                ),
+            ])
+        );
+        assert_eq!(annotations[3].type_name, "u8");
+    }
+
+    #[test]
+    fn test_recovery_at_line_boundaries() {
+        let events: Vec<Result<Event>> =
+            Parser::new(&b"00 ZZ 01\n02 | u32=\n\xFF\xFE 03\n04 [x] [y]\n05\n"[..]).collect();
+        let errors: Vec<String> = events
+            .iter()
+            .filter_map(|e| e.as_ref().err().map(|e| e.to_string()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "line 1, column 4: expected hex byte, found 'ZZ'",
+                "line 2, column 10: expected value, found end of line",
+                "line 3, column 1: line is not valid UTF-8",
+                "line 4, column 8: expected end of line, found '[y]'",
+            ]
+        );
+        let data: Vec<u8> = events
+            .into_iter()
+            .filter_map(|e| match e {
+                Ok(Event::Bytes { data, .. }) => Some(data),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        assert_eq!(data, vec![0x02, 0x04, 0x05]);
+
+        // A broken struct is skipped up to its closing brace
+        let errors: Vec<Error> = Parser::new("#struct s {\n a u8,\n b:u8,\n}\n0G\n".as_bytes())
+            .filter_map(|e| e.err())
+            .collect();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            errors[0].to_string(),
+            "line 2, column 4: expected ':', found 'u8'"
+        );
+        assert_eq!(errors[1].location().unwrap().span.line, 5);
+    }
+
+    #[test]
+    fn test_reference_corpus() {
+        let input = include_str!("../../../tests/corpus/valid/01_reference.hex");
+        let events = parse(input);
+        assert_eq!(bytes(&events).len(), 0x7B + 16 + 48 + 0x7B);
+        assert_eq!(
+            events
+                .iter()
+                .filter(|e| matches!(e, Event::Annotation(_)))
+                .count(),
+            11
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            parse_err("00 01\n00000010: 4C ZZ\n"),
+            "line 2, column 14: expected hex byte, found 'ZZ'"
+        );
+        assert!(parse_err("ABC\n").contains("odd number of hex digits"));
+        assert!(parse_err("#include x\n").contains("unknown directive '#include'"));
+        assert!(parse_err("00 [open\n").contains("unclosed preview"));
+        assert!(parse_err("00 | u32=\n").contains("expected value, found end of line"));
+        assert!(parse_err("#struct s { a:u8\n").contains("expected ',' or '}', found end of input"));
+        assert_eq!(
+            parse_err("#struct s {\n a:u8,\n a:u16,\n}\n"),
+            "line 3, column 2: duplicate field 'a' in struct 's'"
+        );
+        assert_eq!(
+            parse_err("00 | +0:s={a=0}\n#struct s { a:u8 }\n"),
+            "line 1, column 6: unknown type 's'"
+        );
+        let err = Parser::new(&b"00\n01 \xFF\n"[..])
+            .find_map(|e| e.err())
+            .unwrap();
+        assert_eq!(err.to_string(), "line 2, column 1: line is not valid UTF-8");
+        assert_eq!(
+            parse_err("#option address_size=u8\nF0: 01\n0100: 02\n"),
+            "line 3, column 1: address 100 does not fit into address_size=u8"
+        );
+        // without a declared size, the address is as wide as it is written
+        assert!(Parser::new(&b"100000000: 02\n"[..]).all(|e| e.is_ok()));
+    }
+}
@@ -0,0 +1,260 @@
+//! Text of the preview column of data lines
+//!
+//! Every byte of a line takes one cell of the preview. Single-byte encodings show
+//! the character of each byte; in `utf8`, `utf16le` and `utf16be` a character takes
+//! the cell of its first byte and the cells of its other bytes are spaces.
+//! Sequences are decoded within their line, so a character split between two
+//! lines shows as placeholders on both. Bytes that are not printable characters
+//! show as the placeholder, or, with control pictures, C0 control characters and
+//! DEL show as their Unicode pictures (`␀`, `␊`, `␡`).
+
+use crate::config::PreviewEncoding;
+use crate::Config;
+
+/// Preview of the bytes of a data line, as written between square brackets
+///
+/// Brackets are shown as the placeholder, so that the preview ends at its `]`.
+pub fn preview_text(data: &[u8], config: &Config) -> String {
+    render(data, config, true)
+}
+
+/// Preview of the bytes of a line for a template field, which may show brackets
+pub fn template_text(data: &[u8], config: &Config) -> String {
+    render(data, config, false)
+}
+
+fn render(data: &[u8], config: &Config, brackets_hidden: bool) -> String {
+    let shown = |c: char| {
+        if c.is_control() {
+            match control_picture(c) {
+                Some(picture) if config.preview_control_pictures => picture,
+                _ => config.preview_placeholder,
+            }
+        } else if brackets_hidden && (c == '[' || c == ']') {
+            config.preview_placeholder
+        } else {
+            c
+        }
+    };
+
+    let mut text = String::with_capacity(data.len());
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (c, len) = decode(config.preview_encoding, rest);
+        match c {
+            Some(c) => {
+                text.push(shown(c));
+                text.extend(std::iter::repeat_n(' ', len - 1));
+            }
+            None => text.extend(std::iter::repeat_n(config.preview_placeholder, len)),
+        }
+        rest = &rest[len..];
+    }
+    text
+}
+
+/// First character of `data` and the number of its bytes; the character is `None`
+/// if these bytes are not one
+fn decode(encoding: PreviewEncoding, data: &[u8]) -> (Option<char>, usize) {
+    let byte = data[0];
+    let single = |c: char| (Some(c).filter(|&c| c != '\0' || byte == 0), 1);
+    match encoding {
+        PreviewEncoding::Ascii => (Some(byte as char).filter(|_| byte.is_ascii()), 1),
+        PreviewEncoding::Latin1 => single(byte as char),
+        PreviewEncoding::Cp437 => single(upper_half(&CP437, byte)),
+        PreviewEncoding::Cp1251 => single(upper_half(&CP1251, byte)),
+        PreviewEncoding::Koi8R => single(upper_half(&KOI8_R, byte)),
+        PreviewEncoding::Ebcdic037 => single(EBCDIC_037[byte as usize]),
+        PreviewEncoding::Utf8 => decode_utf8(data),
+        PreviewEncoding::Utf16Le => decode_utf16(data, u16::from_le_bytes),
+        PreviewEncoding::Utf16Be => decode_utf16(data, u16::from_be_bytes),
+    }
+}
+
+/// Character of an ASCII byte, or of a byte of the upper half from `table`
+fn upper_half(table: &[char; 128], byte: u8) -> char {
+    match byte {
+        0..=0x7F => byte as char,
+        _ => table[byte as usize - 0x80],
+    }
+}
+
+fn decode_utf8(data: &[u8]) -> (Option<char>, usize) {
+    let len = match data[0] {
+        0x00..=0x7F => 1,
+        0xC2..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF4 => 4,
+        _ => return (None, 1),
+    };
+    match data.get(..len).map(std::str::from_utf8) {
+        Some(Ok(text)) => (text.chars().next(), len),
+        _ => (None, 1),
+    }
+}
+
+/// Character of the first code units; an odd byte at the end or an unpaired
+/// surrogate is not one
+fn decode_utf16(data: &[u8], unit: fn([u8; 2]) -> u16) -> (Option<char>, usize) {
+    if data.len() < 2 {
+        return (None, 1);
+    }
+    let units = data
+        .chunks_exact(2)
+        .take(2)
+        .map(|pair| unit([pair[0], pair[1]]));
+    match char::decode_utf16(units).next() {
+        Some(Ok(c)) => (Some(c), 2 * c.len_utf16()),
+        _ => (None, 2),
+    }
+}
+
+/// Unicode picture of a C0 control character or DEL
+fn control_picture(c: char) -> Option<char> {
+    match c {
+        '\0'..='\u{1F}' => char::from_u32(0x2400 + c as u32),
+        '\u{7F}' => Some('\u{2421}'),
+        _ => None,
+    }
+}
+
+/// Bytes 0x80..=0xFF of code page 437
+const CP437: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', 'É', 'æ', 'Æ',
+    'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', 'á', 'í', 'ó', 'ú', 'ñ', 'Ñ',
+    'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕',
+    '╣', '║', '╗', '╝', '╜', '╛', '┐', '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦',
+    '╠', '═', '╬', '╧', '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐',
+    '▀', 'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', '≡', '±',
+    '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}',
+];
+
+/// Bytes 0x80..=0xFF of Windows-1251; `\0` where undefined
+const CP1251: [char; 128] = [
+    'Ђ', 'Ѓ', '‚', 'ѓ', '„', '…', '†', '‡', '€', '‰', 'Љ', '‹', 'Њ', 'Ќ', 'Ћ', 'Џ', 'ђ', '‘', '’',
+    '“', '”', '•', '–', '—', '\u{0}', '™', 'љ', '›', 'њ', 'ќ', 'ћ', 'џ', '\u{A0}', 'Ў', 'ў', 'Ј',
+    '¤', 'Ґ', '¦', '§', 'Ё', '©', 'Є', '«', '¬', '\u{AD}', '®', 'Ї', '°', '±', 'І', 'і', 'ґ', 'µ',
+    '¶', '·', 'ё', '№', 'є', '»', 'ј', 'Ѕ', 'ѕ', 'ї', 'А', 'Б', 'В', 'Г', 'Д', 'Е', 'Ж', 'З', 'И',
+    'Й', 'К', 'Л', 'М', 'Н', 'О', 'П', 'Р', 'С', 'Т', 'У', 'Ф', 'Х', 'Ц', 'Ч', 'Ш', 'Щ', 'Ъ', 'Ы',
+    'Ь', 'Э', 'Ю', 'Я', 'а', 'б', 'в', 'г', 'д', 'е', 'ж', 'з', 'и', 'й', 'к', 'л', 'м', 'н', 'о',
+    'п', 'р', 'с', 'т', 'у', 'ф', 'х', 'ц', 'ч', 'ш', 'щ', 'ъ', 'ы', 'ь', 'э', 'ю', 'я',
+];
+
+/// Bytes 0x80..=0xFF of KOI8-R
+const KOI8_R: [char; 128] = [
+    '─', '│', '┌', '┐', '└', '┘', '├', '┤', '┬', '┴', '┼', '▀', '▄', '█', '▌', '▐', '░', '▒', '▓',
+    '⌠', '■', '∙', '√', '≈', '≤', '≥', '\u{A0}', '⌡', '°', '²', '·', '÷', '═', '║', '╒', 'ё', '╓',
+    '╔', '╕', '╖', '╗', '╘', '╙', '╚', '╛', '╜', '╝', '╞', '╟', '╠', '╡', 'Ё', '╢', '╣', '╤', '╥',
+    '╦', '╧', '╨', '╩', '╪', '╫', '╬', '©', 'ю', 'а', 'б', 'ц', 'д', 'е', 'ф', 'г', 'х', 'и', 'й',
+    'к', 'л', 'м', 'н', 'о', 'п', 'я', 'р', 'с', 'т', 'у', 'ж', 'в', 'ь', 'ы', 'з', 'ш', 'э', 'щ',
+    'ч', 'ъ', 'Ю', 'А', 'Б', 'Ц', 'Д', 'Е', 'Ф', 'Г', 'Х', 'И', 'Й', 'К', 'Л', 'М', 'Н', 'О', 'П',
+    'Я', 'Р', 'С', 'Т', 'У', 'Ж', 'В', 'Ь', 'Ы', 'З', 'Ш', 'Э', 'Щ', 'Ч', 'Ъ',
+];
+
+/// Bytes of EBCDIC code page 037
+const EBCDIC_037: [char; 256] = [
+    '\u{0}', '\u{1}', '\u{2}', '\u{3}', '\u{9C}', '\u{9}', '\u{86}', '\u{7F}', '\u{97}', '\u{8D}',
+    '\u{8E}', '\u{B}', '\u{C}', '\u{D}', '\u{E}', '\u{F}', '\u{10}', '\u{11}', '\u{12}', '\u{13}',
+    '\u{9D}', '\u{85}', '\u{8}', '\u{87}', '\u{18}', '\u{19}', '\u{92}', '\u{8F}', '\u{1C}',
+    '\u{1D}', '\u{1E}', '\u{1F}', '\u{80}', '\u{81}', '\u{82}', '\u{83}', '\u{84}', '\u{A}',
+    '\u{17}', '\u{1B}', '\u{88}', '\u{89}', '\u{8A}', '\u{8B}', '\u{8C}', '\u{5}', '\u{6}',
+    '\u{7}', '\u{90}', '\u{91}', '\u{16}', '\u{93}', '\u{94}', '\u{95}', '\u{96}', '\u{4}',
+    '\u{98}', '\u{99}', '\u{9A}', '\u{9B}', '\u{14}', '\u{15}', '\u{9E}', '\u{1A}', ' ', '\u{A0}',
+    'â', 'ä', 'à', 'á', 'ã', 'å', 'ç', 'ñ', '¢', '.', '<', '(', '+', '|', '&', 'é', 'ê', 'ë', 'è',
+    'í', 'î', 'ï', 'ì', 'ß', '!', '$', '*', ')', ';', '¬', '-', '/', 'Â', 'Ä', 'À', 'Á', 'Ã', 'Å',
+    'Ç', 'Ñ', '¦', ',', '%', '_', '>', '?', 'ø', 'É', 'Ê', 'Ë', 'È', 'Í', 'Î', 'Ï', 'Ì', '`', ':',
+    '#', '@', '\'', '=', '"', 'Ø', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', '«', '»', 'ð', 'ý',
+    'þ', '±', '°', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 'ª', 'º', 'æ', '¸', 'Æ', '¤', 'µ',
+    '~', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z', '¡', '¿', 'Ð', 'Ý', 'Þ', '®', '^', '£', '¥', '·',
+    '©', '§', '¶', '¼', '½', '¾', '[', ']', '¯', '¨', '´', '×', '{', 'A', 'B', 'C', 'D', 'E', 'F',
+    'G', 'H', 'I', '\u{AD}', 'ô', 'ö', 'ò', 'ó', 'õ', '}', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q',
+    'R', '¹', 'û', 'ü', 'ù', 'ú', 'ÿ', '\\', '÷', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z', '²', 'Ô',
+    'Ö', 'Ò', 'Ó', 'Õ', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', '³', 'Û', 'Ü', 'Ù', 'Ú',
+    '\u{9F}',
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check::{check_with, CheckOptions};
+
+    fn preview(data: &[u8], encoding: &str) -> String {
+        let config = Config::default().with_preview_encoding(encoding.parse().unwrap());
+        preview_text(data, &config)
+    }
+
+    #[test]
+    fn test_single_byte_encodings() {
+        let data = b"a[b]\x00\x7F\xE9";
+        assert_eq!(preview(data, "ascii"), "a.b....");
+        assert_eq!(template_text(data, &Config::default()), "a[b]...");
+        assert_eq!(preview(data, "latin1"), "a.b...é");
+        assert_eq!(preview(b"\x80\xDB\xFF", "cp437"), "Ç█\u{A0}");
+        assert_eq!(
+            preview(b"\xCF\xF0\xE8\xE2\xE5\xF2\x98", "cp1251"),
+            "Привет."
+        );
+        assert_eq!(preview(b"\xF0\xD2\xC9\xD7\xC5\xD4", "koi8-r"), "Привет");
+        assert_eq!(
+            preview(b"\xC8\xC5\xD3\xD3\xD6\x40\x25", "ebcdic-037"),
+            "HELLO ."
+        );
+    }
+
+    #[test]
+    fn test_multi_byte_encodings() {
+        let data = "Añ€😀".as_bytes();
+        assert_eq!(preview(data, "utf8"), "Añ €  😀   ");
+        // a character split at the end of the line, a stray continuation byte
+        assert_eq!(preview(&data[..4], "utf8"), "Añ .");
+        assert_eq!(preview(&data[4..], "utf8"), "..😀   ");
+
+        let units: Vec<u16> = "A€😀".encode_utf16().collect();
+        let le: Vec<u8> = units.iter().flat_map(|unit| unit.to_le_bytes()).collect();
+        let be: Vec<u8> = units.iter().flat_map(|unit| unit.to_be_bytes()).collect();
+        assert_eq!(preview(&le, "utf16le"), "A € 😀   ");
+        assert_eq!(preview(&be, "utf16be"), "A € 😀   ");
+        // an unpaired surrogate and an odd byte at the end
+        assert_eq!(preview(&[0x3D, 0xD8, 0x41, 0x00, 0x42], "utf16le"), "..A .");
+    }
+
+    #[test]
+    fn test_placeholder_and_control_pictures() {
+        let config = Config::default()
+            .with_preview_placeholder('·')
+            .with_preview_control_pictures(true);
+        assert_eq!(preview_text(b"a\x00\n\x7F\xFF]", &config), "a␀␊␡··");
+
+        let config = config.with_preview_encoding(PreviewEncoding::Utf16Le);
+        assert_eq!(preview_text(b"\x0A\x00\x00\xDC", &config), "␊ ··");
+    }
+
+    #[test]
+    fn test_check_encodings() {
+        let data = "Привет, мир! [ok]\x00".as_bytes();
+        for encoding in ["cp1251", "utf8", "utf16be", "ebcdic-037"] {
+            let config = Config::default()
+                .with_preview_encoding(encoding.parse().unwrap())
+                .with_preview_control_pictures(true)
+                .with_line_length(8);
+            let mut hex = Vec::new();
+            crate::convert::bin_to_hex(data, &mut hex, &config).unwrap();
+
+            // the header declares the encoding, so the checker renders the same previews
+            let options = CheckOptions::default().with_text(true);
+            let report = check_with(&hex[..], &Config::default(), &options);
+            assert!(report.diagnostics.is_empty(), "{encoding}: {report:?}");
+        }
+
+        let hex = "#option preview_encoding=utf8\n00000000: D0 9F 21 [П!]\n";
+        let report = check_with(
+            hex.as_bytes(),
+            &Config::default(),
+            &CheckOptions::default().with_text(true),
+        );
+        assert_eq!(
+            report.diagnostics[0].to_string(),
+            "line 2, column 20: error: preview [П!] does not match data [П !]"
+        );
+    }
+}
@@ -0,0 +1,437 @@
+//! Layered configuration
+//!
+//! Options are taken from these layers, each one overriding the ones before:
+//!
+//! 1. the defaults of [`Config`]
+//! 2. a configuration file, with the options of a selected profile over the others
+//! 3. `#option` lines of the hex file being read
+//! 4. `HEXYG_*` environment variables, such as `HEXYG_LINE_LENGTH=8`
+//! 5. command line arguments
+//!
+//! The configuration file is `hexyg.toml`, `hexyg.json`, `hexyg.ini` or `.hexygrc`
+//! in the working directory or the nearest directory above it that has one. A
+//! `.hexygrc` holds JSON, TOML or INI. Profiles are the tables `[profile.NAME]`.
+//! The options of the environment and the command line are kept in
+//! [`Config::overrides`], so `#option` lines do not change them.
+
+use crate::config::OptionValue;
+use crate::options::{self, OPTIONS};
+use crate::{Config, Error, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Names of configuration files, in the order they are looked for in a directory
+pub const CONFIG_FILE_NAMES: &[&str] = &["hexyg.toml", "hexyg.json", "hexyg.ini", ".hexygrc"];
+
+/// Prefix of the environment variables of options
+pub const ENV_PREFIX: &str = "HEXYG_";
+
+/// Environment variable with the path of the configuration file
+pub const ENV_CONFIG: &str = "HEXYG_CONFIG";
+
+/// Environment variable with the name of the profile
+pub const ENV_PROFILE: &str = "HEXYG_PROFILE";
+
+/// Layer an option value comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Layer {
+    /// Default of [`Config`]
+    Default,
+    /// Configuration file, and the profile the option is in
+    File {
+        path: PathBuf,
+        profile: Option<String>,
+    },
+    /// Environment variable
+    Environment(String),
+    /// Command line argument
+    CommandLine,
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Layer::Default => write!(f, "default"),
+            Layer::File {
+                path,
+                profile: None,
+            } => write!(f, "{}", path.display()),
+            Layer::File {
+                path,
+                profile: Some(profile),
+            } => write!(f, "{} [profile.{}]", path.display(), profile),
+            Layer::Environment(name) => write!(f, "environment {}", name),
+            Layer::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// Options of a configuration file
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    profile: BTreeMap<String, BTreeMap<String, OptionValue>>,
+    #[serde(flatten)]
+    options: BTreeMap<String, OptionValue>,
+}
+
+/// Configuration built from layers, with the layer of every option
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    config: Config,
+    /// Layer that set each option last; options that are not here are defaults
+    layers: BTreeMap<String, Layer>,
+    /// Problems that did not stop loading, such as unknown environment variables
+    warnings: Vec<String>,
+}
+
+impl Settings {
+    /// Settings with the default options
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Settings of the configuration file and the environment
+    ///
+    /// The file is `config_file` or else the one in `HEXYG_CONFIG`, or else the one
+    /// found from `dir` upwards. The profile is `profile` or else the one in
+    /// `HEXYG_PROFILE`.
+    pub fn load<I>(
+        config_file: Option<&Path>,
+        profile: Option<&str>,
+        dir: &Path,
+        env: I,
+    ) -> Result<Self>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let env: Vec<(String, String)> = env
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+        let var = |var: &str| {
+            env.iter()
+                .find(|(name, _)| name == var)
+                .map(|(_, value)| value.as_str())
+        };
+
+        let mut settings = Settings::new();
+        let path = match config_file.or(var(ENV_CONFIG).map(Path::new)) {
+            Some(path) => Some(path.to_path_buf()),
+            None => find_config_file(dir),
+        };
+        let profile = profile.or(var(ENV_PROFILE));
+        match path {
+            Some(path) => settings.load_file(&path, profile)?,
+            None if profile.is_some() => {
+                return Err(Error::Config(format!(
+                    "profile '{}' is selected, but there is no configuration file",
+                    profile.unwrap_or_default()
+                )))
+            }
+            None => {}
+        }
+        settings.apply_env(env)?;
+        Ok(settings)
+    }
+
+    /// Apply the options of a configuration file and of one of its profiles
+    pub fn load_file(&mut self, path: &Path, profile: Option<&str>) -> Result<()> {
+        let text = std::fs::read_to_string(path)?;
+        let file = parse_config_file(path, &text)
+            .map_err(|message| Error::Config(format!("{}: {}", path.display(), message)))?;
+        let layer = |profile: Option<&str>| Layer::File {
+            path: path.to_path_buf(),
+            profile: profile.map(str::to_string),
+        };
+        for (name, value) in &file.options {
+            self.apply(name, &value.to_string(), layer(None))?;
+        }
+        if let Some(profile) = profile {
+            let options = file.profile.get(profile).ok_or_else(|| {
+                Error::Config(format!(
+                    "{}: no profile '{}', expected one of: {}",
+                    path.display(),
+                    profile,
+                    file.profile.keys().cloned().collect::<Vec<_>>().join(", ")
+                ))
+            })?;
+            for (name, value) in options {
+                self.apply(name, &value.to_string(), layer(Some(profile)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply `HEXYG_*` environment variables of options
+    ///
+    /// Variables of other programs are skipped. Unknown `HEXYG_*` ones are skipped
+    /// with a warning, as they may belong to another tool; invalid values of known
+    /// options are errors.
+    pub fn apply_env<I>(&mut self, env: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        for (var, value) in env {
+            let Some(name) = var.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            if var == ENV_CONFIG || var == ENV_PROFILE {
+                continue;
+            }
+            let name = name.to_ascii_lowercase();
+            let layer = Layer::Environment(var.clone());
+            if options::find(&name).is_none() {
+                let message = options::unknown_message(&name);
+                self.warnings
+                    .push(format!("{} ({}), ignored", message, layer));
+                continue;
+            }
+            self.apply(&name, &value, layer)?;
+        }
+        Ok(())
+    }
+
+    /// Apply an option of a layer
+    ///
+    /// Only the command line passes unknown options through to `extra_options`; in
+    /// files and the environment they are errors, with a suggestion for misspelled
+    /// names.
+    pub fn apply(&mut self, name: &str, value: &str, layer: Layer) -> Result<()> {
+        let context = |err: Error| match err {
+            Error::Config(message) if layer != Layer::Default => {
+                Error::Config(format!("{} ({})", message, layer))
+            }
+            err => err,
+        };
+        let name = match options::find(name) {
+            Some(spec) => spec.name,
+            None if layer == Layer::CommandLine => name,
+            None => return Err(context(options::unknown(name))),
+        };
+        self.config.apply_option(name, value).map_err(context)?;
+        if matches!(layer, Layer::Environment(_) | Layer::CommandLine) {
+            let overrides = &mut self.config.overrides;
+            overrides.retain(|(n, _)| n != name);
+            overrides.push((name.to_string(), value.to_string()));
+        }
+        self.layers.insert(name.to_string(), layer);
+        Ok(())
+    }
+
+    /// Effective options
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn into_config(self) -> Config {
+        self.config
+    }
+
+    /// Problems that did not stop loading, to be shown to the user
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Effective options with their values and the layers that set them
+    pub fn explain(&self) -> Vec<(String, String, Layer)> {
+        let extra = self
+            .config
+            .extra_options
+            .iter()
+            .map(|(name, _)| name.as_str());
+        OPTIONS
+            .iter()
+            .map(|spec| spec.name)
+            .chain(extra)
+            .map(|name| {
+                let value = self
+                    .config
+                    .option_value(name)
+                    .or_else(|| {
+                        let extra = self.config.extra_options.iter();
+                        extra
+                            .filter(|(n, _)| n == name)
+                            .map(|(_, value)| value.clone())
+                            .next()
+                    })
+                    .unwrap_or_default();
+                let layer = self.layers.get(name).cloned().unwrap_or(Layer::Default);
+                (name.to_string(), value, layer)
+            })
+            .collect()
+    }
+}
+
+/// Configuration file in `dir` or the nearest directory above it
+pub fn find_config_file(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .flat_map(|dir| CONFIG_FILE_NAMES.iter().map(move |name| dir.join(name)))
+        .find(|path| path.is_file())
+}
+
+/// Options of a configuration file in the format of its name or content
+fn parse_config_file(path: &Path, text: &str) -> std::result::Result<ConfigFile, String> {
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    match extension {
+        Some("toml") => toml::from_str(text).map_err(|err| err.to_string()),
+        Some("json") => serde_json::from_str(text).map_err(|err| err.to_string()),
+        Some("ini") => parse_ini(text),
+        _ if text.trim_start().starts_with('{') => {
+            serde_json::from_str(text).map_err(|err| err.to_string())
+        }
+        // TOML and INI look alike; values of INI need no quotes
+        _ => toml::from_str(text).or_else(|_| parse_ini(text)),
+    }
+}
+
+/// Options of an INI file; sections are profiles
+fn parse_ini(text: &str) -> std::result::Result<ConfigFile, String> {
+    let ini = ini::Ini::load_from_str(text).map_err(|err| err.to_string())?;
+    let mut file = ConfigFile::default();
+    for (section, properties) in ini.iter() {
+        let options = match section {
+            None => &mut file.options,
+            Some(section) => {
+                let profile = section.strip_prefix("profile.").ok_or_else(|| {
+                    format!("unknown section [{}], expected [profile.NAME]", section)
+                })?;
+                file.profile.entry(profile.to_string()).or_default()
+            }
+        };
+        for (name, value) in properties.iter() {
+            options.insert(name.to_string(), OptionValue::Text(value.to_string()));
+        }
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Padding;
+    use std::fs;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_config_file_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = [
+            (
+                "hexyg.toml",
+                "line_length = 8\npadding = \"FF\"\n[profile.wide]\nline_length = 32\n",
+            ),
+            (
+                "hexyg.json",
+                r#"{"line_length": 8, "padding": "FF", "profile": {"wide": {"line_length": 32}}}"#,
+            ),
+            (
+                "hexyg.ini",
+                "line_length = 8\npadding = FF\n[profile.wide]\nline_length = 32\n",
+            ),
+            (
+                ".hexygrc",
+                "line_length = 8\npadding = FF\n[profile.wide]\nline_length = 32\n",
+            ),
+        ];
+        for (name, text) in files {
+            let path = dir.path().join(name);
+            fs::write(&path, text).unwrap();
+
+            let mut settings = Settings::new();
+            settings.load_file(&path, None).unwrap();
+            assert_eq!(settings.config().line_length, 8, "{name}");
+            assert_eq!(settings.config().padding, Padding::Value(0xFF), "{name}");
+
+            settings.load_file(&path, Some("wide")).unwrap();
+            assert_eq!(settings.config().line_length, 32, "{name}");
+            assert!(settings.load_file(&path, Some("narrow")).is_err(), "{name}");
+            fs::remove_file(&path).unwrap();
+        }
+
+        let path = dir.path().join("hexyg.toml");
+        fs::write(&path, "line_length = 0\n").unwrap();
+        assert!(matches!(
+            Settings::new().load_file(&path, None),
+            Err(Error::Config(_))
+        ));
+        fs::write(&path, "line_lenght = 8\n").unwrap();
+        let err = Settings::new().load_file(&path, None).unwrap_err();
+        assert!(err.to_string().contains("did you mean 'line_length'?"));
+    }
+
+    #[test]
+    fn test_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("a/b");
+        fs::create_dir_all(&nested).unwrap();
+        let path = dir.path().join("hexyg.toml");
+        fs::write(
+            &path,
+            "line_length = 8\nblock_length = 4\n[profile.wide]\nline_length = 32\n",
+        )
+        .unwrap();
+        assert_eq!(find_config_file(&nested), Some(path.clone()));
+
+        let vars = env(&[
+            ("HEXYG_PROFILE", "wide"),
+            ("HEXYG_PADDING", "FF"),
+            ("PATH", "/bin"),
+        ]);
+        let mut settings = Settings::load(None, None, &nested, vars).unwrap();
+        settings
+            .apply("block_length", "2", Layer::CommandLine)
+            .unwrap();
+
+        let explain = settings.explain();
+        let layer = |option: &str| {
+            explain
+                .iter()
+                .find(|(name, _, _)| name == option)
+                .map(|(_, value, layer)| (value.as_str(), layer.clone()))
+                .unwrap()
+        };
+        let file = |profile: Option<&str>| Layer::File {
+            path: path.clone(),
+            profile: profile.map(str::to_string),
+        };
+        assert_eq!(layer("line_length"), ("32", file(Some("wide"))));
+        assert_eq!(
+            layer("padding"),
+            ("FF", Layer::Environment("HEXYG_PADDING".to_string()))
+        );
+        assert_eq!(layer("block_length"), ("2", Layer::CommandLine));
+        assert_eq!(layer("endian"), ("LITTLE_ENDIAN", Layer::Default));
+
+        // the environment and the command line win over #option lines
+        let mut config = settings.into_config();
+        config.apply_directive("padding", "00").unwrap();
+        config.apply_directive("line_length", "4").unwrap();
+        assert_eq!(config.padding, Padding::Value(0xFF));
+        assert_eq!(config.line_length, 4);
+
+        // unknown variables are skipped with a warning, invalid values are errors
+        let vars = env(&[("HEXYG_LINE_LENGHT", "8"), ("HEXYG_FOO", "1")]);
+        let settings = Settings::load(None, None, &nested, vars).unwrap();
+        assert_eq!(
+            settings.warnings(),
+            [
+                "unknown option 'line_lenght', did you mean 'line_length'? \
+                 (environment HEXYG_LINE_LENGHT), ignored",
+                "unknown option 'foo' (environment HEXYG_FOO), ignored",
+            ]
+        );
+        assert_eq!(settings.config().line_length, 8);
+        let vars = env(&[("HEXYG_LINE_LENGTH", "0")]);
+        assert!(Settings::load(None, None, &nested, vars).is_err());
+        let vars = env(&[("HEXYG_PROFILE", "wide")]);
+        assert!(Settings::load(None, None, Path::new("/"), vars).is_err());
+    }
+}
@@ -0,0 +1,141 @@
+//! Address windows given by `--from`/`--to` positions
+//!
+//! A position is a decimal or `0x`-prefixed hex number. A negative position counts
+//! from the end of the data, as slices do in Python.
+
+use crate::{Error, Result};
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Position of a byte, counted from the start or from the end of the data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    /// `n` bytes after the start
+    Start(u64),
+    /// `n` bytes before the end
+    End(u64),
+}
+
+impl Position {
+    /// Address of the position in data of `len` bytes
+    pub fn resolve(self, len: u64) -> Result<u64> {
+        let address = match self {
+            Position::Start(offset) => Some(offset).filter(|&offset| offset <= len),
+            Position::End(offset) => len.checked_sub(offset),
+        };
+        address.ok_or_else(|| {
+            Error::OutOfRange(format!(
+                "address {} is outside of the data of {} (0x{:X}) bytes",
+                self, len, len
+            ))
+        })
+    }
+}
+
+impl FromStr for Position {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let text = s.trim();
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+        let value = match digits
+            .strip_prefix("0x")
+            .or_else(|| digits.strip_prefix("0X"))
+        {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => digits.parse(),
+        };
+        match value {
+            Ok(value) if negative => Ok(Position::End(value)),
+            Ok(value) => Ok(Position::Start(value)),
+            Err(_) => Err(Error::Config(format!(
+                "invalid address '{}': expected a decimal or 0x-prefixed hex number",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Position::Start(offset) => write!(f, "{}", offset),
+            Position::End(offset) => write!(f, "-{}", offset),
+        }
+    }
+}
+
+/// Window of data between two positions; the end is exclusive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Slice {
+    /// First byte, the start of the data if `None`
+    pub from: Option<Position>,
+    /// Byte after the last one, the end of the data if `None`
+    pub to: Option<Position>,
+}
+
+impl Slice {
+    pub fn new(from: Option<Position>, to: Option<Position>) -> Self {
+        Slice { from, to }
+    }
+
+    /// Whether the slice is the whole data
+    pub fn is_full(&self) -> bool {
+        self.from.is_none() && self.to.is_none()
+    }
+
+    /// Whether the length of the data is needed to find the window
+    pub fn is_end_relative(&self) -> bool {
+        matches!(self.from, Some(Position::End(_))) || matches!(self.to, Some(Position::End(_)))
+    }
+
+    /// Addresses of the window in data of `len` bytes
+    ///
+    /// A slice that is not end-relative can be resolved for data of unknown length
+    /// with `u64::MAX`; an end of `u64::MAX` then means the end of the data.
+    pub fn resolve(&self, len: u64) -> Result<Range<u64>> {
+        let start = self.from.map_or(Ok(0), |from| from.resolve(len))?;
+        let end = self.to.map_or(Ok(len), |to| to.resolve(len))?;
+        if start > end {
+            return Err(Error::OutOfRange(format!(
+                "start address {} is after end address {}",
+                start, end
+            )));
+        }
+        Ok(start..end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_position() {
+        assert_eq!("987".parse::<Position>().unwrap(), Position::Start(987));
+        assert_eq!("0x4FE".parse::<Position>().unwrap(), Position::Start(0x4FE));
+        assert_eq!("-16".parse::<Position>().unwrap(), Position::End(16));
+        assert_eq!("-0x10".parse::<Position>().unwrap(), Position::End(16));
+        assert!("4FE".parse::<Position>().is_err());
+        assert!("".parse::<Position>().is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let slice =
+            |from: &str, to: &str| Slice::new(from.parse().ok(), to.parse().ok()).resolve(100);
+        assert_eq!(slice("", "").unwrap(), 0..100);
+        assert_eq!(slice("10", "0x20").unwrap(), 10..32);
+        assert_eq!(slice("-10", "").unwrap(), 90..100);
+        assert_eq!(slice("", "-1").unwrap(), 0..99);
+        assert_eq!(slice("100", "").unwrap(), 100..100);
+        assert!(matches!(slice("101", ""), Err(Error::OutOfRange(_))));
+        assert!(matches!(slice("-101", ""), Err(Error::OutOfRange(_))));
+        assert!(matches!(slice("", "200"), Err(Error::OutOfRange(_))));
+        assert!(matches!(slice("50", "-60"), Err(Error::OutOfRange(_))));
+    }
+}
@@ -0,0 +1,399 @@
+//! Lossless concrete syntax tree of the hexyg text format
+//!
+//! [`parse`] groups the tokens of the [`Lexer`] into nodes for directives, data lines
+//! and annotations. Whitespace, newlines and comments stay in the tree as tokens, so
+//! printing the tree gives back the input byte for byte.
+//!
+//! The tree only describes structure and never fails: text that does not fit the
+//! grammar becomes an [`NodeKind::Error`] node up to the end of its line. Semantic
+//! checks (addresses, options, struct definitions) are left to [`crate::parser`].
+
+use crate::lexer::{Lexer, Span, TokenKind};
+use std::fmt;
+
+/// Kinds of syntax nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    /// Whole file
+    Root,
+    /// `#option name=value` with a trailing comment
+    Option,
+    /// `#struct name { ... }` with a trailing comment, possibly several lines
+    Struct,
+    /// Address, hex data, preview, annotations and comments of one line
+    DataLine,
+    /// `00000100:`
+    Address,
+    /// Hex sequences with the whitespace between them
+    HexData,
+    /// `|` and the annotations after it
+    Annotations,
+    /// `+04(name):u16=3`
+    Annotation,
+    /// Text that does not fit the grammar, up to the end of line
+    Error,
+}
+
+/// Token with its text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxToken {
+    pub kind: TokenKind,
+    pub text: String,
+    pub span: Span,
+}
+
+/// Child of a [`SyntaxNode`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+/// Node of the syntax tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxNode {
+    pub kind: NodeKind,
+    pub children: Vec<SyntaxElement>,
+}
+
+impl SyntaxNode {
+    fn new(kind: NodeKind) -> Self {
+        SyntaxNode {
+            kind,
+            children: Vec::new(),
+        }
+    }
+
+    /// All tokens of the node in source order
+    pub fn tokens(&self) -> Vec<&SyntaxToken> {
+        let mut tokens = Vec::new();
+        self.collect_tokens(&mut tokens);
+        tokens
+    }
+
+    fn collect_tokens<'a>(&'a self, tokens: &mut Vec<&'a SyntaxToken>) {
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => node.collect_tokens(tokens),
+                SyntaxElement::Token(token) => tokens.push(token),
+            }
+        }
+    }
+
+    /// Direct child nodes of the given kind
+    pub fn nodes(&self, kind: NodeKind) -> impl Iterator<Item = &SyntaxNode> + '_ {
+        self.children.iter().filter_map(move |child| match child {
+            SyntaxElement::Node(node) if node.kind == kind => Some(node),
+            _ => None,
+        })
+    }
+
+    /// Source text of the node
+    pub fn text(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for SyntaxNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for token in self.tokens() {
+            f.write_str(&token.text)?;
+        }
+        Ok(())
+    }
+}
+
+/// Build the syntax tree of a source text
+pub fn parse(src: &str) -> SyntaxNode {
+    let lexer = Lexer::new(src);
+    let tokens = lexer
+        .map(|token| SyntaxToken {
+            kind: token.kind,
+            text: src[token.span.start..token.span.end].to_string(),
+            span: token.span,
+        })
+        .collect();
+    let mut builder = Builder { tokens, pos: 0 };
+    builder.root()
+}
+
+struct Builder {
+    tokens: Vec<SyntaxToken>,
+    pos: usize,
+}
+
+impl Builder {
+    fn peek(&self) -> Option<TokenKind> {
+        self.tokens.get(self.pos).map(|token| token.kind)
+    }
+
+    /// Kind of the first token after the next `skip` that is not whitespace or a comment
+    fn peek_past_trivia(&self, skip: usize) -> Option<TokenKind> {
+        self.tokens[self.pos + skip..]
+            .iter()
+            .map(|token| token.kind)
+            .find(|kind| !kind.is_trivia())
+    }
+
+    fn bump(&mut self, node: &mut SyntaxNode) {
+        node.children
+            .push(SyntaxElement::Token(self.tokens[self.pos].clone()));
+        self.pos += 1;
+    }
+
+    /// Move whitespace and comments into `node`, and newlines as well if asked
+    fn trivia(&mut self, node: &mut SyntaxNode, newlines: bool) {
+        while let Some(kind) = self.peek() {
+            if !(kind.is_trivia() || newlines && kind == TokenKind::Newline) {
+                break;
+            }
+            self.bump(node);
+        }
+    }
+
+    /// Move the rest of the line into `node`
+    fn rest_of_line(&mut self, node: &mut SyntaxNode) {
+        while self.peek().is_some_and(|kind| kind != TokenKind::Newline) {
+            self.bump(node);
+        }
+    }
+
+    /// Move the rest of the line into an error node of `parent`
+    fn error(&mut self, parent: &mut SyntaxNode) {
+        let mut error = SyntaxNode::new(NodeKind::Error);
+        self.rest_of_line(&mut error);
+        if !error.children.is_empty() {
+            parent.children.push(SyntaxElement::Node(error));
+        }
+    }
+
+    /// Consume a token of the given kinds into `node`
+    fn expect(&mut self, node: &mut SyntaxNode, kinds: &[TokenKind]) -> bool {
+        match self.peek() {
+            Some(kind) if kinds.contains(&kind) => {
+                self.bump(node);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn root(&mut self) -> SyntaxNode {
+        let mut root = SyntaxNode::new(NodeKind::Root);
+        while let Some(kind) = self.peek() {
+            if kind.is_trivia() || kind == TokenKind::Newline {
+                self.bump(&mut root);
+                continue;
+            }
+            let node = match kind {
+                TokenKind::DirectiveOption => {
+                    let mut node = SyntaxNode::new(NodeKind::Option);
+                    self.rest_of_line(&mut node);
+                    node
+                }
+                TokenKind::DirectiveStruct => self.structure(),
+                TokenKind::HexSequence | TokenKind::Preview | TokenKind::Pipe => self.data_line(),
+                _ => {
+                    let mut node = SyntaxNode::new(NodeKind::Error);
+                    self.rest_of_line(&mut node);
+                    node
+                }
+            };
+            root.children.push(SyntaxElement::Node(node));
+        }
+        root
+    }
+
+    /// `#struct` up to the closing brace and the end of its line
+    fn structure(&mut self) -> SyntaxNode {
+        let mut node = SyntaxNode::new(NodeKind::Struct);
+        while let Some(kind) = self.peek() {
+            self.bump(&mut node);
+            if kind == TokenKind::RBrace {
+                break;
+            }
+        }
+        self.rest_of_line(&mut node);
+        node
+    }
+
+    fn data_line(&mut self) -> SyntaxNode {
+        let mut line = SyntaxNode::new(NodeKind::DataLine);
+        let is_address = self.peek() == Some(TokenKind::HexSequence)
+            && self.peek_past_trivia(1) == Some(TokenKind::Colon);
+        if is_address {
+            let mut address = SyntaxNode::new(NodeKind::Address);
+            self.bump(&mut address);
+            self.trivia(&mut address, false);
+            self.bump(&mut address);
+            line.children.push(SyntaxElement::Node(address));
+        }
+
+        loop {
+            self.trivia(&mut line, false);
+            if self.peek() != Some(TokenKind::HexSequence) {
+                break;
+            }
+            let mut data = SyntaxNode::new(NodeKind::HexData);
+            self.bump(&mut data);
+            while self.peek() == Some(TokenKind::Whitespace)
+                && self.tokens.get(self.pos + 1).map(|token| token.kind)
+                    == Some(TokenKind::HexSequence)
+            {
+                self.bump(&mut data);
+                self.bump(&mut data);
+            }
+            line.children.push(SyntaxElement::Node(data));
+        }
+
+        self.expect(&mut line, &[TokenKind::Preview]);
+        self.trivia(&mut line, false);
+        if self.peek() == Some(TokenKind::Pipe) {
+            let mut annotations = SyntaxNode::new(NodeKind::Annotations);
+            self.bump(&mut annotations);
+            loop {
+                self.trivia(&mut annotations, false);
+                match self.peek() {
+                    None | Some(TokenKind::Newline) => break,
+                    Some(_) => match self.annotation() {
+                        Some(annotation) => {
+                            annotations.children.push(SyntaxElement::Node(annotation))
+                        }
+                        None => {
+                            self.error(&mut annotations);
+                            break;
+                        }
+                    },
+                }
+            }
+            line.children.push(SyntaxElement::Node(annotations));
+        }
+        self.error(&mut line);
+        line
+    }
+
+    /// `(+offset)? ((name))? :type=value` or `type=value`; `None` leaves the
+    /// position unchanged
+    fn annotation(&mut self) -> Option<SyntaxNode> {
+        let start = self.pos;
+        let mut node = SyntaxNode::new(NodeKind::Annotation);
+        let parsed = self.annotation_parts(&mut node);
+        if parsed.is_none() {
+            self.pos = start;
+        }
+        parsed.map(|_| node)
+    }
+
+    fn annotation_parts(&mut self, node: &mut SyntaxNode) -> Option<()> {
+        const NAME: &[TokenKind] = &[TokenKind::Identifier, TokenKind::HexSequence];
+        if self.expect(node, &[TokenKind::Plus]) {
+            self.trivia(node, false);
+            self.expect(node, &[TokenKind::HexSequence]).then_some(())?;
+            self.trivia(node, false);
+        }
+        if self.expect(node, &[TokenKind::LParen]) {
+            self.trivia(node, false);
+            self.expect(node, NAME).then_some(())?;
+            self.trivia(node, false);
+            self.expect(node, &[TokenKind::RParen]).then_some(())?;
+            self.trivia(node, false);
+        }
+        if self.expect(node, &[TokenKind::Colon]) {
+            self.trivia(node, false);
+        }
+        self.expect(node, NAME).then_some(())?;
+        self.trivia(node, false);
+        self.expect(node, &[TokenKind::Equals]).then_some(())?;
+        self.trivia(node, false);
+        self.value(node)
+    }
+
+    /// Integer, string or struct value; struct values may span several lines
+    fn value(&mut self, node: &mut SyntaxNode) -> Option<()> {
+        if self.expect(node, &[TokenKind::HexSequence, TokenKind::String]) {
+            return Some(());
+        }
+        self.expect(node, &[TokenKind::LBrace]).then_some(())?;
+        loop {
+            self.trivia(node, true);
+            if self.expect(node, &[TokenKind::RBrace]) {
+                return Some(());
+            }
+            self.expect(node, &[TokenKind::Identifier, TokenKind::HexSequence])
+                .then_some(())?;
+            self.trivia(node, true);
+            self.expect(node, &[TokenKind::Equals]).then_some(())?;
+            self.trivia(node, true);
+            self.value(node)?;
+            self.trivia(node, true);
+            self.expect(node, &[TokenKind::Comma]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(node: &SyntaxNode) -> Vec<NodeKind> {
+        node.children
+            .iter()
+            .filter_map(|child| match child {
+                SyntaxElement::Node(node) => Some(node.kind),
+                SyntaxElement::Token(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lossless_round_trip() {
+        let inputs = [
+            include_str!("../../../tests/corpus/valid/01_reference.hex"),
+            "",
+            "00: 01 02 /* inline */ 03 [...]   | +0 : u8 = 1   u16=2 // tail  \r\n",
+            "#struct s {\n  a:u8, // first\n  b:u8 }  \n00: 0102 | s={a=1,\n b=2}\n",
+            "#unknown x\n00: zz 11 | +:u8\n[ab\n\"open\n/* never closed\n",
+            "00: 01 \u{00E9} 02\n\t\t\n",
+        ];
+        for input in inputs {
+            assert_eq!(parse(input).text(), input);
+        }
+    }
+
+    #[test]
+    fn test_structure() {
+        let root = parse(
+            "#option a=b // c\n#struct s { a:u8 }\n00: 01 02 [..] | u8=1 +1:u8=2\n  03\n#bad\n",
+        );
+        assert_eq!(
+            kinds(&root),
+            vec![
+                NodeKind::Option,
+                NodeKind::Struct,
+                NodeKind::DataLine,
+                NodeKind::DataLine,
+                NodeKind::Error
+            ]
+        );
+
+        let line = root.nodes(NodeKind::DataLine).next().unwrap();
+        assert_eq!(
+            kinds(line),
+            vec![NodeKind::Address, NodeKind::HexData, NodeKind::Annotations]
+        );
+        let annotations: Vec<String> = line
+            .nodes(NodeKind::Annotations)
+            .flat_map(|node| node.nodes(NodeKind::Annotation))
+            .map(SyntaxNode::text)
+            .collect();
+        assert_eq!(annotations, vec!["u8=1", "+1:u8=2"]);
+
+        let root = parse("00: 01 | u8=1 +:u8=2 // c\n");
+        let line = root.nodes(NodeKind::DataLine).next().unwrap();
+        let annotations = line.nodes(NodeKind::Annotations).next().unwrap();
+        assert_eq!(
+            kinds(annotations),
+            vec![NodeKind::Annotation, NodeKind::Error]
+        );
+    }
+}
@@ -0,0 +1,633 @@
+//! Line templates for text in the layouts of other tools
+//!
+//! A line template is text with fields in braces, such as
+//! `{addr:08X}: {hex:block=4} |{ascii}|`; `{{` and `}}` are literal braces.
+//!
+//! - `{addr}` – address of the line; `{addr:08x}` is zero-padded to 8 digits,
+//!   `x` and `X` select the case. Without a width it is as wide as `address_size`.
+//! - `{hex}` – bytes of the line; options after `:` are separated with commas:
+//!   `x` or `X` for the case, `block=N` bytes per block, `sep=N` and `gap=N`
+//!   spaces between bytes and between blocks. Options that are not given are
+//!   taken from `block_length`, `byte_separator` and `block_separator`. The hex of
+//!   a short line is padded to the width of a full line.
+//! - `{ascii}` – the bytes as ASCII, `.` for non-printable ones.
+//!
+//! A format file describes a whole file: `line=` is the line template, `header=`
+//! lines are written before the data and `footer=` after it, where `{end}` is the
+//! address after the last byte. Empty lines and lines starting with `#` are ignored.
+//!
+//! Text written with a template is read back with [`Template::read`]; `*` lines of
+//! `hexdump` and `od`, which stand for repeats of the line before, are expanded.
+
+use crate::config::Radix;
+use crate::convert::{advance, columns, hex_text, AddressedWriter, HexLine, HexWriter};
+use crate::lexer::Span;
+use crate::preview::template_text;
+use crate::{Config, Error, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::str::FromStr;
+
+/// Built-in templates: name and format file
+pub const PRESETS: &[(&str, &str)] = &[
+    // xxd
+    (
+        "xxd",
+        "line={addr:08x}: {hex:x,block=2,sep=0,gap=1}  {ascii}",
+    ),
+    // hexdump -C -v
+    (
+        "hexdump",
+        "line={addr:08x}  {hex:x,block=8,sep=1,gap=2}  |{ascii}|\nfooter={end:08x}",
+    ),
+    // od -Ax -tx1 -v
+    (
+        "od",
+        "line={addr:06x} {hex:x,block=0,sep=1}\nfooter={end:06x}",
+    ),
+];
+
+/// Layout of the lines of a text file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    header: Vec<String>,
+    line: Vec<Item>,
+    footer: Option<Vec<Item>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Item {
+    Text(String),
+    Address(AddressSpec),
+    End(AddressSpec),
+    Hex(HexSpec),
+    Ascii,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct AddressSpec {
+    width: Option<usize>,
+    zero: bool,
+    lower: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct HexSpec {
+    lower: bool,
+    block: Option<usize>,
+    sep: Option<usize>,
+    gap: Option<usize>,
+}
+
+impl Template {
+    /// Template of a line; a name from [`PRESETS`] selects a built-in template
+    pub fn line(template: &str) -> Result<Self> {
+        if let Some((_, file)) = PRESETS.iter().find(|(name, _)| *name == template) {
+            return Template::from_format_file(file);
+        }
+        Ok(Template {
+            header: Vec::new(),
+            line: parse_items(template, &["addr", "hex", "ascii"])?,
+            footer: None,
+        })
+    }
+
+    /// Template described by the text of a format file
+    pub fn from_format_file(text: &str) -> Result<Self> {
+        let mut header = Vec::new();
+        let mut line = None;
+        let mut footer = None;
+        for entry in text.lines() {
+            if entry.trim().is_empty() || entry.starts_with('#') {
+                continue;
+            }
+            let (key, value) = entry.split_once('=').ok_or_else(|| {
+                Error::Config(format!(
+                    "invalid format file line '{}': expected key=value",
+                    entry
+                ))
+            })?;
+            match key.trim() {
+                "header" => header.push(parse_literal(value)?),
+                "line" if line.is_none() => {
+                    line = Some(parse_items(value, &["addr", "hex", "ascii"])?)
+                }
+                "footer" if footer.is_none() => footer = Some(parse_items(value, &["end"])?),
+                "line" | "footer" => {
+                    return Err(Error::Config(format!("duplicate {} in format file", key)))
+                }
+                _ => {
+                    return Err(Error::Config(format!(
+                        "unknown key '{}' in format file: expected header, line or footer",
+                        key
+                    )))
+                }
+            }
+        }
+        let line = line.ok_or_else(|| Error::Config("format file has no line=".to_string()))?;
+        Ok(Template {
+            header,
+            line,
+            footer,
+        })
+    }
+
+    /// [`HexWriter`] that writes lines with the template
+    pub fn writer(&self) -> TemplateWriter<'_> {
+        TemplateWriter {
+            template: self,
+            config: Config::default(),
+            address_width: 0,
+        }
+    }
+
+    /// Read the bytes of text written with the template
+    ///
+    /// Bytes are placed at the addresses of their lines; gaps are filled with the
+    /// padding byte of `config`. Lines of the header are skipped, a line that matches
+    /// neither the line template nor the footer is an error.
+    pub fn read<R: Read, W: Write>(&self, reader: R, writer: W, config: &Config) -> Result<()> {
+        let mut output = AddressedWriter::new(writer, config.padding);
+        let mut repeated: Option<Vec<u8>> = None;
+        let mut previous = Vec::new();
+        let mut offset = 0;
+        for (number, text) in BufReader::new(reader).lines().enumerate() {
+            let text = text?;
+            let span = Span {
+                start: offset,
+                end: offset + text.len(),
+                line: number + 1,
+                column: 1,
+            };
+            offset += text.len() + 1;
+            let line = text.trim_end();
+            if line.is_empty() || self.header.iter().any(|header| header.trim_end() == line) {
+                continue;
+            }
+            if line == "*" {
+                repeated = Some(previous.clone());
+                continue;
+            }
+
+            let (address, data) = match self
+                .footer
+                .as_ref()
+                .and_then(|footer| match_line(footer, line, config))
+            {
+                Some(end) => (end.address, Vec::new()),
+                None => match match_line(&self.line, line, config) {
+                    Some(matched) => (matched.address, matched.data),
+                    None => return Err(Error::parse(span, "line does not match the template")),
+                },
+            };
+            if let Some(address) = address {
+                if let Some(data) = repeated.take() {
+                    repeat(&mut output, &data, address, span)?;
+                }
+                output.seek(address, span)?;
+            }
+            output.write_all(&data)?;
+            if !data.is_empty() {
+                previous = data;
+            }
+        }
+        output.get_mut().flush()?;
+        Ok(())
+    }
+}
+
+impl FromStr for Template {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Template::line(s)
+    }
+}
+
+/// Write copies of `data` up to `address`, as a `*` line stands for
+fn repeat<W: Write>(
+    output: &mut AddressedWriter<W>,
+    data: &[u8],
+    address: u64,
+    span: Span,
+) -> Result<()> {
+    if data.is_empty() {
+        return Err(Error::parse(span, "'*' without a line to repeat"));
+    }
+    while output.position() < address {
+        let count = (address - output.position()).min(data.len() as u64) as usize;
+        output.write_all(&data[..count])?;
+    }
+    Ok(())
+}
+
+/// [`HexWriter`] of a [`Template`]
+#[derive(Debug)]
+pub struct TemplateWriter<'a> {
+    template: &'a Template,
+    config: Config,
+    address_width: usize,
+}
+
+impl TemplateWriter<'_> {
+    /// Text of `items` for the bytes of a line, without trailing whitespace
+    fn render(&self, items: &[Item], address: Option<u64>, data: &[u8]) -> String {
+        let mut text = String::new();
+        for (i, item) in items.iter().enumerate() {
+            match item {
+                Item::Text(literal) => text.push_str(literal),
+                Item::Address(spec) | Item::End(spec) => {
+                    let width = spec.width.unwrap_or(self.address_width);
+                    match address {
+                        Some(address) => text.push_str(&spec.format(address, width)),
+                        None => text.push_str(&" ".repeat(width)),
+                    }
+                }
+                Item::Hex(spec) => {
+                    let start = columns(&text);
+                    text.push_str(&spec.text(data, &self.config));
+                    if i + 1 < items.len() {
+                        let full = spec.text(&vec![0; self.config.line_length], &self.config);
+                        let padding = advance(start, &full).saturating_sub(columns(&text));
+                        text.extend(std::iter::repeat_n(' ', padding));
+                    }
+                }
+                Item::Ascii => text.push_str(&template_text(data, &self.config)),
+            }
+        }
+        text.truncate(text.trim_end().len());
+        text
+    }
+}
+
+impl HexWriter for TemplateWriter<'_> {
+    fn begin(
+        &mut self,
+        writer: &mut dyn Write,
+        config: &Config,
+        address_width: usize,
+    ) -> Result<()> {
+        self.config = config.clone();
+        self.address_width = address_width;
+        for line in &self.template.header {
+            writeln!(writer, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    fn write_line(&mut self, writer: &mut dyn Write, line: &HexLine<'_>) -> Result<()> {
+        let address = Some(line.address).filter(|_| line.show_address);
+        writeln!(
+            writer,
+            "{}",
+            self.render(&self.template.line, address, line.data)
+        )?;
+        Ok(())
+    }
+
+    fn end(&mut self, writer: &mut dyn Write, end: u64) -> Result<()> {
+        if let Some(footer) = &self.template.footer {
+            writeln!(writer, "{}", self.render(footer, Some(end), &[]))?;
+        }
+        Ok(())
+    }
+}
+
+impl AddressSpec {
+    fn parse(spec: &str) -> Option<Self> {
+        let (digits, lower) = match spec.strip_suffix('x') {
+            Some(digits) => (digits, true),
+            None => (spec.strip_suffix('X').unwrap_or(spec), false),
+        };
+        Some(AddressSpec {
+            width: Some(digits)
+                .filter(|digits| !digits.is_empty())
+                .map(str::parse)
+                .transpose()
+                .ok()?,
+            zero: digits.starts_with('0'),
+            lower,
+        })
+    }
+
+    fn format(&self, address: u64, width: usize) -> String {
+        match (self.zero || self.width.is_none(), self.lower) {
+            (true, true) => format!("{:0width$x}", address),
+            (true, false) => format!("{:0width$X}", address),
+            (false, true) => format!("{:width$x}", address),
+            (false, false) => format!("{:width$X}", address),
+        }
+    }
+}
+
+impl HexSpec {
+    fn parse(spec: &str) -> Option<Self> {
+        let mut hex = HexSpec::default();
+        for option in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|option| !option.is_empty())
+        {
+            match option.split_once('=') {
+                None if option == "x" => hex.lower = true,
+                None if option == "X" => hex.lower = false,
+                Some(("block", n)) => hex.block = Some(n.trim().parse().ok()?),
+                Some(("sep", n)) => hex.sep = Some(n.trim().parse().ok()?),
+                Some(("gap", n)) => hex.gap = Some(n.trim().parse().ok()?),
+                _ => return None,
+            }
+        }
+        Some(hex)
+    }
+
+    /// Bytes per block and the separators of bytes and of blocks
+    fn separators(&self, config: &Config) -> (usize, String, String) {
+        let spaces =
+            |n: Option<usize>, separator: &str| n.map_or(separator.to_string(), |n| " ".repeat(n));
+        (
+            self.block.unwrap_or(config.block_length),
+            spaces(self.sep, &config.byte_separator),
+            spaces(self.gap, &config.block_separator),
+        )
+    }
+
+    fn text(&self, data: &[u8], config: &Config) -> String {
+        let (block, sep, gap) = self.separators(config);
+        let text = hex_text(data, Radix::Hex, 1, block, &sep, &gap);
+        match self.lower {
+            true => text.to_ascii_lowercase(),
+            false => text,
+        }
+    }
+
+    /// Read the bytes at the start of `text` and return the rest
+    ///
+    /// Bytes are read while they are apart by the separator of their position, up
+    /// to `line_length` bytes; either case is accepted.
+    fn read<'a>(&self, text: &'a str, config: &Config, data: &mut Vec<u8>) -> &'a str {
+        let (block, sep, gap) = self.separators(config);
+        let mut rest = text;
+        for i in 0..config.line_length {
+            let separator = match i {
+                0 => "",
+                _ if block != 0 && i.is_multiple_of(block) => &gap,
+                _ => &sep,
+            };
+            let Some(next) = rest.strip_prefix(separator) else {
+                break;
+            };
+            let byte = next
+                .get(..2)
+                .filter(|digits| digits.chars().all(|c| c.is_ascii_hexdigit()))
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+            let Some(byte) = byte else {
+                break;
+            };
+            data.push(byte);
+            rest = &next[2..];
+        }
+        rest
+    }
+}
+
+/// Items of a template with the fields in `fields`
+fn parse_items(template: &str, fields: &[&str]) -> Result<Vec<Item>> {
+    let invalid =
+        |message: String| Error::Config(format!("invalid template '{}': {}", template, message));
+    let mut items = Vec::new();
+    let mut text = String::new();
+    let mut rest = template;
+    while let Some(c) = rest.chars().next() {
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            text.push(c);
+            rest = &rest[2..];
+            continue;
+        }
+        if c == '}' {
+            return Err(invalid("unmatched '}'".to_string()));
+        }
+        if c != '{' {
+            text.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        let end = rest
+            .find('}')
+            .ok_or_else(|| invalid("unmatched '{'".to_string()))?;
+        let field = &rest[1..end];
+        rest = &rest[end + 1..];
+        let (name, spec) = field.split_once(':').unwrap_or((field, ""));
+        let name = name.trim();
+        if !fields.contains(&name) {
+            return Err(invalid(format!(
+                "unknown field '{{{}}}', expected {}",
+                name,
+                fields
+                    .iter()
+                    .map(|field| format!("{{{}}}", field))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )));
+        }
+        let item = match name {
+            "addr" => AddressSpec::parse(spec).map(Item::Address),
+            "end" => AddressSpec::parse(spec).map(Item::End),
+            "hex" => HexSpec::parse(spec).map(Item::Hex),
+            _ if spec.is_empty() => Some(Item::Ascii),
+            _ => None,
+        }
+        .ok_or_else(|| invalid(format!("invalid format '{}' of {{{}}}", spec, name)))?;
+        if !text.is_empty() {
+            items.push(Item::Text(std::mem::take(&mut text)));
+        }
+        items.push(item);
+    }
+    if !text.is_empty() {
+        items.push(Item::Text(text));
+    }
+    Ok(items)
+}
+
+/// Text of a template without fields
+fn parse_literal(template: &str) -> Result<String> {
+    Ok(parse_items(template, &[])?
+        .into_iter()
+        .map(|item| match item {
+            Item::Text(text) => text,
+            _ => unreachable!("no fields are allowed"),
+        })
+        .collect())
+}
+
+/// Address and bytes read from a line
+struct Matched {
+    address: Option<u64>,
+    data: Vec<u8>,
+}
+
+/// Match a line against the items of a template
+///
+/// Whitespace of the template matches any whitespace, including none. The ASCII
+/// column takes the text up to the literal text after it.
+fn match_line(items: &[Item], line: &str, config: &Config) -> Option<Matched> {
+    let mut matched = Matched {
+        address: None,
+        data: Vec::new(),
+    };
+    let mut rest = line;
+    for (i, item) in items.iter().enumerate() {
+        match item {
+            Item::Text(text) => rest = match_text(rest, text)?,
+            Item::Address(_) | Item::End(_) => {
+                let text = rest.trim_start_matches([' ', '\t']);
+                let digits = text.len()
+                    - text
+                        .trim_start_matches(|c: char| c.is_ascii_hexdigit())
+                        .len();
+                if digits > 0 {
+                    matched.address = Some(u64::from_str_radix(&text[..digits], 16).ok()?);
+                }
+                rest = &text[digits..];
+            }
+            Item::Hex(spec) => {
+                rest = spec.read(rest, config, &mut matched.data);
+            }
+            Item::Ascii => {
+                let suffix: String = items[i + 1..]
+                    .iter()
+                    .map(|item| match item {
+                        Item::Text(text) => Some(text.as_str()),
+                        _ => None,
+                    })
+                    .collect::<Option<String>>()?;
+                let suffix = suffix.trim();
+                rest = &rest[rest.strip_suffix(suffix)?.len()..];
+            }
+        }
+    }
+    rest.trim().is_empty().then_some(matched)
+}
+
+/// Rest of `line` after `text`; whitespace matches any whitespace
+fn match_text<'a>(line: &'a str, text: &str) -> Option<&'a str> {
+    let mut rest = line;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            rest = rest.trim_start_matches([' ', '\t']);
+        } else {
+            rest = rest.strip_prefix(c)?;
+        }
+    }
+    Some(rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RepeatAddress;
+    use crate::convert::bin_to_hex_with;
+
+    fn write(template: &Template, data: &[u8], config: &Config) -> String {
+        let mut output = Vec::new();
+        bin_to_hex_with(data, &mut output, config, None, &mut template.writer()).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    fn read(template: &Template, text: &str) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        template.read(text.as_bytes(), &mut output, &Config::default())?;
+        Ok(output)
+    }
+
+    #[test]
+    fn test_presets() {
+        let data = b"Lorem ipsum dolor sit amet\n";
+        let config = Config::default();
+        let expected = [
+            (
+                "xxd",
+                "00000000: 4c6f 7265 6d20 6970 7375 6d20 646f 6c6f  Lorem ipsum dolo\n\
+                 00000010: 7220 7369 7420 616d 6574 0a              r sit amet.\n",
+            ),
+            (
+                "hexdump",
+                "00000000  4c 6f 72 65 6d 20 69 70  73 75 6d 20 64 6f 6c 6f  |Lorem ipsum dolo|\n\
+                 00000010  72 20 73 69 74 20 61 6d  65 74 0a                 |r sit amet.|\n\
+                 0000001b\n",
+            ),
+            (
+                "od",
+                "000000 4c 6f 72 65 6d 20 69 70 73 75 6d 20 64 6f 6c 6f\n\
+                 000010 72 20 73 69 74 20 61 6d 65 74 0a\n\
+                 00001b\n",
+            ),
+        ];
+        for (name, text) in expected {
+            let template: Template = name.parse().unwrap();
+            assert_eq!(write(&template, data, &config), text);
+            assert_eq!(read(&template, text).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_line_template() {
+        let template = Template::line("{addr:4x} {hex:block=4} {{{ascii}}}").unwrap();
+        let config = Config::default()
+            .with_line_length(8)
+            .with_start_address(0x20);
+        let text = write(&template, b"0123456789", &config);
+        assert_eq!(
+            text,
+            "  20 30313233 34353637 {01234567}\n  28 3839              {89}\n"
+        );
+        let mut data = vec![0; 0x20];
+        data.extend_from_slice(b"0123456789");
+        assert_eq!(read(&template, &text).unwrap(), data);
+
+        // the address column is left empty on lines without an address
+        let config = config.with_repeat_address(RepeatAddress::Once);
+        assert!(
+            write(&template, b"0123456789", &config).ends_with("\n     3839              {89}\n")
+        );
+
+        assert!(Template::line("{adr}").is_err());
+        assert!(Template::line("{hex:block=x}").is_err());
+        assert!(Template::line("{addr").is_err());
+        assert!(Template::line("{ascii:8}").is_err());
+    }
+
+    #[test]
+    fn test_format_file() {
+        let file = "# comment\nheader=dump of {{data}}\nline={addr:X}: {hex}\nfooter=end {end:X}\n";
+        let template = Template::from_format_file(file).unwrap();
+        let config = Config::default().with_line_length(4);
+        let text = write(&template, b"abcdef", &config);
+        assert_eq!(
+            text,
+            "dump of {data}\n00000000: 61 62 63 64\n00000004: 65 66\nend 00000006\n"
+        );
+        assert_eq!(read(&template, &text).unwrap(), b"abcdef");
+
+        assert!(Template::from_format_file("header=x\n").is_err());
+        assert!(Template::from_format_file("line={hex}\nline={hex}").is_err());
+        assert!(Template::from_format_file("line={hex}\nfooter={addr}").is_err());
+        assert!(Template::from_format_file("trailer=x\nline={hex}").is_err());
+    }
+
+    #[test]
+    fn test_read_repeated_lines() {
+        // output of `hexdump -C` for 40 zero bytes and "abc"
+        let text = "\
+00000000  00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00  |................|
+*
+00000020  00 00 00 00 00 00 00 00  61 62 63                 |........abc|
+0000002b
+";
+        let mut data = vec![0; 40];
+        data.extend_from_slice(b"abc");
+        assert_eq!(read(&"hexdump".parse().unwrap(), text).unwrap(), data);
+
+        let err = read(&"od".parse().unwrap(), "000000 01 02\nxyz\n").unwrap_err();
+        assert_eq!(err.location().unwrap().span.line, 2);
+        assert!(read(&"od".parse().unwrap(), "*\n000010\n").is_err());
+    }
+}
@@ -0,0 +1,638 @@
+//! Annotation types and their binary representation
+//!
+//! Types are resolved by name through a [`TypeRegistry`], which holds the `#struct`
+//! definitions seen so far and fixes the byte order of multi-byte integers from the
+//! effective [`Config`]. A resolved [`Type`] is self-contained, so [`decode`] and
+//! [`encode`] need nothing else.
+
+use crate::config::Endian;
+use crate::lexer::Span;
+use crate::parser::{Literal, StructField};
+use crate::{Config, Error, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Names of the built-in types
+pub const BUILTIN_TYPES: [&str; 8] = ["u8", "u16", "u24", "u32", "u40", "u48", "u64", "utf8"];
+
+/// Value of an annotation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    /// Unsigned integer of any width
+    Integer(u64),
+    /// Text of a `utf8` value
+    String(String),
+    /// Struct value, fields in definition order
+    Struct(Vec<(String, Value)>),
+}
+
+impl From<&Literal> for Value {
+    fn from(literal: &Literal) -> Self {
+        match literal {
+            Literal::Integer(number) => Value::Integer(*number),
+            Literal::String(text) => Value::String(text.clone()),
+            Literal::Struct(fields) => Value::Struct(
+                fields
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.into()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    /// Value as written in annotations, e.g. `255`, `"text"` or `{x=1, y=2}`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Integer(number) => write!(f, "{}", number),
+            Value::String(text) => write!(f, "{:?}", text),
+            Value::Struct(fields) => {
+                write!(f, "{{")?;
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}={}", name, value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+/// Resolved annotation type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    /// Unsigned integer of 1 to 8 bytes, including the odd widths `u24`, `u40`, `u48`
+    Unsigned { size: usize, endian: Endian },
+    /// UTF-8 text, as long as its encoding
+    Utf8,
+    /// Struct with fields laid out one after another; nested structs share their layout
+    Struct(Arc<StructType>),
+}
+
+/// Resolved `#struct` type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructType {
+    pub name: String,
+    pub fields: Vec<Field>,
+    size: usize,
+}
+
+/// Field of a resolved struct
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    /// Offset from the start of the struct
+    pub offset: usize,
+    pub ty: Type,
+}
+
+impl StructType {
+    /// Size in bytes of all fields
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Field by name
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+}
+
+impl Type {
+    /// Size in bytes, `None` for types whose size depends on the value
+    pub fn size(&self) -> Option<usize> {
+        match self {
+            Type::Unsigned { size, .. } => Some(*size),
+            Type::Utf8 => None,
+            Type::Struct(ty) => Some(ty.size()),
+        }
+    }
+
+    /// Type name as written in annotations
+    pub fn name(&self) -> String {
+        match self {
+            Type::Unsigned { size, .. } => format!("u{}", size * 8),
+            Type::Utf8 => "utf8".to_string(),
+            Type::Struct(ty) => ty.name.clone(),
+        }
+    }
+}
+
+/// Types available to annotations
+#[derive(Debug, Clone, Default)]
+pub struct TypeRegistry {
+    endian: Endian,
+    /// `#struct` definitions by name, laid out when defined
+    structs: HashMap<String, StructDefinition>,
+}
+
+/// `#struct` definition with its layout in both byte orders
+#[derive(Debug, Clone)]
+struct StructDefinition {
+    fields: Vec<StructField>,
+    little: Arc<StructType>,
+    big: Arc<StructType>,
+}
+
+impl TypeRegistry {
+    /// Registry of the built-in types with the byte order from `config`
+    pub fn new(config: &Config) -> Self {
+        TypeRegistry {
+            endian: config.endian,
+            structs: HashMap::new(),
+        }
+    }
+
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    /// Change the byte order for types resolved from now on
+    pub fn set_endian(&mut self, endian: Endian) {
+        self.endian = endian;
+    }
+
+    /// Register a `#struct` definition
+    ///
+    /// Field types must be built-in or defined earlier, so structs can be nested but
+    /// not recursive. Errors point at the offending part of the definition.
+    pub fn define_struct(&mut self, name: &str, fields: &[StructField], span: Span) -> Result<()> {
+        if BUILTIN_TYPES.contains(&name) {
+            return Err(Error::parse(
+                span,
+                format!("struct name '{}' conflicts with a built-in type", name),
+            ));
+        }
+        if self.structs.contains_key(name) {
+            return Err(Error::parse(
+                span,
+                format!("struct '{}' is already defined", name),
+            ));
+        }
+        if fields.is_empty() {
+            return Err(Error::parse(
+                span,
+                format!("struct '{}' has no fields", name),
+            ));
+        }
+        for (i, field) in fields.iter().enumerate() {
+            if fields[..i].iter().any(|f| f.name == field.name) {
+                return Err(Error::parse(
+                    field.span,
+                    format!("duplicate field '{}' in struct '{}'", field.name, name),
+                ));
+            }
+            if field.type_name == name {
+                return Err(Error::parse(
+                    field.span,
+                    format!("struct '{}' cannot contain itself", name),
+                ));
+            }
+            let ty = self
+                .resolve(&field.type_name)
+                .map_err(|e| Error::parse(field.span, e.to_string()))?;
+            if ty.size().is_none() {
+                return Err(Error::parse(
+                    field.span,
+                    format!(
+                        "field '{}' of struct '{}' has no fixed size",
+                        field.name, name
+                    ),
+                ));
+            }
+        }
+        let definition = StructDefinition {
+            fields: fields.to_vec(),
+            little: self.layout(name, fields, Endian::Little)?,
+            big: self.layout(name, fields, Endian::Big)?,
+        };
+        self.structs.insert(name.to_string(), definition);
+        Ok(())
+    }
+
+    /// Lay out the fields of a struct being defined
+    fn layout(
+        &self,
+        name: &str,
+        fields: &[StructField],
+        endian: Endian,
+    ) -> Result<Arc<StructType>> {
+        let mut size = 0usize;
+        let mut resolved = Vec::with_capacity(fields.len());
+        for field in fields {
+            let ty = self.resolve_with(&field.type_name, endian)?;
+            let offset = size;
+            size = ty
+                .size()
+                .and_then(|field_size| size.checked_add(field_size))
+                .ok_or_else(|| {
+                    Error::parse(field.span, format!("struct '{}' is too large", name))
+                })?;
+            resolved.push(Field {
+                name: field.name.clone(),
+                offset,
+                ty,
+            });
+        }
+        Ok(Arc::new(StructType {
+            name: name.to_string(),
+            fields: resolved,
+            size,
+        }))
+    }
+
+    /// Fields of a defined struct, as written in its definition
+    pub fn struct_fields(&self, name: &str) -> Option<&[StructField]> {
+        self.structs.get(name).map(|s| s.fields.as_slice())
+    }
+
+    /// Look up a type by name
+    pub fn resolve(&self, name: &str) -> Result<Type> {
+        self.resolve_with(name, self.endian)
+    }
+
+    /// Look up a type by name with multi-byte integers in `endian` byte order
+    fn resolve_with(&self, name: &str, endian: Endian) -> Result<Type> {
+        if let Some(definition) = self.structs.get(name) {
+            let layout = match endian {
+                Endian::Little => &definition.little,
+                Endian::Big => &definition.big,
+            };
+            return Ok(Type::Struct(Arc::clone(layout)));
+        }
+        let size = match name {
+            "u8" => 1,
+            "u16" => 2,
+            "u24" => 3,
+            "u32" => 4,
+            "u40" => 5,
+            "u48" => 6,
+            "u64" => 8,
+            "utf8" => return Ok(Type::Utf8),
+            _ => return Err(Error::Value(format!("unknown type '{}'", name))),
+        };
+        Ok(Type::Unsigned { size, endian })
+    }
+}
+
+/// Decode bytes as a value of `ty`; all of `bytes` must belong to the value
+pub fn decode(ty: &Type, bytes: &[u8]) -> Result<Value> {
+    match ty {
+        Type::Unsigned { size, endian } => {
+            if bytes.len() != *size {
+                return Err(Error::Value(format!(
+                    "{} needs {} bytes, found {}",
+                    ty.name(),
+                    size,
+                    bytes.len()
+                )));
+            }
+            let mut buffer = [0u8; 8];
+            let number = match endian {
+                Endian::Little => {
+                    buffer[..*size].copy_from_slice(bytes);
+                    u64::from_le_bytes(buffer)
+                }
+                Endian::Big => {
+                    buffer[8 - size..].copy_from_slice(bytes);
+                    u64::from_be_bytes(buffer)
+                }
+            };
+            Ok(Value::Integer(number))
+        }
+        Type::Utf8 => match std::str::from_utf8(bytes) {
+            Ok(text) => Ok(Value::String(text.to_string())),
+            Err(_) => Err(Error::Value("bytes are not valid UTF-8".to_string())),
+        },
+        Type::Struct(st) => {
+            if bytes.len() != st.size() {
+                return Err(Error::Value(format!(
+                    "{} needs {} bytes, found {}",
+                    st.name,
+                    st.size(),
+                    bytes.len()
+                )));
+            }
+            let mut fields = Vec::with_capacity(st.fields.len());
+            for field in &st.fields {
+                let end = field.offset + field.ty.size().unwrap_or(0);
+                let value = decode(&field.ty, &bytes[field.offset..end])?;
+                fields.push((field.name.clone(), value));
+            }
+            Ok(Value::Struct(fields))
+        }
+    }
+}
+
+/// Encode a value of `ty` into bytes
+pub fn encode(ty: &Type, value: &Value) -> Result<Vec<u8>> {
+    match (ty, value) {
+        (Type::Unsigned { size, endian }, Value::Integer(number)) => {
+            if *size < 8 && number >> (size * 8) != 0 {
+                return Err(Error::Value(format!(
+                    "value {} does not fit into {}",
+                    number,
+                    ty.name()
+                )));
+            }
+            Ok(match endian {
+                Endian::Little => number.to_le_bytes()[..*size].to_vec(),
+                Endian::Big => number.to_be_bytes()[8 - size..].to_vec(),
+            })
+        }
+        (Type::Unsigned { .. }, _) => Err(Error::Value(format!(
+            "{} value must be an integer",
+            ty.name()
+        ))),
+        (Type::Utf8, Value::String(text)) => Ok(text.as_bytes().to_vec()),
+        (Type::Utf8, _) => Err(Error::Value("utf8 value must be a string".to_string())),
+        (Type::Struct(st), Value::Struct(values)) => {
+            if let Some((name, _)) = values.iter().find(|(name, _)| st.field(name).is_none()) {
+                return Err(Error::Value(format!(
+                    "struct '{}' has no field '{}'",
+                    st.name, name
+                )));
+            }
+            let mut bytes = Vec::with_capacity(st.size());
+            for field in &st.fields {
+                let value = struct_field_value(st, values, &field.name)?;
+                bytes.extend(encode(&field.ty, value)?);
+            }
+            Ok(bytes)
+        }
+        (Type::Struct(st), _) => Err(Error::Value(format!(
+            "{} value must be a struct literal",
+            st.name
+        ))),
+    }
+}
+
+/// Number of bytes an annotated value covers
+///
+/// That is the length of the encoded value, or the size of the type if the value
+/// does not fit it.
+pub(crate) fn value_size(ty: &Type, value: &Value) -> u64 {
+    encode(ty, value)
+        .map(|bytes| bytes.len())
+        .unwrap_or_else(|_| ty.size().unwrap_or(0)) as u64
+}
+
+/// Value of a struct field in a struct literal
+pub(crate) fn struct_field_value<'a>(
+    st: &StructType,
+    values: &'a [(String, Value)],
+    name: &str,
+) -> Result<&'a Value> {
+    let mut found = values.iter().filter(|(n, _)| n == name).map(|(_, v)| v);
+    match (found.next(), found.next()) {
+        (Some(value), None) => Ok(value),
+        (Some(_), Some(_)) => Err(Error::Value(format!(
+            "field '{}' of struct '{}' is given more than once",
+            name, st.name
+        ))),
+        (None, _) => Err(Error::Value(format!(
+            "missing value for field '{}' of struct '{}'",
+            name, st.name
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_integer_round_trip() {
+        let mut types = TypeRegistry::default();
+        let u24 = types.resolve("u24").unwrap();
+        assert_eq!(u24.size(), Some(3));
+        assert_eq!(u24.name(), "u24");
+
+        let bytes = encode(&u24, &Value::Integer(0x123456)).unwrap();
+        assert_eq!(bytes, [0x56, 0x34, 0x12]);
+        assert_eq!(decode(&u24, &bytes).unwrap(), Value::Integer(0x123456));
+
+        types.set_endian(Endian::Big);
+        let u48 = types.resolve("u48").unwrap();
+        let bytes = encode(&u48, &Value::Integer(0x0102_0304_0506)).unwrap();
+        assert_eq!(bytes, [1, 2, 3, 4, 5, 6]);
+        assert_eq!(
+            decode(&u48, &bytes).unwrap(),
+            Value::Integer(0x0102_0304_0506)
+        );
+
+        let u64 = types.resolve("u64").unwrap();
+        let bytes = encode(&u64, &Value::Integer(u64::MAX)).unwrap();
+        assert_eq!(decode(&u64, &bytes).unwrap(), Value::Integer(u64::MAX));
+
+        // a type resolved earlier keeps its byte order
+        assert_eq!(encode(&u24, &Value::Integer(1)).unwrap(), [1, 0, 0]);
+    }
+
+    #[test]
+    fn test_utf8() {
+        let utf8 = TypeRegistry::default().resolve("utf8").unwrap();
+        assert_eq!(utf8.size(), None);
+
+        let value = Value::String("Привет\n".to_string());
+        let bytes = encode(&utf8, &value).unwrap();
+        assert_eq!(decode(&utf8, &bytes).unwrap(), value);
+        assert_eq!(value.to_string(), "\"Привет\\n\"");
+    }
+
+    #[test]
+    fn test_errors() {
+        let types = TypeRegistry::default();
+        let u8 = types.resolve("u8").unwrap();
+        let utf8 = types.resolve("utf8").unwrap();
+
+        let message = |result: Result<Vec<u8>>| result.unwrap_err().to_string();
+        assert_eq!(
+            message(encode(&u8, &Value::Integer(256))),
+            "value 256 does not fit into u8"
+        );
+        assert_eq!(
+            message(encode(&u8, &Value::String("x".into()))),
+            "u8 value must be an integer"
+        );
+        assert_eq!(
+            message(encode(&utf8, &Value::Integer(1))),
+            "utf8 value must be a string"
+        );
+        assert_eq!(
+            types.resolve("i7").unwrap_err().to_string(),
+            "unknown type 'i7'"
+        );
+        assert_eq!(
+            decode(&u8, &[1, 2]).unwrap_err().to_string(),
+            "u8 needs 1 bytes, found 2"
+        );
+        assert_eq!(
+            decode(&utf8, &[0xFF]).unwrap_err().to_string(),
+            "bytes are not valid UTF-8"
+        );
+    }
+
+    fn field(name: &str, type_name: &str) -> StructField {
+        StructField {
+            name: name.to_string(),
+            type_name: type_name.to_string(),
+            span: Span::default(),
+        }
+    }
+
+    fn define(types: &mut TypeRegistry, name: &str, fields: &[(&str, &str)]) -> Result<()> {
+        let fields: Vec<StructField> = fields.iter().map(|(n, t)| field(n, t)).collect();
+        types.define_struct(name, &fields, Span::default())
+    }
+
+    #[test]
+    fn test_struct_layout() {
+        let mut types = TypeRegistry::default();
+        define(&mut types, "point", &[("x", "u16"), ("y", "u24")]).unwrap();
+        define(
+            &mut types,
+            "segment",
+            &[("id", "u8"), ("from", "point"), ("to", "point")],
+        )
+        .unwrap();
+
+        let Type::Struct(segment) = types.resolve("segment").unwrap() else {
+            panic!("segment is not a struct");
+        };
+        assert_eq!(segment.size(), 11);
+        let offsets: Vec<usize> = segment.fields.iter().map(|f| f.offset).collect();
+        assert_eq!(offsets, [0, 1, 6]);
+        let Type::Struct(point) = &segment.field("to").unwrap().ty else {
+            panic!("to is not a struct");
+        };
+        assert_eq!(point.field("y").unwrap().offset, 2);
+    }
+
+    #[test]
+    fn test_deeply_nested_structs() {
+        // every level holds two of the previous one, so each resolve used to lay out
+        // 2^depth structs
+        let mut types = TypeRegistry::default();
+        define(&mut types, "s0", &[("a", "u8"), ("b", "u8")]).unwrap();
+        for depth in 1..63 {
+            let inner = format!("s{}", depth - 1);
+            define(
+                &mut types,
+                &format!("s{}", depth),
+                &[("a", &inner), ("b", &inner)],
+            )
+            .unwrap();
+        }
+        let Type::Struct(s62) = types.resolve("s62").unwrap() else {
+            panic!("s62 is not a struct");
+        };
+        assert_eq!(s62.size(), 1 << 63);
+        assert_eq!(s62.field("b").unwrap().offset, 1 << 62);
+
+        assert_eq!(
+            define(&mut types, "s63", &[("a", "s62"), ("b", "s62")])
+                .unwrap_err()
+                .to_string(),
+            "line 0, column 0: struct 's63' is too large"
+        );
+        assert!(types.struct_fields("s63").is_none());
+    }
+
+    #[test]
+    fn test_struct_values() {
+        let mut types = TypeRegistry::default();
+        define(&mut types, "point", &[("x", "u16"), ("y", "u16")]).unwrap();
+        define(&mut types, "segment", &[("from", "point"), ("to", "point")]).unwrap();
+        types.set_endian(Endian::Big);
+        let segment = types.resolve("segment").unwrap();
+
+        let point = |x, y| {
+            Value::Struct(vec![
+                ("x".to_string(), Value::Integer(x)),
+                ("y".to_string(), Value::Integer(y)),
+            ])
+        };
+        let value = Value::Struct(vec![
+            ("to".to_string(), point(3, 4)),
+            ("from".to_string(), point(1, 2)),
+        ]);
+        let bytes = encode(&segment, &value).unwrap();
+        assert_eq!(bytes, [0, 1, 0, 2, 0, 3, 0, 4]);
+        assert_eq!(
+            decode(&segment, &bytes).unwrap().to_string(),
+            "{from={x=1, y=2}, to={x=3, y=4}}"
+        );
+
+        let message = |value: Value| encode(&segment, &value).unwrap_err().to_string();
+        assert_eq!(
+            message(Value::Struct(vec![("from".to_string(), point(1, 2))])),
+            "missing value for field 'to' of struct 'segment'"
+        );
+        assert_eq!(
+            message(Value::Struct(vec![
+                ("from".to_string(), point(1, 2)),
+                ("to".to_string(), point(3, 4)),
+                ("z".to_string(), Value::Integer(0)),
+            ])),
+            "struct 'segment' has no field 'z'"
+        );
+        assert_eq!(
+            message(Value::Integer(1)),
+            "segment value must be a struct literal"
+        );
+    }
+
+    #[test]
+    fn test_struct_definition_errors() {
+        let mut types = TypeRegistry::default();
+        define(&mut types, "point", &[("x", "u16")]).unwrap();
+
+        let message = |types: &mut TypeRegistry, name, fields: &[(&str, &str)]| {
+            define(types, name, fields).unwrap_err().to_string()
+        };
+        assert_eq!(
+            message(&mut types, "point", &[("x", "u8")]),
+            "line 0, column 0: struct 'point' is already defined"
+        );
+        assert_eq!(
+            message(&mut types, "u8", &[("x", "u8")]),
+            "line 0, column 0: struct name 'u8' conflicts with a built-in type"
+        );
+        assert_eq!(
+            message(&mut types, "s", &[("a", "u8"), ("a", "u16")]),
+            "line 0, column 0: duplicate field 'a' in struct 's'"
+        );
+        assert_eq!(
+            message(&mut types, "s", &[("a", "later")]),
+            "line 0, column 0: unknown type 'later'"
+        );
+        assert_eq!(
+            message(&mut types, "s", &[("a", "s")]),
+            "line 0, column 0: struct 's' cannot contain itself"
+        );
+        assert_eq!(
+            message(&mut types, "s", &[("a", "utf8")]),
+            "line 0, column 0: field 'a' of struct 's' has no fixed size"
+        );
+        assert_eq!(
+            message(&mut types, "s", &[]),
+            "line 0, column 0: struct 's' has no fields"
+        );
+        assert!(types.struct_fields("s").is_none());
+    }
+
+    #[test]
+    fn test_value_from_literal() {
+        let literal = Literal::Struct(vec![
+            ("x".to_string(), Literal::Integer(1)),
+            ("name".to_string(), Literal::String("a\"b".to_string())),
+        ]);
+        let value = Value::from(&literal);
+        assert_eq!(value.to_string(), "{x=1, name=\"a\\\"b\"}");
+    }
+}
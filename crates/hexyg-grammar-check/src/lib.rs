@@ -78,7 +78,7 @@ mod tests {
         let java_files: Vec<PathBuf> = fs::read_dir(gen_dir)?
             .filter_map(|entry| entry.ok())
             .map(|e| e.path())
-            .filter(|p| p.extension().map_or(false, |ext| ext == "java"))
+            .filter(|p| p.extension().is_some_and(|ext| ext == "java"))
             .collect();
 
         if java_files.is_empty() {
@@ -117,7 +117,7 @@ mod tests {
             }
 
             // Only process .hex files
-            if entry.path().extension().map_or(false, |ext| ext == "hex") {
+            if entry.path().extension().is_some_and(|ext| ext == "hex") {
                 test_count += 1;
                 print!("Validating {:?}... ", entry.path().file_name().unwrap());
 
@@ -95,6 +95,7 @@ address: HEX_SEQUENCE;
 // Hex data: one or more hex bytes OR one long hex sequence without spaces
 // A hex byte is exactly 2 hex digits
 // Parser accepts any HEX_SEQUENCE, semantic validation must check length is even and divide by 2
+// With `#option radix=2|8|10` a byte is 8, 3 or 3 digits of that radix instead (addresses stay hex)
 hex_data: HEX_SEQUENCE+;
 
 // Preview: ASCII representation in square brackets